@@ -1,44 +1,252 @@
-use std::{fs::read_to_string, net::SocketAddr, path::Path, process, str::FromStr};
+use std::{
+    env,
+    fs::read_to_string,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
+    process,
+    str::FromStr,
+};
 
 use crypto_common::InvalidLength;
 use hmac::{Hmac, Mac};
+use libc::{
+    c_int, LOG_ALERT, LOG_CRIT, LOG_DAEMON, LOG_DEBUG, LOG_EMERG, LOG_ERR, LOG_INFO, LOG_LOCAL0,
+    LOG_LOCAL1, LOG_LOCAL2, LOG_LOCAL3, LOG_LOCAL4, LOG_LOCAL5, LOG_LOCAL6, LOG_LOCAL7, LOG_NOTICE,
+    LOG_USER, LOG_WARNING,
+};
 use lrlex::{lrlex_mod, DefaultLexerTypes, LRNonStreamingLexer};
 use lrpar::{lrpar_mod, NonStreamingLexer, Span};
-use regex::Regex;
+use nix::sys::signal::Signal;
+use regex::{Regex, RegexBuilder};
 use secstr::SecStr;
 use sha2::Sha256;
 
-use crate::config_ast;
+use crate::{
+    config_ast,
+    httpserver::{valid_github_ownername, valid_github_reponame},
+};
 
 type StorageT = u8;
 
 const DEFAULT_TIMEOUT: u64 = 60 * 60; // 1 hour
+const DEFAULT_QUARANTINE_COOLDOWN: u64 = 60 * 60; // 1 hour
+const DEFAULT_JSON_MODE: u32 = 0o600;
+/// The maximum size, in bytes, of a compiled `match` regex. Since regexes only match `owner/repo`
+/// names, this is far larger than any reasonable regex needs.
+const MAX_REGEX_SIZE: usize = 1024 * 1024;
+/// Secrets shorter than this many bytes are valid, but cause a warning, since they are likely to
+/// be easier to guess than the user intended.
+const MIN_SECRET_LEN: usize = 16;
+/// The `Content-Type`s of requests that snare knows how to decode.
+const CONTENT_TYPES: &[&str] = &["application/json", "application/x-www-form-urlencoded"];
 
 lrlex_mod!("config.l");
 lrpar_mod!("config.y");
 
+/// The kind of value an option takes.
+pub enum OptionValue {
+    /// A `{ ... }` block, optionally preceded by a string (e.g. `match "<regex>" { ... }`).
+    Block(Option<&'static str>),
+    /// An integer in the given range.
+    Int(RangeInclusive<i64>),
+    /// A non-empty list of integers, each in the given range.
+    IntList(RangeInclusive<i64>),
+    /// A quoted duration (e.g. `"60s"`): see `parse_duration`.
+    Duration,
+    /// A quoted size (e.g. `"64K"`): see `parse_size`.
+    Size,
+    /// A quoted string: the `&str` is a short human readable description of its contents.
+    Str(&'static str),
+    /// A non-empty list of quoted strings: the `&str` is a short human readable description of
+    /// the contents of each string.
+    StrList(&'static str),
+    /// One of a fixed set of keywords.
+    Choice(&'static [&'static str]),
+}
+
+// The following tables list the options snare.conf recognises and the kind of value each takes.
+// They are the single source of truth for integer, duration, and size values: `option_int` parses
+// and range checks such values according to them, so changing an option's kind or range here
+// changes what the parser accepts. `Config::schema` (and thus `--config-schema`) is also generated
+// from them. The `test_schema_complete` test checks that the tables, `config.l`, and the value
+// tokens in `config.y` agree with each other.
+
+/// Options that can be specified at the top-level.
+pub const TOPLEVEL_OPTIONS: &[(&str, OptionValue)] = &[
+    ("allow_root", OptionValue::Choice(&["on", "off"])),
+    ("allowed_cmds", OptionValue::StrList("path-prefix/")),
+    ("allowed_run_as", OptionValue::StrList("user-name")),
+    ("auditlog", OptionValue::Str("path")),
+    ("chdir", OptionValue::Str("path")),
+    ("content_types", OptionValue::StrList("content-type")),
+    (
+        "default_queue",
+        OptionValue::Choice(&["evict", "parallel", "sequential"]),
+    ),
+    ("everycmd", OptionValue::Str("shell-cmd")),
+    ("fast_ack", OptionValue::Choice(&["on", "off"])),
+    ("github", OptionValue::Block(None)),
+    ("gitlab", OptionValue::Block(None)),
+    ("listen", OptionValue::Str("address")),
+    ("listen_backlog", OptionValue::Int(1..=c_int::MAX as i64)),
+    ("match_mode", OptionValue::Choice(&["cascade", "first"])),
+    ("max_conns_per_ip", OptionValue::Int(1..=i64::MAX)),
+    ("max_json_size", OptionValue::Int(1..=i64::MAX)),
+    ("max_parallel_global", OptionValue::Int(1..=i64::MAX)),
+    ("max_repo_queues", OptionValue::Int(1..=i64::MAX)),
+    ("max_total_queue", OptionValue::Int(1..=i64::MAX)),
+    (
+        "maxjobs",
+        OptionValue::Int(1..=((usize::MAX - 1) / 2) as i64),
+    ),
+    ("on_idle", OptionValue::Str("shell-cmd")),
+    ("ping_cmd", OptionValue::Str("shell-cmd")),
+    ("reload_errorcmd", OptionValue::Str("shell-cmd")),
+    ("reloadcmd", OptionValue::Str("shell-cmd")),
+    ("require_secret", OptionValue::Choice(&["on", "off"])),
+    ("runner_priority", OptionValue::Int(-20..=19)),
+    ("socket_rcvbuf", OptionValue::Int(1..=c_int::MAX as i64)),
+    ("socket_sndbuf", OptionValue::Int(1..=c_int::MAX as i64)),
+    ("trusted_proxies", OptionValue::StrList("ip-address")),
+    ("user", OptionValue::Str("user-name")),
+];
+
+/// Options that can be specified in a `github` or `gitlab` block.
+pub const GITHUB_OPTIONS: &[(&str, OptionValue)] = &[
+    ("fallback_repo", OptionValue::Str("owner/repo")),
+    ("full_name", OptionValue::Choice(&["on", "off"])),
+    ("host_header", OptionValue::Str("header-name")),
+    ("match", OptionValue::Block(Some("regex"))),
+    ("org_repo", OptionValue::Str("repo-name")),
+    ("owner_path", OptionValue::Str("json-pointer")),
+    ("repo_path", OptionValue::Str("json-pointer")),
+    ("signature_header", OptionValue::Str("header-name")),
+    ("strict_names", OptionValue::Choice(&["on", "off"])),
+];
+
+/// Options that can be specified in a `match` block.
+pub const MATCH_OPTIONS: &[(&str, OptionValue)] = &[
+    ("allow_header_timeout", OptionValue::Choice(&["on", "off"])),
+    ("cgroup", OptionValue::Str("cgroup")),
+    ("cmd", OptionValue::Str("shell-cmd")),
+    ("cpu_limit", OptionValue::Duration),
+    ("errorcmd", OptionValue::Str("shell-cmd")),
+    ("errorcmd_on", OptionValue::IntList(1..=255)),
+    ("errorcmd_retries", OptionValue::Int(0..=i64::MAX)),
+    ("errorcmd_unless", OptionValue::IntList(1..=255)),
+    ("errorcmd_without_cmd", OptionValue::Choice(&["on", "off"])),
+    ("hook_id", OptionValue::Int(0..=i64::MAX)),
+    ("host", OptionValue::Str("host-name")),
+    ("idle_timeout", OptionValue::Duration),
+    ("ignore_senders", OptionValue::StrList("login")),
+    ("json_mode", OptionValue::Str("octal-mode")),
+    ("kill_group", OptionValue::Choice(&["on", "off"])),
+    ("lock", OptionValue::Str("lock-name")),
+    ("max_header_timeout", OptionValue::Int(1..=i64::MAX)),
+    ("mem_limit", OptionValue::Size),
+    ("merge_output", OptionValue::Choice(&["on", "off"])),
+    ("quarantine_after", OptionValue::Int(1..=i64::MAX)),
+    ("quarantine_cooldown", OptionValue::Int(0..=i64::MAX)),
+    (
+        "queue",
+        OptionValue::Choice(&["evict", "parallel", "sequential"]),
+    ),
+    ("raw_body", OptionValue::Choice(&["on", "off"])),
+    ("secret", OptionValue::Str("secret")),
+    ("syslog", OptionValue::Choice(&["on", "off"])),
+    ("syslog_priority", OptionValue::Str("[facility.]level")),
+    ("tail_output", OptionValue::Size),
+    ("timeout", OptionValue::Int(0..=i64::MAX)),
+    ("timeout_signal", OptionValue::Str("signal-name")),
+    ("topic", OptionValue::Str("topic")),
+    (
+        "visibility",
+        OptionValue::Choice(&["internal", "private", "public"]),
+    ),
+    ("weight", OptionValue::Int(1..=i64::MAX)),
+];
+
+/// Options which are still recognised by the parser, but only so that we can tell the user what
+/// to replace them with.
+#[cfg(test)]
+const DEPRECATED_OPTIONS: &[&str] = &["email", "reposdir"];
+
 pub struct Config {
-    /// The IP address/port on which to listen.
-    pub listen: SocketAddr,
+    /// May snare run as root (i.e. with a UID or EUID of 0)?
+    pub allow_root: bool,
+    /// If set, the file to which a line is appended for every delivery that reaches
+    /// authentication.
+    pub auditlog: Option<PathBuf>,
+    /// The directory to change to at startup.
+    pub chdir: PathBuf,
+    /// The `Content-Type`s that requests may use: requests with any other `Content-Type` are
+    /// rejected.
+    pub content_types: Vec<String>,
+    /// An optional command to run, as a job of its own, for every authenticated request, in
+    /// addition to any command run for the matching repository.
+    pub everycmd: Option<String>,
+    /// Should requests be acknowledged as soon as they have been authenticated, before any jobs
+    /// have been queued?
+    pub fast_ack: bool,
+    /// The IP address/port on which to listen. This is only `None` if the user is relying on
+    /// systemd socket activation to provide the listening socket.
+    pub listen: Option<SocketAddr>,
+    /// The maximum number of pending connections the OS should queue for us. If `None`, the OS
+    /// default is used.
+    pub listen_backlog: Option<usize>,
+    /// The maximum number of connections a single IP address can have open at once. If `None`,
+    /// only `MAX_SIMULTANEOUS_CONNECTIONS` limits a source's connections.
+    pub max_conns_per_ip: Option<usize>,
+    /// The maximum size (in bytes) of a request's JSON payload once it has been decoded. If
+    /// `None`, only `MAX_HTTP_BODY_SIZE` limits the payload's size.
+    pub max_json_size: Option<usize>,
+    /// The maximum number of `queue = parallel` jobs that can run at once (across all
+    /// repositories). If `None`, only `maxjobs` limits such jobs.
+    pub max_parallel_global: Option<usize>,
+    /// The maximum number of repositories which can have jobs queued at once. If `None`, there is
+    /// no limit.
+    pub max_repo_queues: Option<usize>,
+    /// The maximum number of jobs that can be queued (across all repositories). If `None`, the
+    /// queue is unbounded.
+    pub max_total_queue: Option<usize>,
     /// The maximum number of parallel jobs to run.
     pub maxjobs: usize,
-    /// The GitHub block.
-    pub github: GitHub,
+    /// An optional command to run when snare becomes idle after having run one or more jobs.
+    pub on_idle: Option<String>,
+    /// An optional command to run in response to `ping` events. If `None`, `ping` events are
+    /// acknowledged but otherwise ignored.
+    pub ping_cmd: Option<String>,
+    /// An optional command to run after an attempt to reload the config has failed.
+    pub reload_errorcmd: Option<String>,
+    /// An optional command to run after the config has been successfully reloaded.
+    pub reloadcmd: Option<String>,
+    /// Must every request be for a repository with a `secret`?
+    pub require_secret: bool,
+    /// If set, the nice value that the job runner thread (but not the jobs it runs) should run at.
+    pub runner_priority: Option<c_int>,
+    /// If set, the size (in bytes) of the receive buffer the OS should use for each connection.
+    pub socket_rcvbuf: Option<usize>,
+    /// If set, the size (in bytes) of the send buffer the OS should use for each connection.
+    pub socket_sndbuf: Option<usize>,
+    /// Proxies whose `X-Forwarded-For` headers are trusted to report the client's IP address.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// The GitHub block, if specified.
+    pub github: Option<GitHub>,
+    /// The GitLab block, if specified. At least one of `github` and `gitlab` is always specified.
+    pub gitlab: Option<GitHub>,
     /// The Unix user to change to after snare has bound itself to a network port.
     pub user: Option<String>,
 }
 
-impl Config {
-    /// Create a `Config` from `path`, returning `Err(String)` (containing a human readable
-    /// message) if it was unable to do so.
-    pub fn from_path(conf_path: &Path) -> Result<Self, String> {
-        let input = match read_to_string(conf_path) {
-            Ok(s) => s,
-            Err(e) => return Err(format!("Can't read {:?}: {}", conf_path, e)),
-        };
+impl FromStr for Config {
+    type Err = String;
 
+    /// Create a `Config` from the configuration file contents `input`, returning `Err(String)`
+    /// (containing a human readable message) if it was unable to do so.
+    fn from_str(input: &str) -> Result<Self, String> {
         let lexerdef = config_l::lexerdef();
-        let lexer = lexerdef.lexer(&input);
+        let lexer = lexerdef.lexer(input);
         let (astopt, errs) = config_y::parse(&lexer);
         if !errs.is_empty() {
             let msgs = errs
@@ -47,14 +255,164 @@ impl Config {
                 .collect::<Vec<_>>();
             return Err(msgs.join("\n"));
         }
+        let mut allow_root = None;
+        let mut allowed_cmds = None;
+        let mut allowed_run_as = None;
+        let mut auditlog = None;
+        let mut chdir = None;
+        let mut content_types = None;
+        let mut default_queue = None;
+        let mut everycmd = None;
+        let mut fast_ack = None;
         let mut github = None;
+        let mut gitlab = None;
         let mut listen = None;
+        let mut listen_backlog = None;
+        let mut match_mode = None;
+        let mut max_conns_per_ip = None;
+        let mut max_json_size = None;
+        let mut max_parallel_global = None;
+        let mut max_repo_queues = None;
+        let mut max_total_queue = None;
         let mut maxjobs = None;
+        let mut on_idle = None;
+        let mut ping_cmd = None;
+        let mut reload_errorcmd = None;
+        let mut reloadcmd = None;
+        let mut require_secret = None;
+        let mut runner_priority = None;
+        let mut socket_rcvbuf = None;
+        let mut socket_sndbuf = None;
+        let mut trusted_proxies = None;
         let mut user = None;
         match astopt {
             Some(Ok(opts)) => {
                 for opt in opts {
                     match opt {
+                        config_ast::TopLevelOption::AllowRoot(span, b) => {
+                            if allow_root.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'allow_root' more than once",
+                                ));
+                            }
+                            allow_root = Some(b);
+                        }
+                        config_ast::TopLevelOption::AllowedCmds(span, prefixes) => {
+                            if allowed_cmds.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'allowed_cmds' more than once",
+                                ));
+                            }
+                            let mut v = Vec::with_capacity(prefixes.len());
+                            for span in prefixes {
+                                let prefix = unescape_str(lexer.span_str(span));
+                                if !prefix.starts_with('/') || !prefix.ends_with('/') {
+                                    return Err(error_at_span(
+                                        &lexer,
+                                        span,
+                                        "Allowed command prefixes must start and end with '/'",
+                                    ));
+                                }
+                                v.push(prefix);
+                            }
+                            allowed_cmds = Some(v);
+                        }
+                        config_ast::TopLevelOption::AllowedRunAs(span, users) => {
+                            if allowed_run_as.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'allowed_run_as' more than once",
+                                ));
+                            }
+                            let mut v = Vec::with_capacity(users.len());
+                            for span in users {
+                                let user_str = unescape_str(lexer.span_str(span));
+                                if user_str.is_empty() {
+                                    return Err(error_at_span(
+                                        &lexer,
+                                        span,
+                                        "User names must not be empty",
+                                    ));
+                                }
+                                v.push(user_str);
+                            }
+                            allowed_run_as = Some(v);
+                        }
+                        config_ast::TopLevelOption::AuditLog(span) => {
+                            if auditlog.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'auditlog' more than once",
+                                ));
+                            }
+                            let auditlog_str = unescape_str(lexer.span_str(span));
+                            if auditlog_str.is_empty() {
+                                return Err(error_at_span(&lexer, span, "Path must not be empty"));
+                            }
+                            auditlog = Some(PathBuf::from(auditlog_str));
+                        }
+                        config_ast::TopLevelOption::ChDir(span) => {
+                            if chdir.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'chdir' more than once",
+                                ));
+                            }
+                            let chdir_str = unescape_str(lexer.span_str(span));
+                            if chdir_str.is_empty() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Directory must not be empty",
+                                ));
+                            }
+                            chdir = Some(PathBuf::from(chdir_str));
+                        }
+                        config_ast::TopLevelOption::DefaultQueue(span, qkind) => {
+                            if default_queue.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'default_queue' more than once",
+                                ));
+                            }
+                            default_queue = Some(match qkind {
+                                config_ast::QueueKind::Evict => QueueKind::Evict,
+                                config_ast::QueueKind::Parallel => QueueKind::Parallel,
+                                config_ast::QueueKind::Sequential => QueueKind::Sequential,
+                            });
+                        }
+                        config_ast::TopLevelOption::EveryCmd(span) => {
+                            if everycmd.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'everycmd' more than once",
+                                ));
+                            }
+                            let everycmd_str = unescape_str(lexer.span_str(span));
+                            GitHub::verify_cmd_str(&everycmd_str, CmdContext::Cmd)?;
+                            // As with `ping_cmd`, whether the command is allowed can only be
+                            // checked once we've seen all top-level options.
+                            everycmd = Some((span, everycmd_str));
+                        }
+                        config_ast::TopLevelOption::FastAck(span, b) => {
+                            if fast_ack.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'fast_ack' more than once",
+                                ));
+                            }
+                            fast_ack = Some(b);
+                        }
                         config_ast::TopLevelOption::GitHub(span, options, matches) => {
                             if github.is_some() {
                                 return Err(error_at_span(
@@ -63,7 +421,19 @@ impl Config {
                                     "Mustn't specify 'github' more than once",
                                 ));
                             }
-                            github = Some(GitHub::parse(&lexer, options, matches)?);
+                            // We can only parse the GitHub block once we know all the top-level
+                            // options it depends upon.
+                            github = Some((options, matches));
+                        }
+                        config_ast::TopLevelOption::GitLab(span, options, matches) => {
+                            if gitlab.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'gitlab' more than once",
+                                ));
+                            }
+                            gitlab = Some((options, matches));
                         }
                         config_ast::TopLevelOption::Listen(span) => {
                             if listen.is_some() {
@@ -74,7 +444,7 @@ impl Config {
                                 ));
                             }
                             let listen_str = unescape_str(lexer.span_str(span));
-                            match SocketAddr::from_str(&listen_str) {
+                            match listen_addr(&listen_str) {
                                 Ok(l) => listen = Some(l),
                                 Err(e) => {
                                     return Err(error_at_span(
@@ -85,6 +455,105 @@ impl Config {
                                 }
                             }
                         }
+                        config_ast::TopLevelOption::ListenBacklog(span) => {
+                            if listen_backlog.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'listen_backlog' more than once",
+                                ));
+                            }
+                            listen_backlog = Some(option_int(
+                                &lexer,
+                                TOPLEVEL_OPTIONS,
+                                "listen_backlog",
+                                span,
+                            )?);
+                        }
+                        config_ast::TopLevelOption::MatchMode(span, mmode) => {
+                            if match_mode.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'match_mode' more than once",
+                                ));
+                            }
+                            match_mode = Some(match mmode {
+                                config_ast::MatchMode::Cascade => MatchMode::Cascade,
+                                config_ast::MatchMode::First => MatchMode::First,
+                            });
+                        }
+                        config_ast::TopLevelOption::MaxConnsPerIp(span) => {
+                            if max_conns_per_ip.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'max_conns_per_ip' more than once",
+                                ));
+                            }
+                            max_conns_per_ip = Some(option_int(
+                                &lexer,
+                                TOPLEVEL_OPTIONS,
+                                "max_conns_per_ip",
+                                span,
+                            )?);
+                        }
+                        config_ast::TopLevelOption::MaxJsonSize(span) => {
+                            if max_json_size.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'max_json_size' more than once",
+                                ));
+                            }
+                            max_json_size =
+                                Some(option_int(&lexer, TOPLEVEL_OPTIONS, "max_json_size", span)?);
+                        }
+                        config_ast::TopLevelOption::MaxParallelGlobal(span) => {
+                            if max_parallel_global.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'max_parallel_global' more than once",
+                                ));
+                            }
+                            max_parallel_global = Some(option_int(
+                                &lexer,
+                                TOPLEVEL_OPTIONS,
+                                "max_parallel_global",
+                                span,
+                            )?);
+                        }
+                        config_ast::TopLevelOption::MaxRepoQueues(span) => {
+                            if max_repo_queues.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'max_repo_queues' more than once",
+                                ));
+                            }
+                            max_repo_queues = Some(option_int(
+                                &lexer,
+                                TOPLEVEL_OPTIONS,
+                                "max_repo_queues",
+                                span,
+                            )?);
+                        }
+                        config_ast::TopLevelOption::MaxTotalQueue(span) => {
+                            if max_total_queue.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'max_total_queue' more than once",
+                                ));
+                            }
+                            max_total_queue = Some(option_int(
+                                &lexer,
+                                TOPLEVEL_OPTIONS,
+                                "max_total_queue",
+                                span,
+                            )?);
+                        }
                         config_ast::TopLevelOption::MaxJobs(span) => {
                             if maxjobs.is_some() {
                                 return Err(error_at_span(
@@ -94,29 +563,152 @@ impl Config {
                                 ));
                             }
                             let maxjobs_str = lexer.span_str(span);
-                            match maxjobs_str.parse() {
-                                Ok(0) => {
-                                    return Err(error_at_span(
-                                        &lexer,
-                                        span,
-                                        "Must allow at least 1 job",
-                                    ))
-                                }
-                                Ok(x) if x > (std::usize::MAX - 1) / 2 => {
+                            maxjobs = Some(option_int(&lexer, TOPLEVEL_OPTIONS, "maxjobs", span)?);
+                        }
+                        config_ast::TopLevelOption::OnIdle(span) => {
+                            if on_idle.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'on_idle' more than once",
+                                ));
+                            }
+                            on_idle = Some((span, unescape_str(lexer.span_str(span))));
+                        }
+                        config_ast::TopLevelOption::PingCmd(span) => {
+                            if ping_cmd.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'ping_cmd' more than once",
+                                ));
+                            }
+                            let ping_cmd_str = unescape_str(lexer.span_str(span));
+                            GitHub::verify_cmd_str(&ping_cmd_str, CmdContext::Cmd)?;
+                            // Whether the command is allowed can only be checked once we've seen
+                            // all top-level options.
+                            ping_cmd = Some((span, ping_cmd_str));
+                        }
+                        config_ast::TopLevelOption::ReloadErrorCmd(span) => {
+                            if reload_errorcmd.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'reload_errorcmd' more than once",
+                                ));
+                            }
+                            let reload_errorcmd_str = unescape_str(lexer.span_str(span));
+                            GitHub::verify_cmd_str(
+                                &reload_errorcmd_str,
+                                CmdContext::ReloadErrorCmd,
+                            )?;
+                            reload_errorcmd = Some((span, reload_errorcmd_str));
+                        }
+                        config_ast::TopLevelOption::ReloadCmd(span) => {
+                            if reloadcmd.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'reloadcmd' more than once",
+                                ));
+                            }
+                            reloadcmd = Some((span, unescape_str(lexer.span_str(span))));
+                        }
+                        config_ast::TopLevelOption::RequireSecret(span, b) => {
+                            if require_secret.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'require_secret' more than once",
+                                ));
+                            }
+                            require_secret = Some(b);
+                        }
+                        config_ast::TopLevelOption::RunnerPriority(span) => {
+                            if runner_priority.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'runner_priority' more than once",
+                                ));
+                            }
+                            runner_priority = Some(option_int(
+                                &lexer,
+                                TOPLEVEL_OPTIONS,
+                                "runner_priority",
+                                span,
+                            )?);
+                        }
+                        config_ast::TopLevelOption::SocketRcvBuf(span) => {
+                            if socket_rcvbuf.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'socket_rcvbuf' more than once",
+                                ));
+                            }
+                            socket_rcvbuf =
+                                Some(option_int(&lexer, TOPLEVEL_OPTIONS, "socket_rcvbuf", span)?);
+                        }
+                        config_ast::TopLevelOption::SocketSndBuf(span) => {
+                            if socket_sndbuf.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'socket_sndbuf' more than once",
+                                ));
+                            }
+                            socket_sndbuf =
+                                Some(option_int(&lexer, TOPLEVEL_OPTIONS, "socket_sndbuf", span)?);
+                        }
+                        config_ast::TopLevelOption::ContentTypes(span, types) => {
+                            if content_types.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'content_types' more than once",
+                                ));
+                            }
+                            let mut v = Vec::with_capacity(types.len());
+                            for span in types {
+                                let type_str = unescape_str(lexer.span_str(span));
+                                if !CONTENT_TYPES.contains(&type_str.as_str()) {
                                     return Err(error_at_span(
                                         &lexer,
                                         span,
                                         &format!(
-                                            "Maximum number of jobs is {}",
-                                            (std::usize::MAX - 1) / 2
+                                            "Unsupported content type: must be one of {}",
+                                            CONTENT_TYPES.join(", ")
                                         ),
-                                    ))
+                                    ));
                                 }
-                                Ok(x) => maxjobs = Some(x),
-                                Err(e) => {
-                                    return Err(error_at_span(&lexer, span, &format!("{}", e)))
+                                v.push(type_str);
+                            }
+                            content_types = Some(v);
+                        }
+                        config_ast::TopLevelOption::TrustedProxies(span, addrs) => {
+                            if trusted_proxies.is_some() {
+                                return Err(error_at_span(
+                                    &lexer,
+                                    span,
+                                    "Mustn't specify 'trusted_proxies' more than once",
+                                ));
+                            }
+                            let mut v = Vec::with_capacity(addrs.len());
+                            for span in addrs {
+                                let addr_str = unescape_str(lexer.span_str(span));
+                                match IpAddr::from_str(&addr_str) {
+                                    Ok(x) => v.push(x),
+                                    Err(e) => {
+                                        return Err(error_at_span(
+                                            &lexer,
+                                            span,
+                                            &format!("Invalid IP address '{addr_str}': {e}"),
+                                        ))
+                                    }
                                 }
                             }
+                            trusted_proxies = Some(v);
                         }
                         config_ast::TopLevelOption::User(span) => {
                             if user.is_some() {
@@ -127,74 +719,524 @@ impl Config {
                                 ));
                             }
                             let user_str = unescape_str(lexer.span_str(span));
-                            user = Some(user_str);
+                            user = Some((span, user_str));
                         }
                     }
                 }
             }
             _ => process::exit(1),
         }
+        let chdir = chdir.unwrap_or_else(|| PathBuf::from("/"));
         let maxjobs = maxjobs.unwrap_or_else(num_cpus::get);
-        let listen = listen.ok_or_else(|| "A 'listen' address must be specified".to_owned())?;
-        let github = github.ok_or_else(|| {
-            "A GitHub block with at least a 'cmd' option must be specified".to_owned()
-        })?;
+        if let Some(allowed_cmds) = &allowed_cmds {
+            for (span, cmd_str) in ping_cmd
+                .iter()
+                .chain(everycmd.iter())
+                .chain(on_idle.iter())
+                .chain(reload_errorcmd.iter())
+                .chain(reloadcmd.iter())
+            {
+                if !cmd_allowed(cmd_str, allowed_cmds) {
+                    return Err(error_at_span(
+                        &lexer,
+                        *span,
+                        "Command is not under any of the 'allowed_cmds' prefixes",
+                    ));
+                }
+            }
+        }
+        if let (Some(allowed_run_as), Some((span, user_str))) = (&allowed_run_as, &user) {
+            if !allowed_run_as.contains(user_str) {
+                return Err(error_at_span(
+                    &lexer,
+                    *span,
+                    "User is not one of those listed in 'allowed_run_as'",
+                ));
+            }
+        }
+        let everycmd = everycmd.map(|(_, everycmd_str)| everycmd_str);
+        let user = user.map(|(_, user_str)| user_str);
+        let ping_cmd = ping_cmd.map(|(_, ping_cmd_str)| ping_cmd_str);
+        let on_idle = on_idle.map(|(_, on_idle_str)| on_idle_str);
+        let reload_errorcmd = reload_errorcmd.map(|(_, reload_errorcmd_str)| reload_errorcmd_str);
+        let reloadcmd = reloadcmd.map(|(_, reloadcmd_str)| reloadcmd_str);
+        if github.is_none() && gitlab.is_none() {
+            return Err(
+                "A GitHub or GitLab block with at least a 'cmd' option must be specified"
+                    .to_owned(),
+            );
+        }
+        let mut github = match github {
+            Some((options, matches)) => Some(GitHub::parse(
+                &lexer,
+                Provider::GitHub,
+                options,
+                matches,
+                allowed_cmds.as_deref(),
+                require_secret.unwrap_or(false),
+            )?),
+            None => None,
+        };
+        let mut gitlab = match gitlab {
+            Some((options, matches)) => Some(GitHub::parse(
+                &lexer,
+                Provider::GitLab,
+                options,
+                matches,
+                allowed_cmds.as_deref(),
+                require_secret.unwrap_or(false),
+            )?),
+            None => None,
+        };
+        for provider in github.iter_mut().chain(gitlab.iter_mut()) {
+            provider.match_mode = match_mode.unwrap_or(MatchMode::Cascade);
+            if let Some(default_queue) = default_queue {
+                // The first match is always the default match block.
+                provider.matches[0].queuekind = Some(default_queue);
+            }
+        }
 
         Ok(Config {
+            allow_root: allow_root.unwrap_or(false),
+            auditlog,
+            chdir,
+            content_types: content_types
+                .unwrap_or_else(|| CONTENT_TYPES.iter().map(|x| (*x).to_owned()).collect()),
+            everycmd,
+            fast_ack: fast_ack.unwrap_or(false),
             listen,
+            listen_backlog,
+            max_conns_per_ip,
+            max_json_size,
+            max_parallel_global,
+            max_repo_queues,
+            max_total_queue,
             maxjobs,
+            on_idle,
+            ping_cmd,
+            reload_errorcmd,
+            reloadcmd,
+            require_secret: require_secret.unwrap_or(false),
+            runner_priority,
+            socket_rcvbuf,
+            socket_sndbuf,
+            trusted_proxies: trusted_proxies.unwrap_or_default(),
             github,
+            gitlab,
             user,
         })
     }
 }
 
+impl Config {
+    /// Create a `Config` from `path`, returning `Err(String)` (containing a human readable
+    /// message) if it was unable to do so.
+    pub fn from_path(conf_path: &Path) -> Result<Self, String> {
+        let input = match read_to_string(conf_path) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Can't read {:?}: {}", conf_path, e)),
+        };
+        Config::from_str(&input)
+    }
+
+    /// Return the configuration for requests from `provider`, or `None` if the user has not
+    /// configured that provider.
+    pub fn provider(&self, provider: Provider) -> Option<&GitHub> {
+        match provider {
+            Provider::GitHub => self.github.as_ref(),
+            Provider::GitLab => self.gitlab.as_ref(),
+        }
+    }
+
+    /// Return the configuration of the `github` block or, if only a `gitlab` block is specified,
+    /// the `gitlab` block. This is for tools (e.g. `--match`) which are given a repository name
+    /// rather than a request, and thus can't tell which provider it would come from.
+    pub fn default_provider(&self) -> &GitHub {
+        // `from_str` guarantees that at least one of the blocks is specified.
+        self.github.as_ref().or(self.gitlab.as_ref()).unwrap()
+    }
+
+    /// Return human readable warnings about settings which are valid, but which are probably not
+    /// what the user intended.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let matches = || {
+            self.github
+                .iter()
+                .chain(self.gitlab.iter())
+                .flat_map(|x| x.matches.iter())
+        };
+        if let Some(listen) = self.listen {
+            if !listen.ip().is_loopback() && matches().all(|m| m.secret.is_none()) {
+                warnings.push(format!(
+                    "Listening on non-loopback address {listen} but no 'secret' is specified"
+                ));
+            }
+        }
+        let cpus = num_cpus::get();
+        if self.maxjobs > cpus * 4 {
+            warnings.push(format!(
+                "'maxjobs' ({}) is more than four times the number of CPUs ({cpus})",
+                self.maxjobs
+            ));
+        }
+        for m in matches() {
+            for cmd in m.cmd.iter().flatten() {
+                if let Some(prog) = cmd.split_whitespace().next() {
+                    if !prog.contains('%') && !prog_exists(prog) {
+                        warnings.push(format!(
+                            "match '{}': 'cmd' program '{prog}' not found",
+                            m.re.as_str()
+                        ));
+                    }
+                }
+            }
+            if let Some(secret) = &m.secret {
+                if secret.unsecure().len() < MIN_SECRET_LEN {
+                    warnings.push(format!(
+                        "match '{}': 'secret' is shorter than {MIN_SECRET_LEN} bytes",
+                        m.re.as_str()
+                    ));
+                }
+            }
+            if m.timeout == Some(0) {
+                warnings.push(format!(
+                    "match '{}': a 'timeout' of 0 will terminate commands immediately",
+                    m.re.as_str()
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Return a human readable listing of all the options snare.conf recognises, grouped by the
+    /// context they can appear in.
+    pub fn schema() -> String {
+        let mut s = String::new();
+        for (title, opts) in [
+            ("Top-level options:", TOPLEVEL_OPTIONS),
+            ("'github' and 'gitlab' block options:", GITHUB_OPTIONS),
+            ("'match' block options:", MATCH_OPTIONS),
+        ] {
+            if !s.is_empty() {
+                s.push('\n');
+            }
+            s.push_str(title);
+            s.push('\n');
+            for (name, val) in opts {
+                s.push_str(&format!("  {}\n", option_syntax(name, val)));
+            }
+        }
+        s
+    }
+
+    /// Return a minimal, commented, working configuration which users can use as a starting
+    /// point. Options not set in the configuration are listed in comments.
+    pub fn default_config() -> String {
+        // Options which are explicitly set (or commented out) below, so shouldn't be listed again.
+        let shown = ["cmd", "github", "listen", "match", "secret"];
+        let others = |opts: &[(&str, OptionValue)], indent: &str| {
+            opts.iter()
+                .filter(|(name, _)| !shown.contains(name))
+                .map(|(name, val)| format!("{indent}//   {}\n", option_syntax(name, val)))
+                .collect::<String>()
+        };
+        format!(
+            r#"// A minimal snare configuration. See snare.conf(5) for details of each option.
+
+// The IP address and port to listen on (e.g. "0.0.0.0:8765" listens on all IPv4
+// interfaces and "[::]:8765" on all IPv4 and IPv6 interfaces).
+listen = "127.0.0.1:8765";
+
+// Other top-level options:
+{}
+github {{
+  // Other 'github' options:
+{}
+  match ".*" {{
+    // For each webhook event, run the per-repo program /path/to/prps/<owner>/<repo>
+    // with the event type and the path to the event's JSON as arguments.
+    cmd = "/path/to/prps/%o/%r %e %j";
+
+    // The secret used to sign webhook requests: this must match the secret set
+    // in the repository's GitHub webhook settings.
+    // secret = "<secret>";
+
+    // Other 'match' options:
+{}  }}
+}}
+"#,
+            others(TOPLEVEL_OPTIONS, ""),
+            others(GITHUB_OPTIONS, "  "),
+            others(MATCH_OPTIONS, "    ")
+        )
+    }
+}
+
+/// Return a human readable summary of the syntax of option `name`.
+fn option_syntax(name: &str, val: &OptionValue) -> String {
+    match val {
+        OptionValue::Block(None) => format!("{name} {{ ... }}"),
+        OptionValue::Block(Some(x)) => format!("{name} \"<{x}>\" {{ ... }}"),
+        OptionValue::Int(_) => format!("{name} = <int>;"),
+        OptionValue::IntList(_) => format!("{name} = [<int>, ...];"),
+        OptionValue::Duration => format!("{name} = \"<duration>\";"),
+        OptionValue::Size => format!("{name} = \"<size>\";"),
+        OptionValue::Str(x) => format!("{name} = \"<{x}>\";"),
+        OptionValue::StrList(x) => format!("{name} = [\"<{x}>\", ...];"),
+        OptionValue::Choice(x) => format!("{name} = ({});", x.join(" | ")),
+    }
+}
+
+/// The configuration of a webhook provider: despite its name, this is used for both the `github`
+/// and `gitlab` blocks, which differ only in their defaults and in how requests are authenticated.
 pub struct GitHub {
+    /// If set, payloads from which no repository owner and name can be extracted are treated as if
+    /// they came from this `(owner, repo)`.
+    fallback_repo: Option<(String, String)>,
+    /// Should the repository owner and name be taken from a payload's `repository.full_name`
+    /// (when present) rather than from `owner_path` and `repo_path`?
+    full_name: bool,
+    /// The (lower case) name of the HTTP header containing the host a request came from.
+    pub host_header: String,
     pub matches: Vec<Match>,
+    /// How `matches` are applied to a given repository.
+    match_mode: MatchMode,
+    /// If set, payloads without a repository, but with an organization, are treated as if they
+    /// came from the repository with this name in that organization.
+    org_repo: Option<String>,
+    /// The JSON pointer to the repository owner's name in a payload.
+    owner_path: String,
+    /// The JSON pointer to the repository's name in a payload.
+    repo_path: String,
+    /// The (lower case) name of the HTTP header containing a request's HMAC-SHA256 signature.
+    pub signature_header: String,
+    /// Must owner and repository names follow GitHub's naming rules? If not, names need only be
+    /// safe to use in filesystem paths.
+    pub strict_names: bool,
 }
 
 impl GitHub {
     fn parse(
         lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+        provider: Provider,
         options: Vec<config_ast::ProviderOption>,
         ast_matches: Vec<config_ast::Match>,
+        allowed_cmds: Option<&[String]>,
+        require_secret: bool,
     ) -> Result<Self, String> {
         let mut matches = vec![Match::default()];
 
-        if let Some(config_ast::ProviderOption::ReposDir(span)) = options.first() {
-            return Err(error_at_span(lexer, *span, "Replace:\n  GitHub { reposdir = \"/path/to/reposdir\"; }\nwith:\n  GitHub {\n    match \".*\" {\n      cmd = \"/path/to/reposdir/%o/%r %e %j\";\n    }\n  }"));
+        let mut fallback_repo = None;
+        let mut full_name = None;
+        let mut host_header = None;
+        let mut org_repo = None;
+        let mut owner_path = None;
+        let mut repo_path = None;
+        let mut signature_header = None;
+        let mut strict_names = None;
+        for option in options {
+            match option {
+                config_ast::ProviderOption::FallbackRepo(span) => {
+                    if fallback_repo.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'fallback_repo' more than once",
+                        ));
+                    }
+                    let fallback_repo_str = unescape_str(lexer.span_str(span));
+                    match fallback_repo_str.split_once('/') {
+                        Some((o, r)) if valid_github_ownername(o) && valid_github_reponame(r) => {
+                            fallback_repo = Some((o.to_owned(), r.to_owned()))
+                        }
+                        _ => {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Must be a valid repository of the form owner/repo",
+                            ))
+                        }
+                    }
+                }
+                config_ast::ProviderOption::FullName(span, b) => {
+                    github_only(lexer, provider, span, "full_name")?;
+                    if full_name.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'full_name' more than once",
+                        ));
+                    }
+                    full_name = Some(b);
+                }
+                config_ast::ProviderOption::HostHeader(span) => {
+                    if host_header.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'host_header' more than once",
+                        ));
+                    }
+                    host_header = Some(header_name(lexer, span)?);
+                }
+                config_ast::ProviderOption::OrgRepo(span) => {
+                    github_only(lexer, provider, span, "org_repo")?;
+                    if org_repo.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'org_repo' more than once",
+                        ));
+                    }
+                    let org_repo_str = unescape_str(lexer.span_str(span));
+                    if !valid_github_reponame(&org_repo_str) {
+                        return Err(error_at_span(lexer, span, "Invalid repository name"));
+                    }
+                    org_repo = Some(org_repo_str);
+                }
+                config_ast::ProviderOption::OwnerPath(span) => {
+                    if owner_path.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'owner_path' more than once",
+                        ));
+                    }
+                    owner_path = Some(json_pointer(lexer, span)?);
+                }
+                config_ast::ProviderOption::RepoPath(span) => {
+                    if repo_path.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'repo_path' more than once",
+                        ));
+                    }
+                    repo_path = Some(json_pointer(lexer, span)?);
+                }
+                config_ast::ProviderOption::SignatureHeader(span) => {
+                    if signature_header.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'signature_header' more than once",
+                        ));
+                    }
+                    signature_header = Some(header_name(lexer, span)?);
+                }
+                config_ast::ProviderOption::StrictNames(span, b) => {
+                    if strict_names.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'strict_names' more than once",
+                        ));
+                    }
+                    strict_names = Some(b);
+                }
+                config_ast::ProviderOption::ReposDir(span) => {
+                    return Err(error_at_span(lexer, span, "Replace:\n  GitHub { reposdir = \"/path/to/reposdir\"; }\nwith:\n  GitHub {\n    match \".*\" {\n      cmd = \"/path/to/reposdir/%o/%r %e %j\";\n    }\n  }"));
+                }
+            }
         }
 
         for m in ast_matches {
-            let re_str = format!("^{}$", unescape_str(lexer.span_str(m.re)));
-            let re = match Regex::new(&re_str) {
+            let pattern = unescape_str(lexer.span_str(m.re));
+            let re_str = format!("^{}$", pattern);
+            let re = match match_regex(&re_str) {
                 Ok(re) => re,
-                Err(e) => {
-                    return Err(error_at_span(
-                        lexer,
-                        m.re,
-                        &format!("Regular expression error: {}", e),
-                    ))
-                }
+                Err(e) => return Err(error_at_span(lexer, m.re, &e)),
             };
+            let mut allow_header_timeout = None;
+            let mut cgroup = None;
             let mut cmd = None;
+            let mut cpu_limit = None;
             let mut errorcmd = None;
+            let mut errorcmd_filter = None;
+            let mut errorcmd_retries = None;
+            let mut errorcmd_without_cmd = None;
+            let mut hook_id = None;
+            let mut host = None;
+            let mut idle_timeout = None;
+            let mut ignore_senders = None;
+            let mut json_mode = None;
+            let mut kill_group = None;
+            let mut lock = None;
+            let mut max_header_timeout = None;
+            let mut mem_limit = None;
+            let mut merge_output = None;
+            let mut quarantine_after = None;
+            let mut quarantine_cooldown = None;
             let mut queuekind = None;
+            let mut raw_body = None;
             let mut secret = None;
+            let mut syslog = None;
+            let mut syslog_priority = None;
+            let mut tail_output = None;
             let mut timeout = None;
+            let mut timeout_signal = None;
+            let mut topic = None;
+            let mut visibility = None;
+            let mut weight = None;
             for opt in m.options {
                 match opt {
-                    config_ast::PerRepoOption::Cmd(span) => {
-                        if cmd.is_some() {
+                    config_ast::PerRepoOption::AllowHeaderTimeout(span, b) => {
+                        if allow_header_timeout.is_some() {
                             return Err(error_at_span(
                                 lexer,
                                 span,
-                                "Mustn't specify 'cmd' more than once",
+                                "Mustn't specify 'allow_header_timeout' more than once",
                             ));
                         }
+                        allow_header_timeout = Some(b);
+                    }
+                    config_ast::PerRepoOption::CGroup(span) => {
+                        if cgroup.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'cgroup' more than once",
+                            ));
+                        }
+                        let cgroup_str = unescape_str(lexer.span_str(span));
+                        let p = Path::new(&cgroup_str);
+                        if cgroup_str.is_empty()
+                            || p.is_absolute()
+                            || p.components().any(|c| c == Component::ParentDir)
+                        {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "cgroup must be a relative path which does not contain '..'",
+                            ));
+                        }
+                        cgroup = Some(cgroup_str);
+                    }
+                    config_ast::PerRepoOption::Cmd(span) => {
+                        // `cmd` can be specified multiple times: the commands are run in order.
                         let cmd_str = unescape_str(lexer.span_str(span));
-                        GitHub::verify_cmd_str(&cmd_str)?;
-                        cmd = Some(cmd_str);
+                        GitHub::verify_cmd_str(&cmd_str, CmdContext::Cmd)?;
+                        if let Some(allowed_cmds) = allowed_cmds {
+                            if !cmd_allowed(&cmd_str, allowed_cmds) {
+                                return Err(error_at_span(
+                                    lexer,
+                                    span,
+                                    "Command is not under any of the 'allowed_cmds' prefixes",
+                                ));
+                            }
+                        }
+                        cmd.get_or_insert_with(Vec::new).push(cmd_str);
+                    }
+                    config_ast::PerRepoOption::CpuLimit(span) => {
+                        if cpu_limit.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'cpu_limit' more than once",
+                            ));
+                        }
+                        cpu_limit = Some(option_int(lexer, MATCH_OPTIONS, "cpu_limit", span)?);
                     }
                     config_ast::PerRepoOption::Email(span) => {
                         return Err(error_at_span(lexer, span, "Replace:\n  email = \"someone@example.com\"; }\nwith:\n  errorcmd = \"cat %s | mailx -s \\\"snare error: github.com/%o/%r\\\" someone@example.com\";"));
@@ -208,9 +1250,221 @@ impl GitHub {
                             ));
                         }
                         let errorcmd_str = unescape_str(lexer.span_str(span));
-                        GitHub::verify_errorcmd_str(&errorcmd_str)?;
+                        GitHub::verify_cmd_str(&errorcmd_str, CmdContext::ErrorCmd)?;
+                        if let Some(allowed_cmds) = allowed_cmds {
+                            if !cmd_allowed(&errorcmd_str, allowed_cmds) {
+                                return Err(error_at_span(
+                                    lexer,
+                                    span,
+                                    "Command is not under any of the 'allowed_cmds' prefixes",
+                                ));
+                            }
+                        }
                         errorcmd = Some(errorcmd_str);
                     }
+                    config_ast::PerRepoOption::ErrorCmdOn(span, codes) => {
+                        if errorcmd_filter.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'errorcmd_on' or 'errorcmd_unless' more than once",
+                            ));
+                        }
+                        errorcmd_filter =
+                            Some(ErrorCmdFilter::On(exit_codes(lexer, "errorcmd_on", codes)?));
+                    }
+                    config_ast::PerRepoOption::ErrorCmdRetries(span) => {
+                        if errorcmd_retries.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'errorcmd_retries' more than once",
+                            ));
+                        }
+                        errorcmd_retries =
+                            Some(option_int(lexer, MATCH_OPTIONS, "errorcmd_retries", span)?);
+                    }
+                    config_ast::PerRepoOption::ErrorCmdUnless(span, codes) => {
+                        if errorcmd_filter.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'errorcmd_on' or 'errorcmd_unless' more than once",
+                            ));
+                        }
+                        errorcmd_filter = Some(ErrorCmdFilter::Unless(exit_codes(
+                            lexer,
+                            "errorcmd_unless",
+                            codes,
+                        )?));
+                    }
+                    config_ast::PerRepoOption::ErrorCmdWithoutCmd(span, b) => {
+                        if errorcmd_without_cmd.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'errorcmd_without_cmd' more than once",
+                            ));
+                        }
+                        errorcmd_without_cmd = Some(b);
+                    }
+                    config_ast::PerRepoOption::HookId(span) => {
+                        github_only(lexer, provider, span, "hook_id")?;
+                        if hook_id.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'hook_id' more than once",
+                            ));
+                        }
+                        hook_id = Some(option_int(lexer, MATCH_OPTIONS, "hook_id", span)?);
+                    }
+                    config_ast::PerRepoOption::Host(span) => {
+                        if host.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'host' more than once",
+                            ));
+                        }
+                        let host_str = unescape_str(lexer.span_str(span));
+                        if host_str.is_empty() {
+                            return Err(error_at_span(lexer, span, "Host must not be empty"));
+                        }
+                        host = Some(host_str);
+                    }
+                    config_ast::PerRepoOption::IdleTimeout(span) => {
+                        if idle_timeout.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'idle_timeout' more than once",
+                            ));
+                        }
+                        idle_timeout =
+                            Some(option_int(lexer, MATCH_OPTIONS, "idle_timeout", span)?);
+                    }
+                    config_ast::PerRepoOption::IgnoreSenders(span, logins) => {
+                        github_only(lexer, provider, span, "ignore_senders")?;
+                        if ignore_senders.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'ignore_senders' more than once",
+                            ));
+                        }
+                        ignore_senders = Some(
+                            logins
+                                .into_iter()
+                                .map(|x| unescape_str(lexer.span_str(x)))
+                                .collect(),
+                        );
+                    }
+                    config_ast::PerRepoOption::JsonMode(span) => {
+                        if json_mode.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'json_mode' more than once",
+                            ));
+                        }
+                        let mode_str = unescape_str(lexer.span_str(span));
+                        match u32::from_str_radix(&mode_str, 8) {
+                            Ok(mode) if mode <= 0o777 => json_mode = Some(mode),
+                            _ => {
+                                return Err(error_at_span(
+                                    lexer,
+                                    span,
+                                    "Mode must be an octal number between 0 and 0777",
+                                ))
+                            }
+                        }
+                    }
+                    config_ast::PerRepoOption::KillGroup(span, b) => {
+                        if kill_group.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'kill_group' more than once",
+                            ));
+                        }
+                        kill_group = Some(b);
+                    }
+                    config_ast::PerRepoOption::Lock(span) => {
+                        if lock.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'lock' more than once",
+                            ));
+                        }
+                        let lock_str = unescape_str(lexer.span_str(span));
+                        if lock_str.is_empty() {
+                            return Err(error_at_span(lexer, span, "Lock name must not be empty"));
+                        }
+                        lock = Some(lock_str);
+                    }
+                    config_ast::PerRepoOption::MaxHeaderTimeout(span) => {
+                        if max_header_timeout.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'max_header_timeout' more than once",
+                            ));
+                        }
+                        max_header_timeout = Some(option_int(
+                            lexer,
+                            MATCH_OPTIONS,
+                            "max_header_timeout",
+                            span,
+                        )?);
+                    }
+                    config_ast::PerRepoOption::MemLimit(span) => {
+                        if mem_limit.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'mem_limit' more than once",
+                            ));
+                        }
+                        mem_limit = Some(option_int(lexer, MATCH_OPTIONS, "mem_limit", span)?);
+                    }
+                    config_ast::PerRepoOption::MergeOutput(span, b) => {
+                        if merge_output.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'merge_output' more than once",
+                            ));
+                        }
+                        merge_output = Some(b);
+                    }
+                    config_ast::PerRepoOption::QuarantineAfter(span) => {
+                        if quarantine_after.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'quarantine_after' more than once",
+                            ));
+                        }
+                        quarantine_after =
+                            Some(option_int(lexer, MATCH_OPTIONS, "quarantine_after", span)?);
+                    }
+                    config_ast::PerRepoOption::QuarantineCooldown(span) => {
+                        if quarantine_cooldown.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'quarantine_cooldown' more than once",
+                            ));
+                        }
+                        quarantine_cooldown = Some(option_int(
+                            lexer,
+                            MATCH_OPTIONS,
+                            "quarantine_cooldown",
+                            span,
+                        )?);
+                    }
                     config_ast::PerRepoOption::Queue(span, qkind) => {
                         if queuekind.is_some() {
                             return Err(error_at_span(
@@ -225,6 +1479,16 @@ impl GitHub {
                             config_ast::QueueKind::Sequential => QueueKind::Sequential,
                         });
                     }
+                    config_ast::PerRepoOption::RawBody(span, b) => {
+                        if raw_body.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'raw_body' more than once",
+                            ));
+                        }
+                        raw_body = Some(b);
+                    }
                     config_ast::PerRepoOption::Secret(span) => {
                         if secret.is_some() {
                             return Err(error_at_span(
@@ -235,64 +1499,231 @@ impl GitHub {
                         }
                         let sec_str = unescape_str(lexer.span_str(span));
 
-                        // Looking at the Hmac code, it seems that a key can't actually be of an
-                        // invalid length despite the API suggesting that it can be... We're
-                        // conservative and assume that it really is possible to have an invalid
-                        // length key.
+                        // We validate the key with the same algorithm (HMAC-SHA256) that
+                        // `httpserver::authenticate` uses. Looking at the Hmac code, it seems that
+                        // a key can't actually be of an invalid length despite the API suggesting
+                        // that it can be... We're conservative and assume that it really is
+                        // possible to have an invalid length key.
                         match Hmac::<Sha256>::new_from_slice(sec_str.as_bytes()) {
                             Ok(_) => (),
                             Err(InvalidLength) => {
                                 return Err(error_at_span(lexer, span, "Invalid secret key length"))
                             }
                         }
-                        secret = Some(SecStr::from(sec_str));
+                        secret = Some(SecStr::from(sec_str));
+                    }
+                    config_ast::PerRepoOption::Syslog(span, b) => {
+                        if syslog.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'syslog' more than once",
+                            ));
+                        }
+                        syslog = Some(b);
+                    }
+                    config_ast::PerRepoOption::SyslogPriority(span) => {
+                        if syslog_priority.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'syslog_priority' more than once",
+                            ));
+                        }
+                        match syslog_priority_from_str(&unescape_str(lexer.span_str(span))) {
+                            Ok(p) => syslog_priority = Some(p),
+                            Err(e) => return Err(error_at_span(lexer, span, &e)),
+                        }
+                    }
+                    config_ast::PerRepoOption::TailOutput(span) => {
+                        if tail_output.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'tail_output' more than once",
+                            ));
+                        }
+                        tail_output = Some(option_int(lexer, MATCH_OPTIONS, "tail_output", span)?);
+                    }
+                    config_ast::PerRepoOption::Timeout(span) => {
+                        if timeout.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'timeout' more than once",
+                            ));
+                        }
+                        timeout = Some(option_int(lexer, MATCH_OPTIONS, "timeout", span)?);
+                    }
+                    config_ast::PerRepoOption::TimeoutSignal(span) => {
+                        if timeout_signal.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'timeout_signal' more than once",
+                            ));
+                        }
+                        let sig_str = unescape_str(lexer.span_str(span));
+                        match timeout_signal_from_str(&sig_str) {
+                            Ok(sig) => timeout_signal = Some(sig),
+                            Err(e) => return Err(error_at_span(lexer, span, &e)),
+                        }
+                    }
+                    config_ast::PerRepoOption::Topic(span) => {
+                        if topic.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'topic' more than once",
+                            ));
+                        }
+                        topic = Some(unescape_str(lexer.span_str(span)));
+                    }
+                    config_ast::PerRepoOption::Visibility(span, vis) => {
+                        if visibility.is_some() {
+                            return Err(error_at_span(
+                                lexer,
+                                span,
+                                "Mustn't specify 'visibility' more than once",
+                            ));
+                        }
+                        visibility = Some(match vis {
+                            config_ast::Visibility::Internal => Visibility::Internal,
+                            config_ast::Visibility::Private => Visibility::Private,
+                            config_ast::Visibility::Public => Visibility::Public,
+                        });
                     }
-                    config_ast::PerRepoOption::Timeout(span) => {
-                        if timeout.is_some() {
+                    config_ast::PerRepoOption::Weight(span) => {
+                        if weight.is_some() {
                             return Err(error_at_span(
                                 lexer,
                                 span,
-                                "Mustn't specify 'timeout' more than once",
+                                "Mustn't specify 'weight' more than once",
                             ));
                         }
-                        let t = match lexer.span_str(span).parse() {
-                            Ok(t) => t,
-                            Err(e) => {
-                                return Err(error_at_span(
-                                    lexer,
-                                    span,
-                                    &format!("Invalid timeout: {}", e),
-                                ))
-                            }
-                        };
-                        timeout = Some(t);
+                        weight = Some(option_int(lexer, MATCH_OPTIONS, "weight", span)?);
                     }
                 }
             }
             matches.push(Match {
                 re,
+                pattern,
+                allow_header_timeout,
+                cgroup,
                 cmd,
+                cpu_limit,
                 errorcmd,
+                errorcmd_filter,
+                errorcmd_retries,
+                errorcmd_without_cmd,
+                hook_id,
+                host,
+                idle_timeout,
+                ignore_senders,
+                json_mode,
+                kill_group,
+                lock,
+                max_header_timeout,
+                mem_limit,
+                merge_output,
+                quarantine_after,
+                quarantine_cooldown,
                 queuekind,
+                raw_body,
                 secret,
+                syslog,
+                syslog_priority,
+                tail_output,
                 timeout,
+                timeout_signal,
+                topic,
+                visibility,
+                weight,
             });
         }
 
-        Ok(GitHub { matches })
-    }
-
-    /// Verify that the `cmd` string is valid, returning `Ok())` if so or `Err(String)` if not.
-    fn verify_cmd_str(cmd: &str) -> Result<(), String> {
-        GitHub::verify_str(cmd, &['e', 'o', 'r', 'j', '%'])
+        // Since a secret set in one `match` block can be inherited by later blocks, whether a given
+        // repository has a secret can only be known once its `RepoConfig` has been resolved, so
+        // `require_secret` is mainly enforced when requests are received. However, if no block
+        // specifies a secret, no request could ever succeed.
+        if require_secret && matches.iter().all(|m| m.secret.is_none()) {
+            return Err(
+                "'require_secret' is on but no match block specifies a 'secret'".to_owned(),
+            );
+        }
+        let (def_host_header, def_owner_path, def_repo_path, def_signature_header) = match provider
+        {
+            Provider::GitHub => (
+                "x-github-enterprise-host",
+                "/repository/owner/login",
+                "/repository/name",
+                "x-hub-signature-256",
+            ),
+            Provider::GitLab => (
+                "x-gitlab-instance",
+                "/project/namespace",
+                "/project/name",
+                "x-gitlab-token",
+            ),
+        };
+        Ok(GitHub {
+            fallback_repo,
+            full_name: full_name.unwrap_or(false),
+            host_header: host_header.unwrap_or_else(|| def_host_header.to_owned()),
+            matches,
+            match_mode: MatchMode::Cascade,
+            org_repo,
+            owner_path: owner_path.unwrap_or_else(|| def_owner_path.to_owned()),
+            repo_path: repo_path.unwrap_or_else(|| def_repo_path.to_owned()),
+            signature_header: signature_header.unwrap_or_else(|| def_signature_header.to_owned()),
+            // GitLab's naming rules differ from GitHub's, so we only check that GitLab names are
+            // safe to use in filesystem paths.
+            strict_names: strict_names.unwrap_or(provider == Provider::GitHub),
+        })
     }
 
-    /// Verify that the `errorcmd` string is valid, returning `Ok())` if so or `Err(String)` if not.
-    fn verify_errorcmd_str(errorcmd: &str) -> Result<(), String> {
-        GitHub::verify_str(errorcmd, &['e', 'o', 'r', 'j', 's', '?', 'x', '%'])
+    /// Extract the repository owner and name from the JSON payload `jv`, returning `None` if
+    /// they can't be determined. If `full_name` is set, and `jv` has a `repository.full_name` of
+    /// the form `owner/repo`, that is used in preference to `owner_path` and `repo_path`. If the
+    /// latter are not present (or are not strings), we then try, in order:
+    ///   1. `repository.full_name` (some events, e.g. for deleted or transferred repositories, may
+    ///      lack other fields).
+    ///   2. If `org_repo` is set, and `jv` has no (or a `null`) `repository` but does have an
+    ///      `organization`, the organization's name and `org_repo`.
+    ///   3. `fallback_repo`, if it is set.
+    pub fn owner_repo<'a>(&'a self, jv: &'a serde_json::Value) -> Option<(&'a str, &'a str)> {
+        let full_name = jv
+            .pointer("/repository/full_name")
+            .and_then(|x| x.as_str())
+            .and_then(|x| x.split_once('/'));
+        if let (true, Some(x)) = (self.full_name, full_name) {
+            return Some(x);
+        }
+        if let (Some(o), Some(r)) = (
+            jv.pointer(&self.owner_path).and_then(|x| x.as_str()),
+            jv.pointer(&self.repo_path).and_then(|x| x.as_str()),
+        ) {
+            return Some((o, r));
+        }
+        if full_name.is_some() {
+            return full_name;
+        }
+        if let Some(org_repo) = &self.org_repo {
+            if jv.get("repository").map_or(true, |x| x.is_null()) {
+                if let Some(o) = jv.pointer("/organization/login").and_then(|x| x.as_str()) {
+                    return Some((o, org_repo.as_str()));
+                }
+            }
+        }
+        self.fallback_repo
+            .as_ref()
+            .map(|(o, r)| (o.as_str(), r.as_str()))
     }
 
-    fn verify_str(s: &str, modifiers: &[char]) -> Result<(), String> {
+    /// Verify that the command string `s`, which will be run in context `ctx`, is valid, returning
+    /// `Ok())` if so or `Err(String)` if not.
+    fn verify_cmd_str(s: &str, ctx: CmdContext) -> Result<(), String> {
+        let modifiers = ctx.modifiers();
         let mut i = 0;
         while i < s.len() {
             if s[i..].starts_with('%') {
@@ -311,51 +1742,515 @@ impl GitHub {
         Ok(())
     }
 
-    /// Return a `RepoConfig` for `owner/repo`. Note that if the user reloads the config later,
+    /// Return the regexes, as the user wrote them, of the `match` blocks (including the default
+    /// block) which apply to `owner/repo` for a request from `host`, in the order that they are
+    /// applied.
+    pub fn applicable_regexes(&self, owner: &str, repo: &str, host: Option<&str>) -> Vec<&str> {
+        let s = format!("{}/{}", owner, repo);
+        let mut regexes = Vec::new();
+        for (i, m) in self.matches.iter().enumerate() {
+            if m.re.is_match(&s) && m.applies_to_host(host) {
+                regexes.push(m.pattern.as_str());
+                if i > 0 && self.match_mode == MatchMode::First {
+                    break;
+                }
+            }
+        }
+        regexes
+    }
+
+    /// Return a `RepoConfig` for `owner/repo` for a request from `host` (i.e. the value of the
+    /// `host_header` header, if present). Note that if the user reloads the config later,
     /// then a given repository might have two or more `RepoConfig`s with internal settings, so
     /// they should not be mixed. We return the repository's secret as a separate member as it is
     /// relatively costly to clone, and we also prefer not to duplicate it repeatedly throughout
     /// the heap.
-    pub fn repoconfig<'a>(&'a self, owner: &str, repo: &str) -> (RepoConfig, Option<&'a SecStr>) {
+    pub fn repoconfig<'a>(
+        &'a self,
+        owner: &str,
+        repo: &str,
+        host: Option<&str>,
+    ) -> (RepoConfig, Option<&'a SecStr>) {
         let s = format!("{}/{}", owner, repo);
+        let mut allow_header_timeout = None;
+        let mut cgroup = None;
         let mut cmd = None;
+        let mut cpu_limit = None;
         let mut errorcmd = None;
+        let mut errorcmd_filter = None;
+        let mut errorcmd_retries = None;
+        let mut errorcmd_without_cmd = None;
+        let mut hook_id = None;
+        let mut idle_timeout = None;
+        let mut ignore_senders = None;
+        let mut json_mode = None;
+        let mut kill_group = None;
+        let mut lock = None;
+        let mut max_header_timeout = None;
+        let mut mem_limit = None;
+        let mut merge_output = None;
+        let mut quarantine_after = None;
+        let mut quarantine_cooldown = None;
         let mut queuekind = None;
+        let mut raw_body = None;
         let mut secret = None;
+        let mut syslog = None;
+        let mut syslog_priority = None;
+        let mut tail_output = None;
         let mut timeout = None;
-        for m in &self.matches {
-            if m.re.is_match(&s) {
+        let mut timeout_signal = None;
+        let mut topic = None;
+        let mut visibility = None;
+        let mut weight = None;
+        let mut captures = serde_json::Map::new();
+        let mut matched = None;
+        for (i, m) in self.matches.iter().enumerate() {
+            if let Some(caps) = m.re.captures(&s).filter(|_| m.applies_to_host(host)) {
+                for n in m.re.capture_names().flatten() {
+                    if let Some(c) = caps.name(n) {
+                        captures.insert(n.to_owned(), c.as_str().into());
+                    }
+                }
+                matched = Some(&m.pattern);
+                if let Some(b) = m.allow_header_timeout {
+                    allow_header_timeout = Some(b);
+                }
+                if let Some(ref c) = m.cgroup {
+                    cgroup = Some(c.clone());
+                }
                 if let Some(ref c) = m.cmd {
                     cmd = Some(c.clone());
                 }
+                if let Some(c) = m.cpu_limit {
+                    cpu_limit = Some(c);
+                }
                 if let Some(ref e) = m.errorcmd {
                     errorcmd = Some(e.clone());
                 }
+                if let Some(ref f) = m.errorcmd_filter {
+                    errorcmd_filter = Some(f.clone());
+                }
+                if let Some(r) = m.errorcmd_retries {
+                    errorcmd_retries = Some(r);
+                }
+                if let Some(b) = m.errorcmd_without_cmd {
+                    errorcmd_without_cmd = Some(b);
+                }
+                if let Some(h) = m.hook_id {
+                    hook_id = Some(h);
+                }
+                if let Some(t) = m.idle_timeout {
+                    idle_timeout = Some(t);
+                }
+                if let Some(ref s) = m.ignore_senders {
+                    ignore_senders = Some(s.clone());
+                }
+                if let Some(j) = m.json_mode {
+                    json_mode = Some(j);
+                }
+                if let Some(b) = m.kill_group {
+                    kill_group = Some(b);
+                }
+                if let Some(ref l) = m.lock {
+                    lock = Some(l.clone());
+                }
+                if let Some(t) = m.max_header_timeout {
+                    max_header_timeout = Some(t);
+                }
+                if let Some(l) = m.mem_limit {
+                    mem_limit = Some(l);
+                }
+                if let Some(b) = m.merge_output {
+                    merge_output = Some(b);
+                }
+                if let Some(q) = m.quarantine_after {
+                    quarantine_after = Some(q);
+                }
+                if let Some(q) = m.quarantine_cooldown {
+                    quarantine_cooldown = Some(q);
+                }
                 if let Some(q) = m.queuekind {
                     queuekind = Some(q);
                 }
+                if let Some(b) = m.raw_body {
+                    raw_body = Some(b);
+                }
                 if let Some(ref s) = m.secret {
                     secret = Some(s);
                 }
+                if let Some(b) = m.syslog {
+                    syslog = Some(b);
+                }
+                if let Some(p) = m.syslog_priority {
+                    syslog_priority = Some(p);
+                }
+                if let Some(t) = m.tail_output {
+                    tail_output = Some(t);
+                }
                 if let Some(t) = m.timeout {
                     timeout = Some(t)
                 }
+                if let Some(s) = m.timeout_signal {
+                    timeout_signal = Some(s);
+                }
+                if let Some(ref t) = m.topic {
+                    topic = Some(t.clone());
+                }
+                if let Some(v) = m.visibility {
+                    visibility = Some(v);
+                }
+                if let Some(w) = m.weight {
+                    weight = Some(w);
+                }
+                // `self.matches[0]` is the default match, which provides values for any options
+                // not set by the first user match.
+                if i > 0 && self.match_mode == MatchMode::First {
+                    break;
+                }
             }
         }
-        // Since we know that Matches::default() provides a default queuekind and timeout, both
-        // unwraps() are safe.
+        // Since we know that Matches::default() provides a default allow_header_timeout,
+        // errorcmd_without_cmd, json_mode, kill_group, merge_output, quarantine_cooldown,
+        // queuekind, raw_body, syslog, syslog_priority, timeout, timeout_signal, and weight, and
+        // that it matches every repository, the unwraps() are safe.
         (
             RepoConfig {
-                cmd,
+                allow_header_timeout: allow_header_timeout.unwrap(),
+                cgroup,
+                cmd: cmd.unwrap_or_default(),
+                cpu_limit,
                 errorcmd,
+                errorcmd_filter,
+                errorcmd_retries: errorcmd_retries.unwrap_or(0),
+                errorcmd_without_cmd: errorcmd_without_cmd.unwrap(),
+                hook_id,
+                idle_timeout,
+                ignore_senders: ignore_senders.unwrap_or_default(),
+                json_mode: json_mode.unwrap(),
+                kill_group: kill_group.unwrap(),
+                lock,
+                matched: matched.unwrap().clone(),
+                max_header_timeout,
+                mem_limit,
+                merge_output: merge_output.unwrap(),
+                quarantine_after,
+                quarantine_cooldown: quarantine_cooldown.unwrap(),
                 queuekind: queuekind.unwrap(),
+                raw_body: raw_body.unwrap(),
+                syslog: if syslog.unwrap() {
+                    Some(syslog_priority.unwrap())
+                } else {
+                    None
+                },
+                tail_output,
                 timeout: timeout.unwrap(),
+                timeout_signal: timeout_signal.unwrap(),
+                topic,
+                visibility,
+                weight: weight.unwrap(),
+                captures,
             },
             secret,
         )
     }
 }
 
+/// Compile the `match` regex `re_str`, returning a human readable error if it is invalid or
+/// excessively large.
+fn match_regex(re_str: &str) -> Result<Regex, String> {
+    // The regex crate guarantees linear time matching, but pathological patterns (e.g. those with
+    // large nested repetitions) can still take excessive memory and time to compile.
+    RegexBuilder::new(re_str)
+        .size_limit(MAX_REGEX_SIZE)
+        .build()
+        .map_err(|e| match e {
+            regex::Error::CompiledTooBig(_) => format!(
+                "Regular expression too large: its compiled form exceeds {MAX_REGEX_SIZE} bytes"
+            ),
+            e => format!("Regular expression error: {}", e),
+        })
+}
+
+/// Map the syslog facility name `name` to the corresponding libc constant, returning `None` if it
+/// is not a facility that snare can log to.
+pub(crate) fn syslog_facility(name: &str) -> Option<c_int> {
+    match name {
+        "daemon" => Some(LOG_DAEMON),
+        "user" => Some(LOG_USER),
+        "local0" => Some(LOG_LOCAL0),
+        "local1" => Some(LOG_LOCAL1),
+        "local2" => Some(LOG_LOCAL2),
+        "local3" => Some(LOG_LOCAL3),
+        "local4" => Some(LOG_LOCAL4),
+        "local5" => Some(LOG_LOCAL5),
+        "local6" => Some(LOG_LOCAL6),
+        "local7" => Some(LOG_LOCAL7),
+        _ => None,
+    }
+}
+
+/// Convert a syslog priority of the form `[facility.]level` (e.g. `local0.info` or `notice`) into
+/// the equivalent value that can be passed to `syslog(3)`. If no facility is specified, the default
+/// facility (i.e. that passed to `openlog(3)`) is used.
+fn syslog_priority_from_str(s: &str) -> Result<c_int, String> {
+    let (facility, level) = match s.split_once('.') {
+        Some((f, l)) => {
+            let facility =
+                syslog_facility(f).ok_or_else(|| format!("Unknown syslog facility '{f}'"))?;
+            (facility, l)
+        }
+        None => (0, s),
+    };
+    let level = match level {
+        "emerg" => LOG_EMERG,
+        "alert" => LOG_ALERT,
+        "crit" => LOG_CRIT,
+        "err" => LOG_ERR,
+        "warning" => LOG_WARNING,
+        "notice" => LOG_NOTICE,
+        "info" => LOG_INFO,
+        "debug" => LOG_DEBUG,
+        _ => return Err(format!("Unknown syslog level '{level}'")),
+    };
+    Ok(facility | level)
+}
+
+/// Parse the value at `span` of the option `name` according to the kind of value `options` says
+/// it takes, which must be an integer (or list of integers), a duration, or a size. An error is
+/// returned if the value is invalid, outside the option's range, or can't be represented as a `T`.
+///
+/// # Panics
+///
+/// If `name` is not in `options`, or does not take one of the kinds of value above.
+fn option_int<T: TryFrom<i128>>(
+    lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+    options: &[(&str, OptionValue)],
+    name: &str,
+    span: Span,
+) -> Result<T, String> {
+    let val = match options.iter().find(|(n, _)| *n == name) {
+        Some((_, val)) => val,
+        None => panic!("'{name}' is not in the options table"),
+    };
+    let s = lexer.span_str(span);
+    let x = match val {
+        OptionValue::Int(range) | OptionValue::IntList(range) => match s.parse::<i64>() {
+            Ok(x) if range.contains(&x) => Ok(i128::from(x)),
+            Ok(_) if *range.end() == i64::MAX => {
+                Err(format!("{name} must be at least {}", range.start()))
+            }
+            Ok(_) => Err(format!(
+                "{name} must be between {} and {}",
+                range.start(),
+                range.end()
+            )),
+            Err(e) => Err(format!("Invalid {name}: {e}")),
+        },
+        OptionValue::Duration => parse_duration(&unescape_str(s)).map(i128::from),
+        OptionValue::Size => parse_size(&unescape_str(s)).map(i128::from),
+        _ => panic!("'{name}' does not take an integer value"),
+    }
+    .map_err(|e| error_at_span(lexer, span, &e))?;
+    T::try_from(x).map_err(|_| error_at_span(lexer, span, &format!("{name} is too large")))
+}
+
+/// Convert the spans `codes` of the option `name` into a list of process exit codes, returning an
+/// error if any of them is not in the range given in `MATCH_OPTIONS`.
+fn exit_codes(
+    lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+    name: &str,
+    codes: Vec<Span>,
+) -> Result<Vec<i32>, String> {
+    codes
+        .into_iter()
+        .map(|span| option_int(lexer, MATCH_OPTIONS, name, span))
+        .collect()
+}
+
+/// Convert `s`, which must be an `ip:port` string, into a `SocketAddr`. Hostnames are rejected
+/// (rather than resolved) since a hostname can resolve to several addresses and we can only listen
+/// on one.
+fn listen_addr(s: &str) -> Result<SocketAddr, String> {
+    let e = match SocketAddr::from_str(s) {
+        Ok(x) => return Ok(x),
+        Err(e) => e,
+    };
+    match s.rsplit_once(':') {
+        Some((host, port))
+            if !host.is_empty()
+                && !host.starts_with('[')
+                && IpAddr::from_str(host).is_err()
+                && port.parse::<u16>().is_ok() =>
+        {
+            Err(format!(
+                "listen must be an IP address, not a hostname; use e.g. 127.0.0.1:{port}"
+            ))
+        }
+        _ => Err(format!("{e} (the format is 'ip:port')")),
+    }
+}
+
+/// Convert the span `span` into a JSON pointer (as defined by RFC 6901), returning an error if it
+/// is not syntactically valid.
+fn json_pointer(
+    lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+    span: Span,
+) -> Result<String, String> {
+    let ptr = unescape_str(lexer.span_str(span));
+    if !ptr.is_empty() && !ptr.starts_with('/') {
+        return Err(error_at_span(
+            lexer,
+            span,
+            "JSON pointers must be empty or start with '/'",
+        ));
+    }
+    Ok(ptr)
+}
+
+/// Convert the span `span` into a lower case HTTP header name, returning an error if it is not
+/// syntactically valid.
+fn header_name(
+    lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+    span: Span,
+) -> Result<String, String> {
+    let hdr = unescape_str(lexer.span_str(span));
+    // HTTP header names are "tokens" (RFC 7230 section 3.2.6).
+    if hdr.is_empty()
+        || !hdr
+            .chars()
+            .all(|c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+    {
+        return Err(error_at_span(lexer, span, "Invalid HTTP header name"));
+    }
+    Ok(hdr.to_lowercase())
+}
+
+/// Is the program run by `cmd` (i.e. its first word) guaranteed to be under one of the
+/// `allowed_cmds` prefixes? Since `%` substitutions are only known when the command is run, only
+/// the part of the program before the first `%` can be checked. Since the shell could otherwise be
+/// asked to run further programs (e.g. `/allowed/x; /bin/evil`), commands containing shell
+/// metacharacters are never allowed. `%` substitutions are shell quoted when the command is run,
+/// so they cannot introduce metacharacters.
+fn cmd_allowed(cmd: &str, allowed_cmds: &[String]) -> bool {
+    if cmd.contains(|c: char| ";&|$`<>()\n".contains(c)) {
+        return false;
+    }
+    let prog = cmd.split_whitespace().next().unwrap_or("");
+    let prog = match prog.find('%') {
+        Some(i) => &prog[..i],
+        None => prog,
+    };
+    !Path::new(prog)
+        .components()
+        .any(|c| c == Component::ParentDir)
+        && allowed_cmds.iter().any(|p| prog.starts_with(p.as_str()))
+}
+
+/// Common shell builtins which are unlikely to be found in `$PATH`.
+const SHELL_BUILTINS: &[&str] = &[
+    ".", ":", "cd", "echo", "exec", "exit", "export", "false", "set", "true",
+];
+
+/// Does `prog` (the first word of a shell command) look like it can be executed? This is a
+/// heuristic: we can't know for sure without running the shell, so we err on the side of saying
+/// "yes" for anything we can't easily check.
+fn prog_exists(prog: &str) -> bool {
+    // Shell syntax and common builtins.
+    if prog.contains(|c: char| "=()[]{}$`'\"!;&|<>".contains(c)) || SHELL_BUILTINS.contains(&prog) {
+        return true;
+    }
+    if prog.contains('/') {
+        return Path::new(prog).is_file();
+    }
+    match env::var_os("PATH") {
+        Some(paths) => env::split_paths(&paths).any(|d| d.join(prog).is_file()),
+        None => true,
+    }
+}
+
+/// Parse the name of the signal to send to commands which exceed their timeout, which may be given
+/// with or without a `SIG` prefix (e.g. both "SIGINT" and "INT" are accepted). Signals which do
+/// not, by default, terminate a process are rejected.
+fn timeout_signal_from_str(s: &str) -> Result<Signal, String> {
+    let name = if s.starts_with("SIG") {
+        s.to_owned()
+    } else {
+        format!("SIG{s}")
+    };
+    match Signal::from_str(&name) {
+        Ok(
+            Signal::SIGCHLD
+            | Signal::SIGCONT
+            | Signal::SIGSTOP
+            | Signal::SIGTSTP
+            | Signal::SIGTTIN
+            | Signal::SIGTTOU
+            | Signal::SIGURG
+            | Signal::SIGWINCH,
+        ) => Err(format!("{name} does not terminate processes")),
+        Ok(sig) => Ok(sig),
+        Err(_) => Err(format!("Unknown signal '{s}'")),
+    }
+}
+
+/// GitHub specific options (e.g. `hook_id`) have no effect in a `gitlab` block, so return an error
+/// if the option `name` at `span` is used in a block for `provider` other than GitHub.
+fn github_only(
+    lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+    provider: Provider,
+    span: Span,
+    name: &str,
+) -> Result<(), String> {
+    match provider {
+        Provider::GitHub => Ok(()),
+        Provider::GitLab => Err(error_at_span(
+            lexer,
+            span,
+            &format!("'{name}' can only be specified in a 'github' block"),
+        )),
+    }
+}
+
+/// Parse the size `s`, which is a positive integer number of bytes, optionally followed by one of
+/// the (case insensitive) suffixes `K`, `M`, or `G` to specify KiB, MiB, or GiB respectively.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, mul) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1024),
+        Some((i, 'm' | 'M')) => (&s[..i], 1024 * 1024),
+        Some((i, 'g' | 'G')) => (&s[..i], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    match num.parse::<u64>() {
+        Ok(0) => Err("Size must be at least 1".to_owned()),
+        Ok(x) => x
+            .checked_mul(mul)
+            .ok_or_else(|| format!("Size '{s}' is too large")),
+        Err(_) => Err(format!(
+            "Invalid size '{s}': must be a positive integer optionally followed by K, M, or G"
+        )),
+    }
+}
+
+/// Parse the duration `s`, which is a positive integer number of seconds, optionally followed by
+/// one of the suffixes `s`, `m`, or `h` to specify seconds, minutes, or hours respectively.
+fn parse_duration(s: &str) -> Result<u64, String> {
+    let (num, mul) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        _ => (s, 1),
+    };
+    match num.parse::<u64>() {
+        Ok(0) => Err("Duration must be at least 1s".to_owned()),
+        Ok(x) => x
+            .checked_mul(mul)
+            .ok_or_else(|| format!("Duration '{s}' is too large")),
+        Err(_) => Err(format!(
+            "Invalid duration '{s}': must be a positive integer optionally followed by s, m, or h"
+        )),
+    }
+}
+
 /// Take a quoted string from the config file and unescape it (i.e. strip the start and end quote
 /// (") characters and process any escape characters in the string.)
 fn unescape_str(us: &str) -> String {
@@ -387,17 +2282,85 @@ fn unescape_str(us: &str) -> String {
 pub struct Match {
     /// The regular expression to match against full owner/repo names.
     re: Regex,
-    /// The command to run (note that this contains escape characters such as %o and %r).
-    cmd: Option<String>,
+    /// The regular expression as the user wrote it (i.e. without the anchors added to `re`).
+    pattern: String,
+    /// May the `X-Snare-Timeout` header override `timeout` for an individual request?
+    allow_header_timeout: Option<bool>,
+    /// The cgroup (relative to `/sys/fs/cgroup`) to run commands in.
+    cgroup: Option<String>,
+    /// The commands to run, in order (note that these contain escape characters such as %o and
+    /// %r).
+    cmd: Option<Vec<String>>,
+    /// The maximum CPU time (in seconds) a command can use before it is sent SIGXCPU.
+    cpu_limit: Option<u64>,
     /// An optional command to run when an error occurs (note that this contains escape characters
     /// such as %o and %r).
     errorcmd: Option<String>,
+    /// Which exit codes of `cmd` cause `errorcmd` to be run.
+    errorcmd_filter: Option<ErrorCmdFilter>,
+    /// How many times should a failing `errorcmd` be retried?
+    errorcmd_retries: Option<usize>,
+    /// Should `errorcmd` be run for requests for which there is no `cmd`?
+    errorcmd_without_cmd: Option<bool>,
+    /// Only run jobs for requests from the webhook with this ID.
+    hook_id: Option<u64>,
+    /// If set, this block only applies to requests whose host header (see
+    /// `GitHub::host_header`) is this host.
+    host: Option<String>,
+    /// The maximum time (in seconds) to allow a command to run without it producing any output
+    /// before it is terminated.
+    idle_timeout: Option<u64>,
+    /// Requests from these senders (i.e. GitHub logins) are ignored.
+    ignore_senders: Option<Vec<String>>,
+    /// The permissions of the files containing the request's payload.
+    json_mode: Option<u32>,
+    /// Should commands be run in their own process group, with timeouts signalling the whole group?
+    kill_group: Option<bool>,
+    /// The name of a lock which must be held while a job runs.
+    lock: Option<String>,
+    /// The maximum timeout (in seconds) that the `X-Snare-Timeout` header can set.
+    max_header_timeout: Option<u64>,
+    /// The maximum size (in bytes) of a command's virtual memory.
+    mem_limit: Option<u64>,
+    /// Should the command's stderr be redirected to its stdout?
+    merge_output: Option<bool>,
+    /// After how many consecutive failures should a repository be quarantined?
+    quarantine_after: Option<usize>,
+    /// How long (in seconds) does a quarantine last?
+    quarantine_cooldown: Option<u64>,
     /// The queue kind.
     queuekind: Option<QueueKind>,
+    /// Should the request body, exactly as received, be made available to commands?
+    raw_body: Option<bool>,
     /// The GitHub secret used to validate requests.
     secret: Option<SecStr>,
+    /// Should the command's stderr/stdout be forwarded to syslog?
+    syslog: Option<bool>,
+    /// The syslog priority (facility and level) that forwarded output is logged at.
+    syslog_priority: Option<c_int>,
+    /// If set, only keep this many bytes from the end of the command's stderr/stdout.
+    tail_output: Option<usize>,
     /// The maximum time to allow a command to run for before it is terminated (in seconds).
     timeout: Option<u64>,
+    /// The signal sent to commands which exceed their `timeout` or `idle_timeout`.
+    timeout_signal: Option<Signal>,
+    /// If set, only run jobs for repositories with this topic.
+    topic: Option<String>,
+    /// If set, only run jobs for repositories with this visibility.
+    visibility: Option<Visibility>,
+    /// How many of the `maxjobs` slots a job consumes.
+    weight: Option<usize>,
+}
+
+impl Match {
+    /// Does this block apply to a request from `host`? Host names are case insensitive.
+    fn applies_to_host(&self, host: Option<&str>) -> bool {
+        match (&self.host, host) {
+            (None, _) => true,
+            (Some(x), Some(y)) => x.eq_ignore_ascii_case(y),
+            (Some(_), None) => false,
+        }
+    }
 }
 
 impl Default for Match {
@@ -406,11 +2369,42 @@ impl Default for Match {
         let re = Regex::new(".*").unwrap();
         Match {
             re,
+            pattern: ".*".to_owned(),
+            allow_header_timeout: Some(false),
+            cgroup: None,
             cmd: None,
+            cpu_limit: None,
             errorcmd: None,
+            errorcmd_filter: None,
+            errorcmd_retries: None,
+            errorcmd_without_cmd: Some(false),
+            hook_id: None,
+            host: None,
+            idle_timeout: None,
+            ignore_senders: None,
+            json_mode: Some(DEFAULT_JSON_MODE),
+            kill_group: Some(false),
+            lock: None,
+            max_header_timeout: None,
+            mem_limit: None,
+            merge_output: Some(false),
+            quarantine_after: None,
+            quarantine_cooldown: Some(DEFAULT_QUARANTINE_COOLDOWN),
+            // Unless overridden by `default_queue`, jobs for a given repository are run one at a
+            // time, in order. Most commands (e.g. deploying a website from a checkout) are not
+            // safe to run concurrently on the same repository, and running them in request order
+            // means that the most recent request is always the last one to take effect.
             queuekind: Some(QueueKind::Sequential),
+            raw_body: Some(false),
             secret: None,
+            syslog: Some(false),
+            syslog_priority: Some(LOG_INFO),
+            tail_output: None,
             timeout: Some(DEFAULT_TIMEOUT),
+            timeout_signal: Some(Signal::SIGTERM),
+            topic: None,
+            visibility: None,
+            weight: Some(1),
         }
     }
 }
@@ -438,11 +2432,247 @@ fn error_at_span(
 }
 
 /// The configuration for a given repository.
+#[derive(Clone)]
 pub struct RepoConfig {
-    pub cmd: Option<String>,
+    /// May the `X-Snare-Timeout` header override `timeout` for an individual request?
+    pub allow_header_timeout: bool,
+    /// The cgroup (relative to `/sys/fs/cgroup`) to run commands in.
+    pub cgroup: Option<String>,
+    /// The commands to run, in order. Each is only run if the previous one succeeded. If empty,
+    /// no command is run.
+    pub cmd: Vec<String>,
+    /// The maximum CPU time (in seconds) a command can use before the kernel sends it SIGXCPU. If
+    /// `None`, there is no limit.
+    pub cpu_limit: Option<u64>,
     pub errorcmd: Option<String>,
+    /// Which exit codes of `cmd` cause `errorcmd` to be run. If `None`, any unsuccessful exit
+    /// does so.
+    pub errorcmd_filter: Option<ErrorCmdFilter>,
+    /// How many times should `errorcmd` be retried (after a short delay) if it exits
+    /// unsuccessfully?
+    pub errorcmd_retries: usize,
+    /// Should `errorcmd` be run (with `%x` set to "none") for requests for which there is no
+    /// `cmd`?
+    pub errorcmd_without_cmd: bool,
+    /// If set, only requests whose `X-GitHub-Hook-ID` header is this ID have jobs run.
+    pub hook_id: Option<u64>,
+    /// The maximum time (in seconds) to allow a command to run without it producing any output
+    /// before it is terminated. If `None`, there is no limit.
+    pub idle_timeout: Option<u64>,
+    /// Requests from these senders (i.e. GitHub logins) are ignored.
+    pub ignore_senders: Vec<String>,
+    /// The permissions of the files containing the request's payload (i.e. those passed to commands
+    /// via `%j`, `%g`, and `%R`).
+    pub json_mode: u32,
+    /// Should commands be run in their own process group so that, when a timeout is exceeded, the
+    /// whole group (and not only the shell that runs the command) is sent SIGTERM?
+    pub kill_group: bool,
+    /// If set, the name of a lock which a job must hold while it runs: at most one job (across
+    /// all repositories) holding a given lock can run at a time.
+    pub lock: Option<String>,
+    /// The pattern of the last `match` block which matched this repository (or `.*` if only the
+    /// default match did so).
+    pub matched: String,
+    /// The maximum timeout (in seconds) that the `X-Snare-Timeout` header can set. If `None`, the
+    /// header can only lower `timeout`.
+    pub max_header_timeout: Option<u64>,
+    /// The maximum size (in bytes) of a command's virtual memory, beyond which its attempts to
+    /// allocate memory fail. If `None`, there is no limit.
+    pub mem_limit: Option<u64>,
+    /// Should the command's stderr be redirected to its stdout (so that the kernel preserves the
+    /// order in which the two are written)?
+    pub merge_output: bool,
+    /// After how many consecutive failures should a repository be quarantined? If `None`,
+    /// repositories are never quarantined.
+    pub quarantine_after: Option<usize>,
+    /// How long (in seconds) does a quarantine last?
+    pub quarantine_cooldown: u64,
     pub queuekind: QueueKind,
+    /// Should the request body, exactly as received, be made available to commands?
+    pub raw_body: bool,
+    /// If `Some`, the command's stderr/stdout should be forwarded, line by line, to syslog at the
+    /// given priority.
+    pub syslog: Option<c_int>,
+    /// If `Some`, only the last this-many bytes of the command's stderr/stdout are kept (and thus
+    /// seen by `errorcmd` via `%s`). If `None`, all output is kept.
+    pub tail_output: Option<usize>,
     pub timeout: u64,
+    /// The signal sent to commands which exceed their `timeout` or `idle_timeout`.
+    pub timeout_signal: Signal,
+    /// If set, only run jobs for repositories with this topic.
+    pub topic: Option<String>,
+    /// If set, only run jobs for repositories with this visibility.
+    pub visibility: Option<Visibility>,
+    /// How many of the `maxjobs` slots a job consumes.
+    pub weight: usize,
+    /// The named capture groups of all the regexes that matched this repository.
+    pub captures: serde_json::Map<String, serde_json::Value>,
+}
+
+impl RepoConfig {
+    /// Return a JSON representation of this `RepoConfig`. This is only intended to allow snare's
+    /// tests to check how options have been resolved.
+    #[cfg(feature = "_internal_testing")]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "allow_header_timeout": self.allow_header_timeout,
+            "cgroup": self.cgroup,
+            "cmd": self.cmd,
+            "cpu_limit": self.cpu_limit,
+            "errorcmd": self.errorcmd,
+            "errorcmd_on": match &self.errorcmd_filter {
+                Some(ErrorCmdFilter::On(x)) => Some(x),
+                _ => None,
+            },
+            "errorcmd_retries": self.errorcmd_retries,
+            "errorcmd_without_cmd": self.errorcmd_without_cmd,
+            "errorcmd_unless": match &self.errorcmd_filter {
+                Some(ErrorCmdFilter::Unless(x)) => Some(x),
+                _ => None,
+            },
+            "hook_id": self.hook_id,
+            "idle_timeout": self.idle_timeout,
+            "ignore_senders": self.ignore_senders,
+            "json_mode": format!("{:04o}", self.json_mode),
+            "kill_group": self.kill_group,
+            "lock": self.lock,
+            "matched": self.matched,
+            "max_header_timeout": self.max_header_timeout,
+            "mem_limit": self.mem_limit,
+            "merge_output": self.merge_output,
+            "quarantine_after": self.quarantine_after,
+            "quarantine_cooldown": self.quarantine_cooldown,
+            "queue": match self.queuekind {
+                QueueKind::Evict => "evict",
+                QueueKind::Parallel => "parallel",
+                QueueKind::Sequential => "sequential",
+            },
+            "raw_body": self.raw_body,
+            "syslog": self.syslog,
+            "tail_output": self.tail_output,
+            "timeout": self.timeout,
+            "timeout_signal": self.timeout_signal.as_str(),
+            "topic": self.topic,
+            "visibility": self.visibility.map(|x| x.as_str()),
+            "weight": self.weight,
+            "captures": self.captures,
+        })
+    }
+
+    /// Should `errorcmd` be run for a command which exited unsuccessfully? `status_code` is the
+    /// command's exit code, or `None` if it did not exit normally (e.g. it was terminated by a
+    /// signal), in which case `errorcmd` is always run.
+    pub fn errorcmd_wanted(&self, status_code: Option<i32>) -> bool {
+        match (&self.errorcmd_filter, status_code) {
+            (Some(ErrorCmdFilter::On(codes)), Some(x)) => codes.contains(&x),
+            (Some(ErrorCmdFilter::Unless(codes)), Some(x)) => !codes.contains(&x),
+            (None, _) | (_, None) => true,
+        }
+    }
+
+    /// Check that the GitHub payload `jv`, and the request's `X-GitHub-Hook-ID` header `hook_id`
+    /// (if present), satisfy the `hook_id`, `ignore_senders`, `topic`, and `visibility` filters
+    /// (if any), returning `Err(String)` (containing a human readable message) if not. A request
+    /// which lacks the relevant field does not satisfy the `hook_id`, `topic`, and `visibility`
+    /// filters, but does satisfy `ignore_senders`.
+    pub fn check_filters(
+        &self,
+        jv: &serde_json::Value,
+        hook_id: Option<&str>,
+    ) -> Result<(), String> {
+        if let Some(id) = self.hook_id {
+            match hook_id {
+                Some(x) if x.parse::<u64>() == Ok(id) => (),
+                Some(x) => return Err(format!("hook ID is '{x}' not '{id}'")),
+                None => return Err("request does not specify a hook ID".to_owned()),
+            }
+        }
+        if let Some(login) = jv["sender"]["login"].as_str() {
+            if self.ignore_senders.iter().any(|x| x == login) {
+                return Err(format!("sender '{login}' is ignored"));
+            }
+        }
+        if let Some(vis) = self.visibility {
+            match jv["repository"]["visibility"].as_str() {
+                Some(x) if x == vis.as_str() => (),
+                Some(x) => return Err(format!("visibility is '{x}' not '{}'", vis.as_str())),
+                None => return Err("payload does not specify a visibility".to_owned()),
+            }
+        }
+        if let Some(ref topic) = self.topic {
+            match jv["repository"]["topics"].as_array() {
+                Some(x) if x.iter().any(|t| t.as_str() == Some(topic.as_str())) => (),
+                Some(_) => return Err(format!("topic '{topic}' not present")),
+                None => return Err("payload does not specify topics".to_owned()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which unsuccessful exit codes of `cmd` cause `errorcmd` to be run.
+#[derive(Clone)]
+pub enum ErrorCmdFilter {
+    /// Only run `errorcmd` for these exit codes.
+    On(Vec<i32>),
+    /// Run `errorcmd` for all exit codes except these.
+    Unless(Vec<i32>),
+}
+
+/// A repository's visibility.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Visibility {
+    Internal,
+    Private,
+    Public,
+}
+
+impl Visibility {
+    /// The name GitHub uses for this visibility.
+    fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Internal => "internal",
+            Visibility::Private => "private",
+            Visibility::Public => "public",
+        }
+    }
+}
+
+/// The services which can send snare requests.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Provider {
+    /// Requests carrying an `X-GitHub-Event` header, authenticated by an HMAC-SHA256 signature.
+    GitHub,
+    /// Requests carrying an `X-Gitlab-Event` header, authenticated by an `X-Gitlab-Token` secret.
+    GitLab,
+}
+
+impl Provider {
+    /// The human readable name of this provider.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Provider::GitHub => "GitHub",
+            Provider::GitLab => "GitLab",
+        }
+    }
+
+    /// The name of this provider's block in snare.conf, which is also used to namespace its
+    /// repositories' queues.
+    pub fn block_name(&self) -> &'static str {
+        match self {
+            Provider::GitHub => "github",
+            Provider::GitLab => "gitlab",
+        }
+    }
+}
+
+/// How `match` blocks are applied to a given repository.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MatchMode {
+    /// All matching blocks are applied in order, with later blocks overriding earlier ones.
+    Cascade,
+    /// Only the first matching block is applied.
+    First,
 }
 
 #[derive(Clone, Copy)]
@@ -452,32 +2682,438 @@ pub enum QueueKind {
     Sequential,
 }
 
+/// The contexts in which user-specified command strings are run.
+#[derive(Clone, Copy)]
+pub(crate) enum CmdContext {
+    /// `cmd`, `everycmd`, and `ping_cmd`.
+    Cmd,
+    /// `errorcmd`.
+    ErrorCmd,
+    /// `reload_errorcmd`.
+    ReloadErrorCmd,
+}
+
+impl CmdContext {
+    /// The `%` modifiers which command strings in this context may contain. This is the only
+    /// place where modifiers are made available to a context: the `jobrunner` functions which
+    /// substitute modifiers must provide a value for each of them.
+    pub(crate) fn modifiers(self) -> &'static [char] {
+        match self {
+            CmdContext::Cmd => &['e', 'F', 'h', 'o', 'r', 'j', 'g', 'R', 'm', 'M', '%'],
+            CmdContext::ErrorCmd => &[
+                'e', 'F', 'h', 'o', 'r', 'j', 'g', 'R', 'M', 's', '?', 'x', '%',
+            ],
+            CmdContext::ReloadErrorCmd => &['s', '%'],
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::path::PathBuf;
 
     #[test]
     fn test_verify_cmd_string() {
-        assert!(GitHub::verify_cmd_str("").is_ok());
-        assert!(GitHub::verify_cmd_str("a").is_ok());
-        assert!(GitHub::verify_cmd_str("%% %e %o %r %j %%").is_ok());
-        assert!(GitHub::verify_cmd_str("%%").is_ok());
-        assert!(GitHub::verify_cmd_str("%").is_err());
-        assert!(GitHub::verify_cmd_str("a%").is_err());
-        assert!(GitHub::verify_cmd_str("%a").is_err());
-        assert!(GitHub::verify_cmd_str("%s").is_err());
+        assert!(GitHub::verify_cmd_str("", CmdContext::Cmd).is_ok());
+        assert!(GitHub::verify_cmd_str("a", CmdContext::Cmd).is_ok());
+        assert!(
+            GitHub::verify_cmd_str("%% %e %F %h %o %r %j %g %R %m %M %%", CmdContext::Cmd).is_ok()
+        );
+        assert!(GitHub::verify_cmd_str("%%", CmdContext::Cmd).is_ok());
+        assert!(GitHub::verify_cmd_str("%", CmdContext::Cmd).is_err());
+        assert!(GitHub::verify_cmd_str("a%", CmdContext::Cmd).is_err());
+        assert!(GitHub::verify_cmd_str("%a", CmdContext::Cmd).is_err());
+        assert!(GitHub::verify_cmd_str("%s", CmdContext::Cmd).is_err());
+        assert!(GitHub::verify_cmd_str("%f", CmdContext::Cmd).is_err());
     }
 
     #[test]
     fn test_verify_errorcmd_string() {
-        assert!(GitHub::verify_errorcmd_str("").is_ok());
-        assert!(GitHub::verify_errorcmd_str("a").is_ok());
-        assert!(GitHub::verify_errorcmd_str("%% %e %o %r %j %s %x %? %%").is_ok());
-        assert!(GitHub::verify_errorcmd_str("%%").is_ok());
-        assert!(GitHub::verify_errorcmd_str("%").is_err());
-        assert!(GitHub::verify_errorcmd_str("a%").is_err());
-        assert!(GitHub::verify_errorcmd_str("%a").is_err());
+        assert!(GitHub::verify_cmd_str("", CmdContext::ErrorCmd).is_ok());
+        assert!(GitHub::verify_cmd_str("a", CmdContext::ErrorCmd).is_ok());
+        assert!(GitHub::verify_cmd_str(
+            "%% %e %F %h %o %r %j %g %R %M %s %x %? %%",
+            CmdContext::ErrorCmd
+        )
+        .is_ok());
+        assert!(GitHub::verify_cmd_str("%%", CmdContext::ErrorCmd).is_ok());
+        assert!(GitHub::verify_cmd_str("%", CmdContext::ErrorCmd).is_err());
+        assert!(GitHub::verify_cmd_str("a%", CmdContext::ErrorCmd).is_err());
+        assert!(GitHub::verify_cmd_str("%a", CmdContext::ErrorCmd).is_err());
+    }
+
+    #[test]
+    fn test_verify_reload_errorcmd_string() {
+        assert!(GitHub::verify_cmd_str("a", CmdContext::ReloadErrorCmd).is_ok());
+        assert!(GitHub::verify_cmd_str("%% %s", CmdContext::ReloadErrorCmd).is_ok());
+        assert!(GitHub::verify_cmd_str("%", CmdContext::ReloadErrorCmd).is_err());
+        assert!(GitHub::verify_cmd_str("%e", CmdContext::ReloadErrorCmd).is_err());
+    }
+
+    #[test]
+    fn test_match_regex() {
+        assert!(match_regex("^a/b$").is_ok());
+        assert!(match_regex("^(a$").is_err());
+        assert!(match_regex("^(a{1000}){1000}$").is_err());
+    }
+
+    #[test]
+    fn test_applicable_regexes() {
+        let conf = Config::from_str(
+            "github { match \"o/.*\" { cmd = \"true\"; } match \"x/y\" { cmd = \"false\"; } }",
+        )
+        .unwrap();
+        let github = conf.default_provider();
+        assert_eq!(
+            github.applicable_regexes("o", "r", None),
+            vec![".*", "o/.*"]
+        );
+        assert_eq!(github.applicable_regexes("x", "y", None), vec![".*", "x/y"]);
+    }
+
+    #[test]
+    fn test_syslog_priority_from_str() {
+        assert_eq!(syslog_priority_from_str("info"), Ok(LOG_INFO));
+        assert_eq!(
+            syslog_priority_from_str("local3.err"),
+            Ok(LOG_LOCAL3 | LOG_ERR)
+        );
+        assert!(syslog_priority_from_str("").is_err());
+        assert!(syslog_priority_from_str("infoo").is_err());
+        assert!(syslog_priority_from_str("kern.info").is_err());
+        assert!(syslog_priority_from_str("local0.").is_err());
+    }
+
+    #[test]
+    fn test_default_config() {
+        let conf = Config::from_str(&Config::default_config()).unwrap();
+        assert_eq!(conf.listen, Some("127.0.0.1:8765".parse().unwrap()));
+        assert_eq!(conf.default_provider().matches.len(), 2);
+    }
+
+    #[test]
+    fn test_listen_addr() {
+        assert_eq!(
+            listen_addr("127.0.0.1:8765"),
+            Ok("127.0.0.1:8765".parse().unwrap())
+        );
+        assert_eq!(listen_addr("[::]:8765"), Ok("[::]:8765".parse().unwrap()));
+        assert_eq!(
+            listen_addr("localhost:8765"),
+            Err("listen must be an IP address, not a hostname; use e.g. 127.0.0.1:8765".to_owned())
+        );
+        assert!(listen_addr("localhost").is_err());
+        assert!(listen_addr("localhost:").is_err());
+        assert!(listen_addr(":8765").is_err());
+        assert!(listen_addr("127.0.0.1:65536").is_err());
+    }
+
+    #[test]
+    fn test_owner_repo() {
+        let jv = serde_json::json!({
+            "repository": {"owner": {"login": "o"}, "name": "r"},
+            "project": {"path": ["p", "q"]}
+        });
+        let conf = Config::from_str("github { match \".*\" { cmd = \"true\"; } }").unwrap();
+        assert_eq!(conf.default_provider().owner_repo(&jv), Some(("o", "r")));
+
+        let conf = Config::from_str(
+            "github { owner_path = \"/project/path/0\"; repo_path = \"/project/path/1\"; }",
+        )
+        .unwrap();
+        assert_eq!(conf.default_provider().owner_repo(&jv), Some(("p", "q")));
+
+        let conf = Config::from_str("github { owner_path = \"/project\"; }").unwrap();
+        assert_eq!(conf.default_provider().owner_repo(&jv), None);
+
+        let org_jv = serde_json::json!({"organization": {"login": "o"}});
+        assert_eq!(conf.default_provider().owner_repo(&org_jv), None);
+        let conf = Config::from_str("github { org_repo = \".github\"; }").unwrap();
+        assert_eq!(conf.default_provider().owner_repo(&jv), Some(("o", "r")));
+        assert_eq!(
+            conf.default_provider().owner_repo(&org_jv),
+            Some(("o", ".github"))
+        );
+        let no_owner_jv = serde_json::json!({
+            "repository": {"name": "r"},
+            "organization": {"login": "o"}
+        });
+        assert_eq!(conf.default_provider().owner_repo(&no_owner_jv), None);
+
+        let full_name_jv = serde_json::json!({
+            "repository": {"owner": {"login": "o"}, "name": "r", "full_name": "p/q"}
+        });
+        assert_eq!(
+            conf.default_provider().owner_repo(&full_name_jv),
+            Some(("o", "r"))
+        );
+        let conf = Config::from_str("github { full_name = on; }").unwrap();
+        assert_eq!(
+            conf.default_provider().owner_repo(&full_name_jv),
+            Some(("p", "q"))
+        );
+        assert_eq!(conf.default_provider().owner_repo(&jv), Some(("o", "r")));
+
+        let null_jv = serde_json::json!({
+            "repository": null,
+            "organization": {"login": "o"}
+        });
+        let conf = Config::from_str("github { org_repo = \".github\"; }").unwrap();
+        assert_eq!(
+            conf.default_provider().owner_repo(&null_jv),
+            Some(("o", ".github"))
+        );
+        let null_owner_jv = serde_json::json!({
+            "repository": {"owner": null, "name": "r", "full_name": "o/r"}
+        });
+        assert_eq!(
+            conf.default_provider().owner_repo(&null_owner_jv),
+            Some(("o", "r"))
+        );
+
+        let conf = Config::from_str("github { fallback_repo = \"f/g\"; }").unwrap();
+        assert_eq!(conf.default_provider().owner_repo(&jv), Some(("o", "r")));
+        assert_eq!(
+            conf.default_provider().owner_repo(&org_jv),
+            Some(("f", "g"))
+        );
+        assert!(Config::from_str("github { fallback_repo = \"f\"; }").is_err());
+        assert!(Config::from_str("github { fallback_repo = \"f/..\"; }").is_err());
+
+        assert!(Config::from_str("github { org_repo = \"..\"; }").is_err());
+        assert!(Config::from_str("github { owner_path = \"project\"; }").is_err());
+    }
+
+    #[test]
+    fn test_gitlab() {
+        let conf = Config::from_str("github { }").unwrap();
+        assert!(conf.gitlab.is_none());
+        assert!(conf.provider(Provider::GitLab).is_none());
+
+        let conf =
+            Config::from_str("github { } gitlab { match \".*\" { cmd = \"true\"; } }").unwrap();
+        let gitlab = conf.provider(Provider::GitLab).unwrap();
+        assert_eq!(gitlab.signature_header, "x-gitlab-token");
+        assert!(!gitlab.strict_names);
+        let jv = serde_json::json!({
+            "repository": {"owner": {"login": "o"}, "name": "r"},
+            "project": {"namespace": "p", "name": "q"}
+        });
+        let github = conf.provider(Provider::GitHub).unwrap();
+        assert_eq!(github.owner_repo(&jv), Some(("o", "r")));
+        assert_eq!(gitlab.owner_repo(&jv), Some(("p", "q")));
+
+        let conf = Config::from_str("gitlab { match \".*\" { cmd = \"true\"; } }").unwrap();
+        assert!(conf.github.is_none());
+        assert!(conf.provider(Provider::GitHub).is_none());
+        assert_eq!(conf.default_provider().owner_repo(&jv), Some(("p", "q")));
+
+        assert!(Config::from_str("").is_err());
+        assert!(Config::from_str("github { } gitlab { } gitlab { }").is_err());
+        for opt in [
+            "full_name = on;",
+            "org_repo = \".github\";",
+            "match \".*\" { hook_id = 1; }",
+            "match \".*\" { ignore_senders = [\"bot\"]; }",
+        ] {
+            assert!(Config::from_str(&format!("github {{ {opt} }}")).is_ok());
+            assert!(Config::from_str(&format!("gitlab {{ {opt} }}")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_require_secret() {
+        let with_secret = "github { match \".*\" { cmd = \"true\"; secret = \"secretsecret\"; } }";
+        let without_secret = "github { match \".*\" { cmd = \"true\"; } }";
+        assert!(Config::from_str(without_secret).is_ok());
+        assert!(Config::from_str(&format!("require_secret = off; {without_secret}")).is_ok());
+        assert!(Config::from_str(&format!("require_secret = on; {without_secret}")).is_err());
+        assert!(Config::from_str(&format!("require_secret = on; {with_secret}")).is_ok());
+        assert!(Config::from_str(&format!("{without_secret} require_secret = on;")).is_err());
+        // A secret can be inherited from an earlier match block.
+        let cascaded = "github { match \".*\" { secret = \"secretsecret\"; } \
+                        match \"o/r\" { cmd = \"true\"; } }";
+        assert!(Config::from_str(&format!("require_secret = on; {cascaded}")).is_ok());
+    }
+
+    #[test]
+    fn test_option_int() {
+        let github = "github { match \".*\" { cmd = \"true\"; } }";
+        assert!(Config::from_str(&format!("maxjobs = 1; {github}")).is_ok());
+        assert!(Config::from_str(&format!("maxjobs = 0; {github}")).is_err());
+        assert!(Config::from_str(&format!("maxjobs = -1; {github}")).is_err());
+        assert!(Config::from_str(&format!("runner_priority = -20; {github}")).is_ok());
+        assert!(Config::from_str(&format!("runner_priority = 20; {github}")).is_err());
+        assert!(Config::from_str(&format!("listen_backlog = 2147483648; {github}")).is_err());
+        let conf = |opt: &str| Config::from_str(&format!("github {{ match \".*\" {{ {opt} }} }}"));
+        assert!(conf("errorcmd_on = [1, 255];").is_ok());
+        assert!(conf("errorcmd_on = [1, 256];").is_err());
+        assert!(conf("errorcmd_unless = [0];").is_err());
+        assert!(conf("timeout = 0;").is_ok());
+        assert!(conf("weight = 0;").is_err());
+        assert!(conf("hook_id = 99999999999999999999;").is_err());
+    }
+
+    #[test]
+    fn test_max_header_timeout() {
+        let conf = Config::from_str(
+            "github { match \".*\" { max_header_timeout = 7200; } \
+                      match \"o/r\" { timeout = 60; } }",
+        )
+        .unwrap();
+        let (rconf, _) = conf.default_provider().repoconfig("o", "r", None);
+        assert_eq!(rconf.timeout, 60);
+        assert_eq!(rconf.max_header_timeout, Some(7200));
+        let conf = Config::from_str("github { }").unwrap();
+        let (rconf, _) = conf.default_provider().repoconfig("o", "r", None);
+        assert_eq!(rconf.max_header_timeout, None);
+        assert!(Config::from_str("github { match \".*\" { max_header_timeout = 0; } }").is_err());
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let conf = |t: &str| {
+            Config::from_str(&format!(
+                "github {{ match \".*\" {{ idle_timeout = {t}; }} }}"
+            ))
+        };
+        let idle_timeout = |t: &str| conf(t).unwrap().default_provider().matches[1].idle_timeout;
+        assert_eq!(idle_timeout("\"60\""), Some(60));
+        assert_eq!(idle_timeout("\"60s\""), Some(60));
+        assert_eq!(idle_timeout("\"5m\""), Some(300));
+        assert!(conf("\"0s\"").is_err());
+        assert!(conf("\"0\"").is_err());
+        assert!(conf("60").is_err());
+    }
+
+    #[test]
+    fn test_tail_output() {
+        let conf = |t: &str| {
+            Config::from_str(&format!(
+                "github {{ match \".*\" {{ tail_output = {t}; }} }}"
+            ))
+        };
+        let tail_output = |t: &str| conf(t).unwrap().default_provider().matches[1].tail_output;
+        assert_eq!(tail_output("\"100\""), Some(100));
+        assert_eq!(tail_output("\"64k\""), Some(64 * 1024));
+        assert!(conf("\"0\"").is_err());
+        assert!(conf("64").is_err());
+    }
+
+    #[test]
+    fn test_allowed_run_as() {
+        let github = "github { match \".*\" { cmd = \"true\"; } }";
+        assert!(Config::from_str(&format!("user = \"root\"; {github}")).is_ok());
+        assert!(Config::from_str(&format!("allowed_run_as = [\"ci\"]; {github}")).is_ok());
+        assert!(Config::from_str(&format!(
+            "allowed_run_as = [\"ci\", \"deploy\"]; user = \"deploy\"; {github}"
+        ))
+        .is_ok());
+        assert!(Config::from_str(&format!(
+            "user = \"root\"; allowed_run_as = [\"ci\", \"deploy\"]; {github}"
+        ))
+        .is_err());
+        assert!(Config::from_str(&format!("allowed_run_as = [\"\"]; {github}")).is_err());
+    }
+
+    #[test]
+    fn test_cmd_allowed() {
+        let allowed = ["/a/".to_owned(), "/b/c/".to_owned()];
+        assert!(cmd_allowed("/a/x", &allowed));
+        assert!(cmd_allowed("/a/x %e %j", &allowed));
+        assert!(cmd_allowed("/a/%o/%r %e", &allowed));
+        assert!(cmd_allowed("/b/c/d/e", &allowed));
+        assert!(!cmd_allowed("", &allowed));
+        assert!(!cmd_allowed("/ab/x", &allowed));
+        assert!(!cmd_allowed("/a%o/x", &allowed));
+        assert!(!cmd_allowed("/a/../x", &allowed));
+        assert!(!cmd_allowed("/b/x", &allowed));
+        assert!(!cmd_allowed("sh -c /a/x", &allowed));
+        assert!(!cmd_allowed("%o", &allowed));
+        assert!(!cmd_allowed("/a/x;rm -rf /", &allowed));
+        assert!(!cmd_allowed("/a/x && /bin/evil", &allowed));
+        assert!(!cmd_allowed("/a/x | /bin/evil", &allowed));
+        assert!(!cmd_allowed("/a/x $(/bin/evil)", &allowed));
+        assert!(!cmd_allowed("/a/x `/bin/evil`", &allowed));
+        assert!(!cmd_allowed("/a/x > /etc/passwd", &allowed));
+        assert!(!cmd_allowed("/a/x\n/bin/evil", &allowed));
+    }
+
+    #[test]
+    fn test_allowed_cmds() {
+        let allowed = "allowed_cmds = [\"/a/\"];";
+        for opt in [
+            "everycmd",
+            "on_idle",
+            "ping_cmd",
+            "reload_errorcmd",
+            "reloadcmd",
+        ] {
+            let conf = |cmd| format!("{allowed} {opt} = \"{cmd}\"; github {{ }}");
+            assert!(Config::from_str(&conf("/a/x")).is_ok());
+            assert!(Config::from_str(&conf("/b/x")).is_err());
+            assert!(Config::from_str(&conf("/a/x; /b/x")).is_err());
+        }
+        for opt in ["cmd", "errorcmd"] {
+            let conf =
+                |cmd| format!("{allowed} github {{ match \".*\" {{ {opt} = \"{cmd}\"; }} }}");
+            assert!(Config::from_str(&conf("/a/x %o")).is_ok());
+            assert!(Config::from_str(&conf("/b/x")).is_err());
+            assert!(Config::from_str(&conf("/a/x && /b/x")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_prog_exists() {
+        assert!(prog_exists("sh"));
+        assert!(prog_exists("cd"));
+        assert!(prog_exists("X=1"));
+        assert!(!prog_exists("/nonexistent/snare/prog"));
+    }
+
+    #[test]
+    fn test_timeout_signal_from_str() {
+        assert_eq!(timeout_signal_from_str("SIGINT"), Ok(Signal::SIGINT));
+        assert_eq!(timeout_signal_from_str("HUP"), Ok(Signal::SIGHUP));
+        assert_eq!(timeout_signal_from_str("SIGKILL"), Ok(Signal::SIGKILL));
+        assert!(timeout_signal_from_str("").is_err());
+        assert!(timeout_signal_from_str("SIG").is_err());
+        assert!(timeout_signal_from_str("sigint").is_err());
+        assert!(timeout_signal_from_str("SIGSTOP").is_err());
+        assert!(timeout_signal_from_str("CHLD").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("1"), Ok(1));
+        assert_eq!(parse_duration("60s"), Ok(60));
+        assert_eq!(parse_duration("2m"), Ok(120));
+        assert_eq!(parse_duration("1h"), Ok(3600));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("18446744073709551615h").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1"), Ok(1));
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("2k"), Ok(2048));
+        assert_eq!(parse_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("0M").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("18446744073709551615G").is_err());
     }
 
     #[test]
@@ -489,6 +3125,75 @@ mod test {
         assert_eq!(unescape_str("\"\\\\\""), "\\");
     }
 
+    #[test]
+    fn test_schema_complete() {
+        let kws = include_str!("config.l")
+            .lines()
+            .filter_map(|l| match l.split_once(' ') {
+                Some((kw, _)) if kw.chars().all(|c| c.is_ascii_lowercase() || c == '_') => Some(kw),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Every keyword in the lexer must either be an option, a value of a `Choice` option, or
+        // a deprecated option.
+        for &kw in &kws {
+            let found = [TOPLEVEL_OPTIONS, GITHUB_OPTIONS, MATCH_OPTIONS]
+                .iter()
+                .flat_map(|x| x.iter())
+                .any(|(name, val)| {
+                    *name == kw || matches!(val, OptionValue::Choice(x) if x.contains(&kw))
+                });
+            assert!(
+                found || DEPRECATED_OPTIONS.contains(&kw),
+                "'{kw}' missing from the options tables"
+            );
+        }
+        // Conversely, every option, and every value of a `Choice` option, must be a keyword.
+        for (name, val) in [TOPLEVEL_OPTIONS, GITHUB_OPTIONS, MATCH_OPTIONS]
+            .iter()
+            .flat_map(|x| x.iter())
+        {
+            assert!(kws.contains(name), "'{name}' is not a keyword in config.l");
+            if let OptionValue::Choice(x) = val {
+                for c in x.iter() {
+                    assert!(kws.contains(c), "'{c}' is not a keyword in config.l");
+                }
+            }
+        }
+        // The token each option's value is parsed from in the grammar must match the kind of
+        // value the tables say it takes, since `option_int` relies on that.
+        for l in include_str!("config.y").lines() {
+            let (name, rest) = match l
+                .trim_start_matches(|c: char| c == ' ' || c == '|')
+                .strip_prefix('"')
+                .and_then(|l| l.split_once("\" \"=\" "))
+            {
+                Some(x) => x,
+                None => continue,
+            };
+            let name = name.to_lowercase();
+            if DEPRECATED_OPTIONS.contains(&name.as_str()) {
+                continue;
+            }
+            let val = [TOPLEVEL_OPTIONS, GITHUB_OPTIONS, MATCH_OPTIONS]
+                .iter()
+                .flat_map(|x| x.iter())
+                .find(|(n, _)| *n == name)
+                .map(|(_, val)| val)
+                .unwrap_or_else(|| panic!("'{name}' missing from the options tables"));
+            let ok = match val {
+                OptionValue::Int(_) => rest.starts_with("\"INT\""),
+                OptionValue::IntList(_) => rest.starts_with("\"[\" Ints"),
+                OptionValue::Duration | OptionValue::Size | OptionValue::Str(_) => {
+                    rest.starts_with("\"STRING\"")
+                }
+                OptionValue::StrList(_) => rest.starts_with("\"[\" Strings"),
+                OptionValue::Block(_) | OptionValue::Choice(_) => true,
+            };
+            assert!(ok, "'{name}' takes a different kind of value in config.y");
+        }
+    }
+
     #[test]
     fn test_example_conf() {
         let mut p = PathBuf::new();
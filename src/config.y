@@ -10,15 +10,72 @@ TopLevelOptions -> Result<Vec<TopLevelOption>, ()>:
   ;
 
 TopLevelOption -> Result<TopLevelOption, ()>:
-    "GITHUB" "{" OptionsOrMatches "}" {
+    "ALLOW_ROOT" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(TopLevelOption::AllowRoot(span, b))
+    }
+  | "ALLOWED_CMDS" "=" "[" Strings "]" ";" {
+        Ok(TopLevelOption::AllowedCmds(map_err($1)?, $4?))
+    }
+  | "ALLOWED_RUN_AS" "=" "[" Strings "]" ";" {
+        Ok(TopLevelOption::AllowedRunAs(map_err($1)?, $4?))
+    }
+  | "AUDITLOG" "=" "STRING" ";" { Ok(TopLevelOption::AuditLog(map_err($3)?)) }
+  | "CHDIR" "=" "STRING" ";" { Ok(TopLevelOption::ChDir(map_err($3)?)) }
+  | "CONTENT_TYPES" "=" "[" Strings "]" ";" {
+        Ok(TopLevelOption::ContentTypes(map_err($1)?, $4?))
+    }
+  | "DEFAULT_QUEUE" "=" QueueKind ";" {
+        let (span, qkind) = $3?;
+        Ok(TopLevelOption::DefaultQueue(span, qkind))
+    }
+  | "EVERYCMD" "=" "STRING" ";" { Ok(TopLevelOption::EveryCmd(map_err($3)?)) }
+  | "FAST_ACK" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(TopLevelOption::FastAck(span, b))
+    }
+  | "GITHUB" "{" OptionsOrMatches "}" {
         let (options, matches) = $3?;
         Ok(TopLevelOption::GitHub($1.unwrap_or_else(|x| x).span(), options, matches))
     }
+  | "GITLAB" "{" OptionsOrMatches "}" {
+        let (options, matches) = $3?;
+        Ok(TopLevelOption::GitLab($1.unwrap_or_else(|x| x).span(), options, matches))
+    }
   | "LISTEN" "=" "STRING" ";" { Ok(TopLevelOption::Listen(map_err($3)?)) }
+  | "LISTEN_BACKLOG" "=" "INT" ";" { Ok(TopLevelOption::ListenBacklog(map_err($3)?)) }
+  | "MATCH_MODE" "=" MatchMode ";" {
+        let (span, mmode) = $3?;
+        Ok(TopLevelOption::MatchMode(span, mmode))
+    }
+  | "MAX_CONNS_PER_IP" "=" "INT" ";" { Ok(TopLevelOption::MaxConnsPerIp(map_err($3)?)) }
+  | "MAX_JSON_SIZE" "=" "INT" ";" { Ok(TopLevelOption::MaxJsonSize(map_err($3)?)) }
+  | "MAX_PARALLEL_GLOBAL" "=" "INT" ";" { Ok(TopLevelOption::MaxParallelGlobal(map_err($3)?)) }
+  | "MAX_REPO_QUEUES" "=" "INT" ";" { Ok(TopLevelOption::MaxRepoQueues(map_err($3)?)) }
+  | "MAX_TOTAL_QUEUE" "=" "INT" ";" { Ok(TopLevelOption::MaxTotalQueue(map_err($3)?)) }
   | "MAXJOBS" "=" "INT" ";" { Ok(TopLevelOption::MaxJobs(map_err($3)?)) }
+  | "ON_IDLE" "=" "STRING" ";" { Ok(TopLevelOption::OnIdle(map_err($3)?)) }
+  | "PING_CMD" "=" "STRING" ";" { Ok(TopLevelOption::PingCmd(map_err($3)?)) }
+  | "RELOAD_ERRORCMD" "=" "STRING" ";" { Ok(TopLevelOption::ReloadErrorCmd(map_err($3)?)) }
+  | "RELOADCMD" "=" "STRING" ";" { Ok(TopLevelOption::ReloadCmd(map_err($3)?)) }
+  | "REQUIRE_SECRET" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(TopLevelOption::RequireSecret(span, b))
+    }
+  | "RUNNER_PRIORITY" "=" "INT" ";" { Ok(TopLevelOption::RunnerPriority(map_err($3)?)) }
+  | "SOCKET_RCVBUF" "=" "INT" ";" { Ok(TopLevelOption::SocketRcvBuf(map_err($3)?)) }
+  | "SOCKET_SNDBUF" "=" "INT" ";" { Ok(TopLevelOption::SocketSndBuf(map_err($3)?)) }
+  | "TRUSTED_PROXIES" "=" "[" Strings "]" ";" {
+        Ok(TopLevelOption::TrustedProxies(map_err($1)?, $4?))
+    }
   | "USER" "=" "STRING" ";" { Ok(TopLevelOption::User(map_err($3)?)) }
   ;
 
+MatchMode -> Result<(Span, MatchMode), ()>:
+    "CASCADE" { Ok((map_err($1)?, MatchMode::Cascade)) }
+  | "FIRST" { Ok((map_err($1)?, MatchMode::First)) }
+  ;
+
 OptionsOrMatches -> Result<(Vec<ProviderOption>, Vec<Match>), ()>:
     OptionsOrMatches ProviderOption {
         let (mut options, matches) = $1?;
@@ -34,7 +91,23 @@ OptionsOrMatches -> Result<(Vec<ProviderOption>, Vec<Match>), ()>:
   ;
 
 ProviderOption -> Result<ProviderOption, ()>:
-    "REPOSDIR" "=" "STRING" ";" { Ok(ProviderOption::ReposDir(map_err($3)?)) }
+    "FALLBACK_REPO" "=" "STRING" ";" { Ok(ProviderOption::FallbackRepo(map_err($3)?)) }
+  | "FULL_NAME" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(ProviderOption::FullName(span, b))
+    }
+  | "HOST_HEADER" "=" "STRING" ";" { Ok(ProviderOption::HostHeader(map_err($3)?)) }
+  | "ORG_REPO" "=" "STRING" ";" { Ok(ProviderOption::OrgRepo(map_err($3)?)) }
+  | "OWNER_PATH" "=" "STRING" ";" { Ok(ProviderOption::OwnerPath(map_err($3)?)) }
+  | "REPO_PATH" "=" "STRING" ";" { Ok(ProviderOption::RepoPath(map_err($3)?)) }
+  | "REPOSDIR" "=" "STRING" ";" { Ok(ProviderOption::ReposDir(map_err($3)?)) }
+  | "SIGNATURE_HEADER" "=" "STRING" ";" {
+        Ok(ProviderOption::SignatureHeader(map_err($3)?))
+    }
+  | "STRICT_NAMES" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(ProviderOption::StrictNames(span, b))
+    }
   ;
 
 Match -> Result<Match, ()>:
@@ -47,15 +120,71 @@ PerRepoOptions -> Result<Vec<PerRepoOption>, ()>:
   ;
 
 PerRepoOption -> Result<PerRepoOption, ()>:
-    "CMD" "=" "STRING" ";" { Ok(PerRepoOption::Cmd(map_err($3)?)) }
+    "ALLOW_HEADER_TIMEOUT" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(PerRepoOption::AllowHeaderTimeout(span, b))
+    }
+  | "CGROUP" "=" "STRING" ";" { Ok(PerRepoOption::CGroup(map_err($3)?)) }
+  | "CMD" "=" "STRING" ";" { Ok(PerRepoOption::Cmd(map_err($3)?)) }
+  | "CPU_LIMIT" "=" "STRING" ";" { Ok(PerRepoOption::CpuLimit(map_err($3)?)) }
   | "EMAIL" "=" "STRING" ";" { Ok(PerRepoOption::Email(map_err($3)?)) }
   | "ERRORCMD" "=" "STRING" ";" { Ok(PerRepoOption::ErrorCmd(map_err($3)?)) }
+  | "ERRORCMD_ON" "=" "[" Ints "]" ";" {
+        Ok(PerRepoOption::ErrorCmdOn(map_err($1)?, $4?))
+    }
+  | "ERRORCMD_RETRIES" "=" "INT" ";" { Ok(PerRepoOption::ErrorCmdRetries(map_err($3)?)) }
+  | "ERRORCMD_UNLESS" "=" "[" Ints "]" ";" {
+        Ok(PerRepoOption::ErrorCmdUnless(map_err($1)?, $4?))
+    }
+  | "ERRORCMD_WITHOUT_CMD" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(PerRepoOption::ErrorCmdWithoutCmd(span, b))
+    }
+  | "HOOK_ID" "=" "INT" ";" { Ok(PerRepoOption::HookId(map_err($3)?)) }
+  | "HOST" "=" "STRING" ";" { Ok(PerRepoOption::Host(map_err($3)?)) }
+  | "IDLE_TIMEOUT" "=" "STRING" ";" { Ok(PerRepoOption::IdleTimeout(map_err($3)?)) }
+  | "IGNORE_SENDERS" "=" "[" Strings "]" ";" {
+        Ok(PerRepoOption::IgnoreSenders(map_err($1)?, $4?))
+    }
+  | "JSON_MODE" "=" "STRING" ";" { Ok(PerRepoOption::JsonMode(map_err($3)?)) }
+  | "KILL_GROUP" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(PerRepoOption::KillGroup(span, b))
+    }
+  | "LOCK" "=" "STRING" ";" { Ok(PerRepoOption::Lock(map_err($3)?)) }
+  | "MAX_HEADER_TIMEOUT" "=" "INT" ";" { Ok(PerRepoOption::MaxHeaderTimeout(map_err($3)?)) }
+  | "MEM_LIMIT" "=" "STRING" ";" { Ok(PerRepoOption::MemLimit(map_err($3)?)) }
+  | "MERGE_OUTPUT" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(PerRepoOption::MergeOutput(span, b))
+    }
+  | "QUARANTINE_AFTER" "=" "INT" ";" { Ok(PerRepoOption::QuarantineAfter(map_err($3)?)) }
+  | "QUARANTINE_COOLDOWN" "=" "INT" ";" {
+        Ok(PerRepoOption::QuarantineCooldown(map_err($3)?))
+    }
   | "QUEUE" "=" QueueKind ";" {
         let (span, qkind) = $3?;
         Ok(PerRepoOption::Queue(span, qkind))
     }
+  | "RAW_BODY" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(PerRepoOption::RawBody(span, b))
+    }
   | "SECRET" "=" "STRING" ";" { Ok(PerRepoOption::Secret(map_err($3)?)) }
+  | "SYSLOG" "=" OnOff ";" {
+        let (span, b) = $3?;
+        Ok(PerRepoOption::Syslog(span, b))
+    }
+  | "SYSLOG_PRIORITY" "=" "STRING" ";" { Ok(PerRepoOption::SyslogPriority(map_err($3)?)) }
+  | "TAIL_OUTPUT" "=" "STRING" ";" { Ok(PerRepoOption::TailOutput(map_err($3)?)) }
   | "TIMEOUT" "=" "INT" ";" { Ok(PerRepoOption::Timeout(map_err($3)?)) }
+  | "TIMEOUT_SIGNAL" "=" "STRING" ";" { Ok(PerRepoOption::TimeoutSignal(map_err($3)?)) }
+  | "TOPIC" "=" "STRING" ";" { Ok(PerRepoOption::Topic(map_err($3)?)) }
+  | "VISIBILITY" "=" Visibility ";" {
+        let (span, vis) = $3?;
+        Ok(PerRepoOption::Visibility(span, vis))
+    }
+  | "WEIGHT" "=" "INT" ";" { Ok(PerRepoOption::Weight(map_err($3)?)) }
   ;
 
 QueueKind -> Result<(Span, QueueKind), ()>:
@@ -64,6 +193,27 @@ QueueKind -> Result<(Span, QueueKind), ()>:
   | "SEQUENTIAL" { Ok((map_err($1)?, QueueKind::Sequential)) }
   ;
 
+Visibility -> Result<(Span, Visibility), ()>:
+    "INTERNAL" { Ok((map_err($1)?, Visibility::Internal)) }
+  | "PRIVATE" { Ok((map_err($1)?, Visibility::Private)) }
+  | "PUBLIC" { Ok((map_err($1)?, Visibility::Public)) }
+  ;
+
+Ints -> Result<Vec<Span>, ()>:
+    Ints "," "INT" { flattenr($1, map_err($3)) }
+  | "INT" { Ok(vec![map_err($1)?]) }
+  ;
+
+Strings -> Result<Vec<Span>, ()>:
+    Strings "," "STRING" { flattenr($1, map_err($3)) }
+  | "STRING" { Ok(vec![map_err($1)?]) }
+  ;
+
+OnOff -> Result<(Span, bool), ()>:
+    "ON" { Ok((map_err($1)?, true)) }
+  | "OFF" { Ok((map_err($1)?, false)) }
+  ;
+
 // This rule helps turn lexing errors into parsing errors.
 Unknown -> ():
     "UNKNOWN" { }
@@ -76,7 +226,7 @@ use lrpar::Span;
 
 type StorageT = u8;
 
-use crate::config_ast::{TopLevelOption, Match, PerRepoOption, ProviderOption, QueueKind};
+use crate::config_ast::{TopLevelOption, Match, MatchMode, PerRepoOption, ProviderOption, QueueKind, Visibility};
 
 fn map_err(r: Result<DefaultLexeme<StorageT>, DefaultLexeme<StorageT>>)
     -> Result<Span, ()>
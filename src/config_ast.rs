@@ -1,14 +1,53 @@
 use lrpar::Span;
 
 pub enum TopLevelOption {
+    AllowRoot(Span, bool),
+    AllowedCmds(Span, Vec<Span>),
+    AllowedRunAs(Span, Vec<Span>),
+    AuditLog(Span),
+    ChDir(Span),
+    ContentTypes(Span, Vec<Span>),
+    DefaultQueue(Span, QueueKind),
+    EveryCmd(Span),
+    FastAck(Span, bool),
     GitHub(Span, Vec<ProviderOption>, Vec<Match>),
+    GitLab(Span, Vec<ProviderOption>, Vec<Match>),
     Listen(Span),
+    ListenBacklog(Span),
+    MatchMode(Span, MatchMode),
+    MaxConnsPerIp(Span),
+    MaxJsonSize(Span),
+    MaxParallelGlobal(Span),
+    MaxRepoQueues(Span),
+    MaxTotalQueue(Span),
     MaxJobs(Span),
+    OnIdle(Span),
+    PingCmd(Span),
+    ReloadErrorCmd(Span),
+    ReloadCmd(Span),
+    RequireSecret(Span, bool),
+    RunnerPriority(Span),
+    SocketRcvBuf(Span),
+    SocketSndBuf(Span),
+    TrustedProxies(Span, Vec<Span>),
     User(Span),
 }
 
+pub enum MatchMode {
+    Cascade,
+    First,
+}
+
 pub enum ProviderOption {
+    FallbackRepo(Span),
+    FullName(Span, bool),
+    HostHeader(Span),
+    OrgRepo(Span),
+    OwnerPath(Span),
+    RepoPath(Span),
     ReposDir(Span),
+    SignatureHeader(Span),
+    StrictNames(Span, bool),
 }
 
 pub struct Match {
@@ -17,12 +56,45 @@ pub struct Match {
 }
 
 pub enum PerRepoOption {
+    AllowHeaderTimeout(Span, bool),
+    CGroup(Span),
     Cmd(Span),
+    CpuLimit(Span),
     Email(Span),
     ErrorCmd(Span),
+    ErrorCmdOn(Span, Vec<Span>),
+    ErrorCmdRetries(Span),
+    ErrorCmdUnless(Span, Vec<Span>),
+    ErrorCmdWithoutCmd(Span, bool),
+    HookId(Span),
+    Host(Span),
+    IdleTimeout(Span),
+    IgnoreSenders(Span, Vec<Span>),
+    JsonMode(Span),
+    KillGroup(Span, bool),
+    Lock(Span),
+    MaxHeaderTimeout(Span),
+    MemLimit(Span),
+    MergeOutput(Span, bool),
+    QuarantineAfter(Span),
+    QuarantineCooldown(Span),
     Queue(Span, QueueKind),
+    RawBody(Span, bool),
     Secret(Span),
+    Syslog(Span, bool),
+    SyslogPriority(Span),
+    TailOutput(Span),
     Timeout(Span),
+    TimeoutSignal(Span),
+    Topic(Span),
+    Visibility(Span, Visibility),
+    Weight(Span),
+}
+
+pub enum Visibility {
+    Internal,
+    Private,
+    Public,
 }
 
 pub enum QueueKind {
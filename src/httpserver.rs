@@ -1,22 +1,40 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
+    env,
     error::Error,
+    fmt,
+    fs::OpenOptions,
     io::{BufRead, BufReader, Read, Write},
-    net::{Shutdown, TcpListener, TcpStream},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    path::Path,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Arc,
+        Arc, Mutex, MutexGuard,
     },
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use hmac::{Hmac, Mac};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{
+        bind, getsockopt, listen, setsockopt, socket, sockopt, AddressFamily, SockFlag, SockType,
+        SockaddrStorage,
+    },
+    unistd::getpid,
+};
 use percent_encoding::percent_decode;
 use secstr::SecStr;
 use sha2::Sha256;
 
-use crate::{queue::QueueJob, Snare};
+use crate::{
+    config::{Provider, QueueKind},
+    queue::QueueJob,
+    Snare,
+};
 
 /// How many connections to accept simultaneously? Limiting this number stops attackers from
 /// causing us to use too many resources.
@@ -27,8 +45,43 @@ static NET_TIMEOUT: Duration = Duration::from_secs(10);
 /// to stop large numbers of requests causing us to run out of memory.
 static MAX_HTTP_BODY_SIZE: usize = 64 * 1024;
 
-pub(crate) fn serve(snare: Arc<Snare>) -> Result<(), Box<dyn Error>> {
-    let listener = TcpListener::bind(snare.conf.lock().unwrap().listen)?;
+/// The first file descriptor passed to us by systemd socket activation (`SD_LISTEN_FDS_START` in
+/// `sd_listen_fds(3)`).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Serve incoming requests. If `inherited` is `Some`, requests are accepted from that listener
+/// (see `systemd_listener`); otherwise we bind to the `listen` address in the config. This only
+/// returns (other than on error) in `--once` mode, after a request has caused a job to be queued.
+pub(crate) fn serve(
+    snare: Arc<Snare>,
+    inherited: Option<TcpListener>,
+) -> Result<(), Box<dyn Error>> {
+    let (listen, listen_backlog, socket_rcvbuf, socket_sndbuf) = {
+        let conf = snare.lock_conf();
+        (
+            conf.listen,
+            conf.listen_backlog,
+            conf.socket_rcvbuf,
+            conf.socket_sndbuf,
+        )
+    };
+    let listener = match inherited {
+        Some(l) => l,
+        None => {
+            let listen = listen.ok_or("No 'listen' address specified")?;
+            match listen_backlog {
+                Some(backlog) => bind_with_backlog(listen, backlog)?,
+                None => TcpListener::bind(listen)?,
+            }
+        }
+    };
+    // Accepted connections inherit the listener's buffer sizes.
+    if let Some(size) = socket_rcvbuf {
+        setsockopt(listener.as_raw_fd(), sockopt::RcvBuf, &size)?;
+    }
+    if let Some(size) = socket_sndbuf {
+        setsockopt(listener.as_raw_fd(), sockopt::SndBuf, &size)?;
+    }
     #[cfg(feature = "_internal_testing")]
     {
         if let Ok(p) = std::env::var("SNARE_DEBUG_PORT_PATH") {
@@ -37,7 +90,38 @@ pub(crate) fn serve(snare: Arc<Snare>) -> Result<(), Box<dyn Error>> {
     }
 
     let active = Arc::new(AtomicUsize::new(0));
+    // How many connections each IP address has open. Only connections accepted while
+    // `max_conns_per_ip` is set are counted, and addresses are removed once they have no open
+    // connections.
+    let per_ip = Arc::new(Mutex::new(HashMap::<IpAddr, usize>::new()));
     for stream in listener.incoming().flatten() {
+        if snare.once {
+            // Requests are handled one at a time so that none are accepted after the one whose
+            // jobs we will run.
+            if request(&snare, stream) {
+                snare.once_queued.store(true, Ordering::Relaxed);
+                // Wake the jobrunner so that it notices if the jobs have already finished.
+                nix::unistd::write(snare.event_write_fd, &[0]).ok();
+                break;
+            }
+            continue;
+        }
+        let max_conns_per_ip = snare.lock_conf().max_conns_per_ip;
+        let ip = match (max_conns_per_ip, stream.peer_addr()) {
+            (Some(max), Ok(addr)) => {
+                let ip = addr.ip();
+                if !acquire_conn(&mut lock_per_ip(&per_ip), ip, max) {
+                    snare.warn(&format!(
+                        "Closing connection from {ip}: it already has {max} connections open"
+                    ));
+                    // Dropping `stream` closes the connection.
+                    continue;
+                }
+                Some(ip)
+            }
+            _ => None,
+        };
+
         // We want to keep a limit on how many threads are started concurrently, so that an
         // attacker can't DOS the machine. `active` keeps track of how many threads are (or are
         // just about to be) active. Since the common case is that we haven't hit the limit, we
@@ -54,17 +138,114 @@ pub(crate) fn serve(snare: Arc<Snare>) -> Result<(), Box<dyn Error>> {
         }
 
         let active = Arc::clone(&active);
+        let per_ip = Arc::clone(&per_ip);
         let snare = Arc::clone(&snare);
         thread::spawn(move || {
             request(&snare, stream);
+            if let Some(ip) = ip {
+                release_conn(&mut lock_per_ip(&per_ip), ip);
+            }
             active.fetch_sub(1, Ordering::Relaxed);
         });
     }
     Ok(())
 }
 
-/// Try processing an HTTP request.
-fn request(snare: &Arc<Snare>, mut stream: TcpStream) {
+/// Lock the per-IP connection counts. Unlike `Snare::lock_conf`, a poisoned lock is recovered from:
+/// `acquire_conn` and `release_conn` can't leave the counts half-updated, so the map is still
+/// consistent even if another thread `panic`ed while holding the lock.
+fn lock_per_ip(per_ip: &Mutex<HashMap<IpAddr, usize>>) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+    per_ip.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// If `ip` has fewer than `max` connections open in `per_ip`, record that it has opened another
+/// and return `true`; otherwise return `false`.
+fn acquire_conn(per_ip: &mut HashMap<IpAddr, usize>, ip: IpAddr, max: usize) -> bool {
+    let n = per_ip.entry(ip).or_insert(0);
+    if *n >= max {
+        return false;
+    }
+    *n += 1;
+    true
+}
+
+/// Record that one of `ip`'s connections (previously recorded by `acquire_conn`) has closed,
+/// removing `ip` from `per_ip` if it has no more open connections.
+fn release_conn(per_ip: &mut HashMap<IpAddr, usize>, ip: IpAddr) {
+    if let Entry::Occupied(mut e) = per_ip.entry(ip) {
+        *e.get_mut() -= 1;
+        if *e.get() == 0 {
+            e.remove();
+        }
+    }
+}
+
+/// If we have been started by systemd socket activation (i.e. `LISTEN_PID` is our PID and
+/// `LISTEN_FDS` is non-zero), return a `TcpListener` for the socket we have inherited. Since
+/// daemonising changes our PID, this must be called before daemonising.
+pub(crate) fn systemd_listener() -> Result<Option<TcpListener>, String> {
+    let (pid, fds) = match (env::var("LISTEN_PID"), env::var("LISTEN_FDS")) {
+        (Ok(pid), Ok(fds)) => (pid, fds),
+        _ => return Ok(None),
+    };
+    if pid.parse::<i32>().ok() != Some(getpid().as_raw()) {
+        return Ok(None);
+    }
+    match fds.parse::<usize>() {
+        Ok(0) => return Ok(None),
+        Ok(1) => (),
+        Ok(n) => return Err(format!("Expected 1 socket from systemd but received {n}")),
+        Err(e) => return Err(format!("Invalid LISTEN_FDS '{fds}': {e}")),
+    }
+    // Ensure that neither the environment variables nor the socket are inherited by jobs.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let fd = SD_LISTEN_FDS_START;
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+        .map_err(|e| format!("Can't set close-on-exec on systemd socket: {e}"))?;
+    match getsockopt(fd, sockopt::SockType) {
+        Ok(SockType::Stream) => (),
+        Ok(_) => return Err("Socket passed by systemd is not a stream socket".to_owned()),
+        Err(e) => return Err(format!("Invalid socket passed by systemd: {e}")),
+    }
+    Ok(Some(unsafe { TcpListener::from_raw_fd(fd) }))
+}
+
+/// Bind a `TcpListener` to `addr` with a `backlog` of pending connections. `TcpListener::bind`
+/// always uses the OS default backlog, so we have to set up the socket manually.
+fn bind_with_backlog(addr: SocketAddr, backlog: usize) -> Result<TcpListener, Box<dyn Error>> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+    // By wrapping `fd` in a `TcpListener` immediately, we ensure it is closed if any of the
+    // following calls fail.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // Mirror `TcpListener::bind`, which sets `SO_REUSEADDR` on Unix.
+    setsockopt(fd, sockopt::ReuseAddr, &true)?;
+    bind(fd, &SockaddrStorage::from(addr))?;
+    listen(fd, backlog)?;
+    Ok(listener)
+}
+
+/// Why a request was rejected. Each variant corresponds to an HTTP response code.
+enum Rejection {
+    /// The request was malformed (400).
+    BadRequest,
+    /// The request couldn't be authenticated (401).
+    Unauthorised,
+    /// The request's decoded payload is too big (413).
+    PayloadTooLarge,
+    /// The queue is full (429).
+    TooManyRequests,
+    /// Something went wrong within snare rather than with the request (500).
+    Internal,
+}
+
+/// Try processing an HTTP request, returning `true` if it caused at least one job to be queued.
+fn request(snare: &Arc<Snare>, mut stream: TcpStream) -> bool {
     match (
         stream.set_read_timeout(Some(NET_TIMEOUT)),
         stream.set_write_timeout(Some(NET_TIMEOUT)),
@@ -73,166 +254,502 @@ fn request(snare: &Arc<Snare>, mut stream: TcpStream) {
         _ => {
             snare.error("Couldn't set timeout on sockets");
             http_500(stream);
-            return;
+            return false;
         }
     }
+    // Our responses are small, so we don't want Nagle's algorithm to delay sending them. If this
+    // fails, responses might be a little slower, but nothing else is affected.
+    stream.set_nodelay(true).ok();
     let req_time = Instant::now();
     let (headers, body) = match parse_get(&mut stream) {
         Ok(x) => x,
         Err(e) => {
             snare.warn(&format!("Processing HTTP request: {e}"));
-            http_400(stream);
-            return;
+            if e.is::<NotPost>() {
+                http_405(stream);
+            } else {
+                http_400(stream);
+            }
+            return false;
         }
     };
     if stream.shutdown(Shutdown::Read).is_err() {
         http_400(stream);
-        return;
+        return false;
     }
 
-    let event_type = match headers.get("x-github-event") {
-        Some(x) => x,
-        None => {
-            snare.warn("HTTP request: X-Github-Event header missing");
+    #[cfg(feature = "_internal_testing")]
+    {
+        if let Some(owner_repo) = headers.get("x-snare-debug-repoconfig") {
+            debug_repoconfig(snare, stream, &headers, owner_repo);
+            return false;
+        }
+    }
+
+    // Requests are dispatched to a provider based on which event header they carry.
+    let (provider, event_type) = if let Some(x) = headers.get("x-gitlab-event") {
+        match gitlab_event_type(x) {
+            Some(event_type) => (Provider::GitLab, event_type),
+            None => {
+                snare.warn(&format!("Invalid GitLab event type '{x}'"));
+                http_400(stream);
+                return false;
+            }
+        }
+    } else {
+        let event_type = match headers.get("x-github-event") {
+            Some(x) => x,
+            None => {
+                snare.warn("HTTP request: X-Github-Event header missing");
+                http_400(stream);
+                return false;
+            }
+        };
+        if !valid_github_event(event_type) {
+            snare.warn("Invalid GitHub event type '{event_type}'");
             http_400(stream);
-            return;
+            return false;
         }
+        (Provider::GitHub, event_type.to_owned())
     };
-    if !valid_github_event(event_type) {
-        snare.warn("Invalid GitHub event type '{event_type}'");
-        http_400(stream);
-        return;
+
+    let (client_ip, sig_header) = {
+        let conf = snare.lock_conf();
+        let pconf = match conf.provider(provider) {
+            Some(x) => x,
+            None => {
+                drop(conf);
+                snare.warn(&format!(
+                    "HTTP request: {} request received but no '{}' block is specified",
+                    provider.as_str(),
+                    provider.block_name()
+                ));
+                http_400(stream);
+                return false;
+            }
+        };
+        if let Some(content_type) = headers.get("content-type") {
+            if !conf.content_types.contains(content_type) {
+                drop(conf);
+                snare.warn(&format!(
+                    "HTTP request: Content-Type '{content_type}' not allowed by 'content_types'"
+                ));
+                http_415(stream);
+                return false;
+            }
+        }
+        let client_ip = stream.peer_addr().ok().map(|a| {
+            effective_ip(
+                a.ip(),
+                headers.get("x-forwarded-for").map(|x| x.as_str()),
+                &conf.trusted_proxies,
+            )
+        });
+        (client_ip, pconf.signature_header.clone())
+    };
+    // For GitHub, the signature is either of the form `sha256=<hex>` (as GitHub sends) or, as
+    // some webhook relays send, just `<hex>`. For GitLab, it is the secret token itself.
+    let sig = match headers.get(&sig_header) {
+        Some(s) if provider == Provider::GitLab => Some(s.as_str()),
+        Some(s) => match s.split_once('=') {
+            Some(("sha256", sig)) => Some(sig),
+            Some(_) => {
+                snare.warn(&format!("Incorrectly formatted {sig_header} header"));
+                http_400(stream);
+                return false;
+            }
+            None => Some(s.as_str()),
+        },
+        None => None,
+    };
+    let mut stream = Some(stream);
+    let r = process(
+        snare,
+        &mut stream,
+        req_time,
+        client_ip,
+        &headers,
+        provider,
+        &event_type,
+        sig,
+        &body,
+    );
+    match (stream, r) {
+        // If `fast_ack` is on, `process` has already acknowledged the request, so any subsequent
+        // errors can only be logged (which `process` has done).
+        (None, r) => r.unwrap_or(false),
+        (Some(stream), Ok(queued)) => {
+            http_200(stream);
+            queued
+        }
+        (Some(stream), Err(rejection)) => {
+            match rejection {
+                Rejection::BadRequest => http_400(stream),
+                Rejection::Unauthorised => http_401(stream),
+                Rejection::PayloadTooLarge => http_413(stream),
+                Rejection::TooManyRequests => http_429(stream),
+                Rejection::Internal => http_500(stream),
+            }
+            false
+        }
     }
-    let sig = match headers
-        .get("x-hub-signature-256")
-        .and_then(|s| s.split_once('='))
-    {
-        Some(("sha256", sig)) => Some(sig),
-        Some(_) => {
-            snare.warn("Incorrectly formatted X-Hub-Signature-256 header");
+}
+
+/// Respond with the JSON representation of the `RepoConfig` for `owner_repo` (of the form
+/// "owner/repo"), taking into account the request's host header (if any). This allows tests to
+/// check how options have been resolved without having to observe the side effects of running
+/// commands.
+#[cfg(feature = "_internal_testing")]
+fn debug_repoconfig(
+    snare: &Arc<Snare>,
+    mut stream: TcpStream,
+    headers: &HashMap<String, String>,
+    owner_repo: &str,
+) {
+    let (owner, repo) = match owner_repo.split_once('/') {
+        Some(x) => x,
+        None => {
             http_400(stream);
             return;
         }
-        None => None,
     };
+    let jv = {
+        let conf = snare.lock_conf();
+        let pconf = conf.default_provider();
+        let host = headers.get(&pconf.host_header).map(|x| x.as_str());
+        let (rconf, secret) = pconf.repoconfig(owner, repo, host);
+        let mut jv = rconf.to_json();
+        // We only report whether a secret is set, not what it is.
+        jv["secret"] = secret.is_some().into();
+        jv
+    };
+    stream
+        .write_all(
+            format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{jv}").as_bytes(),
+        )
+        .ok();
+}
 
+/// Decode, authenticate, and (if appropriate) queue a request whose headers have already been
+/// checked, returning `Ok(true)` if at least one job was queued. `client_ip` is the effective IP
+/// address of the client (see `effective_ip`), if known. Errors are logged before `Err` is
+/// returned. If `fast_ack` is on, `stream` is taken and acknowledged as soon as the request has
+/// been authenticated, so the caller must only respond to requests whose `stream` is still `Some`.
+#[allow(clippy::too_many_arguments)]
+fn process(
+    snare: &Arc<Snare>,
+    stream: &mut Option<TcpStream>,
+    req_time: Instant,
+    client_ip: Option<IpAddr>,
+    headers: &HashMap<String, String>,
+    provider: Provider,
+    event_type: &str,
+    sig: Option<&str>,
+    body: &[u8],
+) -> Result<bool, Rejection> {
     let json_str = match headers.get("content-type").map(|x| x.as_str()) {
-        Some("application/json") => match std::str::from_utf8(&body) {
+        Some("application/json") => match std::str::from_utf8(body) {
             Ok(x) => x.to_owned(),
             Err(_) => {
                 snare.warn("JSON not valid UTF-8");
-                http_400(stream);
-                return;
+                return Err(Rejection::BadRequest);
             }
         },
         Some("application/x-www-form-urlencoded") => {
             if !body.starts_with("payload=".as_bytes()) {
                 snare.warn("Payload does not start with 'payload='");
-                http_400(stream);
-                return;
+                return Err(Rejection::BadRequest);
             }
             match percent_decode(&body[8..]).decode_utf8() {
                 Ok(x) => x.to_string(),
                 Err(_) => {
                     snare.warn("JSON not valid UTF-8");
-                    http_400(stream);
-                    return;
+                    return Err(Rejection::BadRequest);
                 }
             }
         }
         Some(x) => {
             snare.warn(&format!("HTTP request: Unknown Content-Type '{x}'"));
-            http_400(stream);
-            return;
+            return Err(Rejection::BadRequest);
         }
         None => {
             snare.warn("HTTP request: Content-Type header missing");
-            http_400(stream);
-            return;
+            return Err(Rejection::BadRequest);
         }
     };
 
+    let max_json_size = snare.lock_conf().max_json_size;
+    if let Some(max_json_size) = max_json_size {
+        if json_str.len() > max_json_size {
+            snare.warn(&format!(
+                "JSON payload of {} bytes exceeds 'max_json_size' of {max_json_size} bytes",
+                json_str.len()
+            ));
+            return Err(Rejection::PayloadTooLarge);
+        }
+    }
+    let json_str = trim_json(json_str);
     let jv = match serde_json::from_str::<serde_json::Value>(&json_str) {
         Ok(x) => x,
         Err(e) => {
             snare.warn(&format!("Can't parse JSON: {e}"));
-            http_400(stream);
-            return;
+            return Err(Rejection::BadRequest);
         }
     };
-    let (owner, repo) = match (
-        &jv["repository"]["owner"]["login"].as_str(),
-        &jv["repository"]["name"].as_str(),
-    ) {
-        (Some(o), Some(r)) => (o.to_owned(), r.to_owned()),
-        _ => {
+    let conf = snare.lock_conf();
+    // The config may have been reloaded since `request` checked that `provider` is configured.
+    let pconf = match conf.provider(provider) {
+        Some(x) => x,
+        None => {
+            drop(conf);
+            snare.warn(&format!(
+                "No '{}' block is specified",
+                provider.block_name()
+            ));
+            return Err(Rejection::BadRequest);
+        }
+    };
+    let (owner, repo) = match pconf.owner_repo(&jv) {
+        Some((o, r)) => (o.to_owned(), r.to_owned()),
+        None => {
             snare.warn("Invalid JSON");
-            http_400(stream);
-            return;
+            return Err(Rejection::BadRequest);
         }
     };
 
-    if !valid_github_ownername(owner) {
-        snare.warn("Invalid GitHub owner syntax '{owner}'.");
-        http_400(stream);
-        return;
+    if !safe_name(&owner) || (pconf.strict_names && !valid_github_ownername(&owner)) {
+        snare.warn(&format!(
+            "Invalid {} owner syntax '{owner}'.",
+            provider.as_str()
+        ));
+        return Err(Rejection::BadRequest);
     }
-    if !valid_github_reponame(repo) {
-        snare.warn("Invalid GitHub repository syntax '{repo}'.");
-        http_400(stream);
-        return;
+    if !safe_name(&repo) || (pconf.strict_names && !valid_github_reponame(&repo)) {
+        snare.warn(&format!(
+            "Invalid {} repository syntax '{repo}'.",
+            provider.as_str()
+        ));
+        return Err(Rejection::BadRequest);
     }
 
-    let conf = snare.conf.lock().unwrap();
-    let (rconf, secret) = conf.github.repoconfig(owner, repo);
+    let host = headers.get(&pconf.host_header).map(|x| x.as_str());
+    let (mut rconf, secret) = pconf.repoconfig(&owner, &repo, host);
+    let require_secret = conf.require_secret;
 
-    match (secret, sig) {
+    let authenticated = match (secret, sig) {
         (Some(secret), Some(sig)) => {
-            if !authenticate(secret, sig, &body) {
-                snare.error("Authentication failed for {owner}/{repo}.");
-                http_401(stream);
-                return;
+            let valid = match provider {
+                Provider::GitHub => authenticate(secret, sig, body),
+                Provider::GitLab => authenticate_token(secret, sig),
+            };
+            if valid {
+                Ok(true)
+            } else {
+                snare.error(&format!(
+                    "Authentication failed for {owner}/{repo} from {}.",
+                    fmt_ip(client_ip)
+                ));
+                Err(Rejection::Unauthorised)
             }
         }
         (Some(_), None) => {
             snare.error("Secret specified but request unsigned");
-            http_401(stream);
-            return;
+            Err(Rejection::Unauthorised)
+        }
+        (None, _) if require_secret => {
+            snare.error(&format!(
+                "'require_secret' is on but no secret is specified for {owner}/{repo}."
+            ));
+            Err(Rejection::Unauthorised)
         }
         (None, Some(_)) => {
             snare.error("Request was signed but no secret was specified for {owner}/{repo}.");
-            http_401(stream);
-            return;
+            Err(Rejection::Unauthorised)
         }
-        (None, None) => (),
-    }
+        (None, None) => {
+            if client_ip.map(|ip| !ip.is_loopback()).unwrap_or(true)
+                && !snare.warned_unsecured.swap(true, Ordering::Relaxed)
+            {
+                snare.warn(&format!(
+                    "Unsigned request for {owner}/{repo} from {}: no 'secret' is specified, so anyone who can connect to snare can run commands",
+                    fmt_ip(client_ip)
+                ));
+            }
+            Ok(false)
+        }
+    };
+    let auditlog = conf.auditlog.clone();
+    let everycmd = conf.everycmd.clone();
+    let fast_ack = conf.fast_ack;
+    let max_repo_queues = conf.max_repo_queues;
+    let max_total_queue = conf.max_total_queue;
+    let ping_cmd = conf.ping_cmd.clone();
     drop(conf);
 
-    let repo_id = format!("github/{}/{}", owner, repo);
-    snare.info(&format!("Received {event_type} for {repo_id}"));
+    if let Some(auditlog) = auditlog {
+        let authenticated = match authenticated {
+            Ok(true) => "yes",
+            Ok(false) => "no",
+            Err(_) => "failed",
+        };
+        audit(
+            snare,
+            &auditlog,
+            client_ip,
+            headers,
+            event_type,
+            &format!("{owner}/{repo}"),
+            authenticated,
+        );
+    }
+    authenticated?;
+
+    if fast_ack {
+        // We tell the client that we've accepted the request before we've queued it: any
+        // subsequent errors can only be logged.
+        if let Some(stream) = stream.take() {
+            http_200(stream);
+        }
+    }
+
+    if rconf.allow_header_timeout {
+        if let Some(t) = headers.get("x-snare-timeout") {
+            match t.parse::<u64>() {
+                // Unless `max_header_timeout` is set, the sender can only lower the timeout.
+                Ok(t) => rconf.timeout = t.min(rconf.max_header_timeout.unwrap_or(rconf.timeout)),
+                Err(_) => {
+                    snare.warn(&format!("Invalid X-Snare-Timeout header '{t}'"));
+                    return Err(Rejection::BadRequest);
+                }
+            }
+        }
+    }
+
+    let repo_id = format!("{}/{}/{}", provider.block_name(), owner, repo);
+    snare.info(&format!(
+        "Received {event_type} for {repo_id} from {} ({} byte payload)",
+        fmt_ip(client_ip),
+        json_str.len()
+    ));
+
+    let forced = match jv["forced"].as_bool() {
+        Some(true) => "true",
+        Some(false) => "false",
+        None => "",
+    };
+    // Note that `body` has already been checked against `MAX_HTTP_BODY_SIZE`.
+    let raw_body = if rconf.raw_body {
+        Some(body.to_owned())
+    } else {
+        None
+    };
+
+    let mut queued = false;
+    if let Some(everycmd) = everycmd {
+        // `everycmd` jobs are queued under their own repo_id so that they neither delay, nor are
+        // delayed by, the repository's real jobs. Their failure should not affect the repository
+        // either, so they have no `errorcmd` and can't cause quarantine.
+        let mut every_rconf = rconf.clone();
+        every_rconf.cmd = vec![everycmd];
+        every_rconf.errorcmd = None;
+        every_rconf.lock = None;
+        every_rconf.quarantine_after = None;
+        every_rconf.queuekind = QueueKind::Parallel;
+        let qj = QueueJob::new(
+            format!("everycmd/{owner}/{repo}"),
+            owner.clone(),
+            repo.clone(),
+            req_time,
+            event_type.to_owned(),
+            forced.to_owned(),
+            head_sha(&jv).to_owned(),
+            raw_body.clone(),
+            json_str.clone(),
+            every_rconf,
+        );
+        if snare
+            .lock_queue()
+            .push_back(qj, max_total_queue, max_repo_queues)
+            .is_err()
+        {
+            snare.warn(&format!(
+                "Queue full: not running everycmd for {event_type} for {owner}/{repo}"
+            ));
+        } else {
+            wake_runner(snare)?;
+            queued = true;
+        }
+    }
+
     if event_type == "ping" {
-        http_200(stream);
-        return;
+        match ping_cmd {
+            Some(ping_cmd) => rconf.cmd = vec![ping_cmd],
+            None => return Ok(queued),
+        }
+    } else if let Err(msg) = rconf.check_filters(
+        &jv,
+        match provider {
+            Provider::GitHub => headers.get("x-github-hook-id").map(|x| x.as_str()),
+            Provider::GitLab => None,
+        },
+    ) {
+        snare.info(&format!("Dropping {event_type} for {repo_id}: {msg}"));
+        return Ok(queued);
     }
 
     let qj = QueueJob::new(
         repo_id,
-        owner.to_owned(),
-        repo.to_owned(),
+        owner.clone(),
+        repo.clone(),
         req_time,
         event_type.to_owned(),
+        forced.to_owned(),
+        head_sha(&jv).to_owned(),
+        raw_body,
         json_str,
         rconf,
     );
-    (*snare.queue.lock().unwrap()).push_back(qj);
-    // If the write fails, it almost certainly means that the pipe is full i.e. the runner
-    // thread will be notified anyway. If something else happens to have gone wrong, then
-    // we (and the OS) are probably in deep trouble anyway...
-    nix::unistd::write(snare.event_write_fd, &[0]).ok();
+    if snare
+        .lock_queue()
+        .push_back(qj, max_total_queue, max_repo_queues)
+        .is_err()
+    {
+        snare.warn(&format!(
+            "Queue full: dropping {event_type} for {owner}/{repo}"
+        ));
+        return Err(Rejection::TooManyRequests);
+    }
+    wake_runner(snare)?;
 
-    http_200(stream);
+    Ok(true)
 }
 
+/// Tell the job runner that a job has been queued. If the pipe to the runner is full, the runner
+/// will be woken anyway. Any other failure is snare's fault rather than the client's: the job
+/// stays queued, but may not be run until something else wakes the runner, so the error is
+/// logged and reported to the client as a 500 so that monitoring can alert on it.
+fn wake_runner(snare: &Arc<Snare>) -> Result<(), Rejection> {
+    match nix::unistd::write(snare.event_write_fd, &[0]) {
+        Ok(_) | Err(Errno::EAGAIN) => Ok(()),
+        Err(e) => {
+            snare.error(&format!("Can't wake the job runner: {e}"));
+            Err(Rejection::Internal)
+        }
+    }
+}
+
+/// The error returned by `parse_get` for requests whose method is not `POST`.
+#[derive(Debug)]
+struct NotPost;
+
+impl fmt::Display for NotPost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not a POST query")
+    }
+}
+
+impl Error for NotPost {}
+
 /// A very literal, and rather unforgiving, implementation of RFC2616 (HTTP/1.1), returning the URL
 /// of GET requests: returns `Err` for anything else.
 fn parse_get(stream: &mut TcpStream) -> Result<(HashMap<String, String>, Vec<u8>), Box<dyn Error>> {
@@ -245,7 +762,7 @@ fn parse_get(stream: &mut TcpStream) -> Result<(HashMap<String, String>, Vec<u8>
     // where Method = "POST" and `SP` is a single space character.
     let req_line_sp = req_line.split(' ').collect::<Vec<_>>();
     if !matches!(req_line_sp.as_slice(), &["POST", _, _]) {
-        return Err("Not a POST query".into());
+        return Err(Box::new(NotPost));
     }
 
     // Consume rest of HTTP request
@@ -294,20 +811,60 @@ fn parse_get(stream: &mut TcpStream) -> Result<(HashMap<String, String>, Vec<u8>
     Ok((headers_map, body))
 }
 
-fn http_200(mut stream: TcpStream) {
-    stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").ok();
+/// Send an HTTP response with the status line `status` (e.g. "200 OK"), the additional header
+/// lines `headers` (each of which must end in "\r\n"), and an empty body. We never reuse
+/// connections, so we always tell the client that the connection will be closed.
+fn http_response(mut stream: TcpStream, status: &str, headers: &str) {
+    stream
+        .write_all(
+            format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n{headers}\r\n")
+                .as_bytes(),
+        )
+        .ok();
+}
+
+fn http_200(stream: TcpStream) {
+    http_response(stream, "200 OK", "");
+}
+
+fn http_400(stream: TcpStream) {
+    http_response(stream, "400 Bad Request", "");
+}
+
+fn http_401(stream: TcpStream) {
+    http_response(stream, "401 Unauthorized", "");
+}
+
+// No feature currently needs to respond with 404.
+#[allow(dead_code)]
+fn http_404(stream: TcpStream) {
+    http_response(stream, "404 Not Found", "");
+}
+
+fn http_405(stream: TcpStream) {
+    http_response(stream, "405 Method Not Allowed", "Allow: POST\r\n");
+}
+
+fn http_413(stream: TcpStream) {
+    http_response(stream, "413 Payload Too Large", "");
+}
+
+fn http_415(stream: TcpStream) {
+    http_response(stream, "415 Unsupported Media Type", "");
 }
 
-fn http_400(mut stream: TcpStream) {
-    stream.write_all(b"HTTP/1.1 400\r\n\r\n").ok();
+fn http_429(stream: TcpStream) {
+    http_response(stream, "429 Too Many Requests", "");
 }
 
-fn http_401(mut stream: TcpStream) {
-    stream.write_all(b"HTTP/1.1 401\r\n\r\n").ok();
+fn http_500(stream: TcpStream) {
+    http_response(stream, "500 Internal Server Error", "");
 }
 
-fn http_500(mut stream: TcpStream) {
-    stream.write_all(b"HTTP/1.1 500\r\n\r\n").ok();
+// No feature currently needs to respond with 503.
+#[allow(dead_code)]
+fn http_503(stream: TcpStream) {
+    http_response(stream, "503 Service Unavailable", "");
 }
 
 /// Authenticate this request and if successful return `true` (where "success" also includes "the
@@ -322,6 +879,124 @@ fn authenticate(secret: &SecStr, sig: &str, pl: &[u8]) -> bool {
     }
 }
 
+/// Is `token` (the contents of a GitLab `X-Gitlab-Token` header) the same as `secret`? The
+/// comparison takes constant time with respect to the contents of both.
+fn authenticate_token(secret: &SecStr, token: &str) -> bool {
+    // Rather than comparing the two directly, we compare their HMACs (keyed by `secret`):
+    // `verify_slice` takes constant time, and the lengths of the HMACs leak nothing about the
+    // length of `secret`.
+    let mut expected = Hmac::<Sha256>::new_from_slice(secret.unsecure()).unwrap();
+    expected.update(secret.unsecure());
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.unsecure()).unwrap();
+    mac.update(token.as_bytes());
+    mac.verify_slice(&expected.finalize().into_bytes()).is_ok()
+}
+
+/// Return the effective IP address of a client whose connection comes from `peer`. If `peer` is
+/// one of the `trusted` proxies, this is the rightmost address in the `X-Forwarded-For` header
+/// `xff` which is not itself a trusted proxy; otherwise `X-Forwarded-For` is ignored, since
+/// clients can set it to anything they want. Parsing of `xff` stops at the first malformed entry.
+fn effective_ip(peer: IpAddr, xff: Option<&str>, trusted: &[IpAddr]) -> IpAddr {
+    let mut ip = peer;
+    if let (true, Some(xff)) = (trusted.contains(&peer), xff) {
+        for x in xff.rsplit(',') {
+            match x.trim().parse::<IpAddr>() {
+                Ok(x) => {
+                    ip = x;
+                    if !trusted.contains(&x) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    ip
+}
+
+/// Remove a leading UTF-8 byte order mark, and leading/trailing whitespace, from `json_str`. Some
+/// webhook relays add these, and while they are harmless, `serde_json` rejects a leading BOM.
+fn trim_json(json_str: String) -> String {
+    let trimmed = json_str.trim_start_matches('\u{feff}').trim();
+    if trimmed.len() == json_str.len() {
+        json_str
+    } else {
+        trimmed.to_owned()
+    }
+}
+
+/// Return the SHA of the commit that the payload `jv` relates to, or the empty string if there is
+/// no such commit. Different events record the SHA in different places, so we use the first of the
+/// following that is present:
+///   * `after` (`push`; note that this is all zeros when a branch is deleted)
+///   * `head_commit.id` (`push`)
+///   * `pull_request.head.sha` (`pull_request`, `pull_request_review`, etc.)
+///   * `check_suite.head_sha` (`check_suite`)
+///   * `check_run.head_sha` (`check_run`)
+///   * `workflow_run.head_sha` (`workflow_run`)
+///   * `sha` (`status`)
+/// Values which are not non-empty hexadecimal strings are ignored.
+pub(crate) fn head_sha(jv: &serde_json::Value) -> &str {
+    [
+        "/after",
+        "/head_commit/id",
+        "/pull_request/head/sha",
+        "/check_suite/head_sha",
+        "/check_run/head_sha",
+        "/workflow_run/head_sha",
+        "/sha",
+    ]
+    .iter()
+    .filter_map(|p| jv.pointer(p).and_then(|x| x.as_str()))
+    .find(|s| !s.is_empty() && s.chars().all(|c: char| c.is_ascii_hexdigit()))
+    .unwrap_or("")
+}
+
+/// Append a JSON line recording a delivery to `auditlog`. `authenticated` is "yes" if the
+/// delivery's signature was verified, "no" if no secret was specified, or "failed" if it was
+/// rejected. Since the audit log is a record of requests rather than part of their processing,
+/// failing to write to it is logged but is not otherwise an error.
+fn audit(
+    snare: &Arc<Snare>,
+    auditlog: &Path,
+    client_ip: Option<IpAddr>,
+    headers: &HashMap<String, String>,
+    event_type: &str,
+    owner_repo: &str,
+    authenticated: &str,
+) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    let mut line = serde_json::json!({
+        "time": time,
+        "ip": client_ip.map(|x| x.to_string()),
+        "repo": owner_repo,
+        "event": event_type,
+        "delivery": headers.get("x-github-delivery"),
+        "authenticated": authenticated,
+    })
+    .to_string();
+    line.push('\n');
+    // We write the line with a single `write_all` on a file opened in append mode so that lines
+    // from concurrent requests are not interleaved.
+    if let Err(e) = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(auditlog)
+        .and_then(|mut f| f.write_all(line.as_bytes()))
+    {
+        snare.error(&format!("Can't write to auditlog {auditlog:?}: {e}"));
+    }
+}
+
+/// Format the (possibly unknown) client IP address `ip` for log messages.
+fn fmt_ip(ip: Option<IpAddr>) -> String {
+    ip.map(|x| x.to_string())
+        .unwrap_or_else(|| "unknown address".to_owned())
+}
+
 /// Is `t` a valid GitHub event type? If this function returns `true` then it is guaranteed that `t`
 /// is safe to use in file system paths.
 fn valid_github_event(t: &str) -> bool {
@@ -329,9 +1004,24 @@ fn valid_github_event(t: &str) -> bool {
     !t.is_empty() && t.chars().all(|c| c.is_ascii_lowercase() || c == '_')
 }
 
+/// Convert a GitLab `X-Gitlab-Event` header (e.g. `Merge Request Hook`) into an event type of the
+/// same form as GitHub's (e.g. `merge_request`), returning `None` if it is not of the expected
+/// form.
+fn gitlab_event_type(h: &str) -> Option<String> {
+    let t = h
+        .strip_suffix(" Hook")?
+        .to_ascii_lowercase()
+        .replace(' ', "_");
+    if valid_github_event(&t) {
+        Some(t)
+    } else {
+        None
+    }
+}
+
 /// Is `n` a valid GitHub ownername? If this function returns `true` then it is guaranteed that `n`
 /// is safe to use in file system paths.
-fn valid_github_ownername(n: &str) -> bool {
+pub(crate) fn valid_github_ownername(n: &str) -> bool {
     // You can see the rules by going to https://github.com/join, typing in something incorrect and
     // then being told the rules.
 
@@ -356,7 +1046,7 @@ fn valid_github_ownername(n: &str) -> bool {
 
 /// Is `n` a valid GitHub repository name? If this function returns `true` then it is guaranteed that `n`
 /// is safe to use in filesystem paths.
-fn valid_github_reponame(n: &str) -> bool {
+pub(crate) fn valid_github_reponame(n: &str) -> bool {
     // You can see the rules by going to https://github.com/new, typing in something incorrect and
     // then being told the rules.
 
@@ -375,10 +1065,105 @@ fn valid_github_reponame(n: &str) -> bool {
         .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
 }
 
+/// Is `n` safe to use as a component of a filesystem path? Every valid GitHub owner or repository
+/// name is safe, but not vice versa.
+fn safe_name(n: &str) -> bool {
+    if n.is_empty() || n.len() > 255 || n == "." || n == ".." {
+        return false;
+    }
+    n.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn forwarded_ip() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let peer = ip("127.0.0.1");
+        let trusted = [ip("127.0.0.1"), ip("10.0.0.1")];
+        assert_eq!(effective_ip(peer, None, &trusted), peer);
+        assert_eq!(effective_ip(peer, Some("1.2.3.4"), &[]), peer);
+        assert_eq!(effective_ip(peer, Some("1.2.3.4"), &trusted), ip("1.2.3.4"));
+        assert_eq!(
+            effective_ip(peer, Some("5.6.7.8, 1.2.3.4, 10.0.0.1"), &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            effective_ip(peer, Some("junk, 1.2.3.4"), &trusted),
+            ip("1.2.3.4")
+        );
+        assert_eq!(effective_ip(peer, Some("1.2.3.4, junk"), &trusted), peer);
+        assert_eq!(effective_ip(peer, Some("::1"), &trusted), ip("::1"));
+        assert_eq!(
+            effective_ip(ip("1.2.3.4"), Some("5.6.7.8"), &trusted),
+            ip("1.2.3.4")
+        );
+    }
+
+    #[test]
+    fn conns_per_ip() {
+        let mut per_ip = HashMap::new();
+        let a = "1.2.3.4".parse::<IpAddr>().unwrap();
+        let b = "::1".parse::<IpAddr>().unwrap();
+        assert!(acquire_conn(&mut per_ip, a, 2));
+        assert!(acquire_conn(&mut per_ip, a, 2));
+        assert!(!acquire_conn(&mut per_ip, a, 2));
+        assert!(acquire_conn(&mut per_ip, b, 2));
+        release_conn(&mut per_ip, a);
+        assert!(acquire_conn(&mut per_ip, a, 2));
+        release_conn(&mut per_ip, a);
+        release_conn(&mut per_ip, a);
+        release_conn(&mut per_ip, b);
+        assert!(per_ip.is_empty());
+    }
+
+    #[test]
+    fn bom() {
+        assert_eq!(trim_json("{}".to_owned()), "{}");
+        assert_eq!(trim_json("\u{feff}{}".to_owned()), "{}");
+        assert_eq!(
+            trim_json("\u{feff} \n{\"a\": 1}\r\n".to_owned()),
+            "{\"a\": 1}"
+        );
+        assert_eq!(trim_json(" {} ".to_owned()), "{}");
+        assert_eq!(
+            trim_json("{\"a\": \"\u{feff}\"}".to_owned()),
+            "{\"a\": \"\u{feff}\"}"
+        );
+        let jv = serde_json::from_str::<serde_json::Value>(&trim_json(
+            "\u{feff}{\"repository\": {\"name\": \"r\"}}".to_owned(),
+        ))
+        .unwrap();
+        assert_eq!(jv["repository"]["name"], "r");
+    }
+
+    #[test]
+    fn sha() {
+        assert_eq!(head_sha(&serde_json::json!({})), "");
+        assert_eq!(head_sha(&serde_json::json!({"after": "abc123"})), "abc123");
+        assert_eq!(
+            head_sha(&serde_json::json!({"after": "abc", "head_commit": {"id": "def"}})),
+            "abc"
+        );
+        assert_eq!(
+            head_sha(&serde_json::json!({"pull_request": {"head": {"sha": "def"}}})),
+            "def"
+        );
+        assert_eq!(
+            head_sha(&serde_json::json!({"check_run": {"head_sha": "0f"}})),
+            "0f"
+        );
+        assert_eq!(
+            head_sha(&serde_json::json!({"after": "$(x)", "sha": "1"})),
+            "1"
+        );
+        assert_eq!(head_sha(&serde_json::json!({"after": 1})), "");
+        assert_eq!(head_sha(&serde_json::json!({"after": ""})), "");
+    }
+
     #[test]
     fn github_event() {
         assert!(!valid_github_event(""));
@@ -399,6 +1184,28 @@ mod test {
         }
     }
 
+    #[test]
+    fn gitlab_event() {
+        assert_eq!(gitlab_event_type("Push Hook"), Some("push".to_owned()));
+        assert_eq!(
+            gitlab_event_type("Merge Request Hook"),
+            Some("merge_request".to_owned())
+        );
+        assert_eq!(gitlab_event_type("Push"), None);
+        assert_eq!(gitlab_event_type(" Hook"), None);
+        assert_eq!(gitlab_event_type("Push-2 Hook"), None);
+    }
+
+    #[test]
+    fn gitlab_token() {
+        let secret = SecStr::from("secretsecret");
+        assert!(authenticate_token(&secret, "secretsecret"));
+        assert!(!authenticate_token(&secret, ""));
+        assert!(!authenticate_token(&secret, "secretsecre"));
+        assert!(!authenticate_token(&secret, "secretsecret "));
+        assert!(!authenticate_token(&secret, "secretsecreT"));
+    }
+
     #[test]
     fn github_ownername() {
         assert!(!valid_github_ownername(""));
@@ -460,4 +1267,21 @@ mod test {
             assert!(!valid_github_reponame(&s));
         }
     }
+
+    #[test]
+    fn safe_names() {
+        assert!(!safe_name(""));
+        assert!(!safe_name("."));
+        assert!(!safe_name(".."));
+        assert!(!safe_name("a/b"));
+        assert!(!safe_name("a b"));
+        assert!(!safe_name(&"a".repeat(256)));
+
+        assert!(safe_name("a"));
+        assert!(safe_name("a--b"));
+        assert!(safe_name("-a-"));
+        assert!(safe_name("a_b.c"));
+        assert!(safe_name(&"a".repeat(255)));
+        assert!(!valid_github_ownername("a_b") && safe_name("a_b"));
+    }
 }
@@ -7,25 +7,30 @@
 #![allow(clippy::result_large_err)]
 
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
     convert::TryInto,
     env,
     error::Error,
-    fs::{self, remove_file},
-    io::{Read, Write},
+    ffi::{CStr, CString},
+    fs::{self, remove_file, OpenOptions, Permissions},
+    io::{self, BufWriter, Read, Write},
     os::unix::{
+        ffi::OsStringExt,
+        fs::PermissionsExt,
         io::{AsRawFd, RawFd},
-        process::ExitStatusExt,
+        process::{CommandExt, ExitStatusExt},
     },
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{self, Child, Command},
-    sync::Arc,
-    thread,
+    sync::{atomic::Ordering, Arc},
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
-use libc::c_int;
+use libc::{c_int, c_uint};
 use nix::{
+    errno::Errno,
     fcntl::{fcntl, FcntlArg, OFlag},
     poll::{poll, PollFd, PollFlags},
     sys::signal::{kill, Signal},
@@ -33,13 +38,37 @@ use nix::{
 };
 use tempfile::{tempdir, NamedTempFile, TempDir};
 
-use crate::{config::RepoConfig, queue::QueueJob, Snare};
+use crate::{
+    config::{QueueKind, RepoConfig},
+    queue::QueueJob,
+    Snare,
+};
 
+/// The directory under which cgroups named by the `cgroup` option are found.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
 /// The size of the temporary read buffer in bytes. Should be >= PIPE_BUF for performance reasons.
 const READBUF: usize = 8 * 1024;
+/// The size of each job's `stderrout` write buffer in bytes. Should be > READBUF so that reads are
+/// coalesced into fewer writes.
+const STDERROUT_BUF: usize = 64 * 1024;
+/// The maximum number of bytes to read from one of a job's pipes before going back around the
+/// `poll` loop. Should be >= STDERROUT_BUF so that output-heavy jobs mostly cause full-sized
+/// writes to `stderrout`.
+const MAX_DRAIN: usize = 1024 * 1024;
 /// Maximum time to wait in `poll` (in seconds) while waiting for child processes to terminate
 /// and/or because there are jobs on the queue that we haven't been able to run yet.
 const WAIT_TIMEOUT: i32 = 1;
+/// How long must we have been idle before the `on_idle` command is run?
+const ON_IDLE_DELAY: Duration = Duration::from_secs(5);
+/// How many seconds of CPU time a job which has exceeded its `cpu_limit` (and thus been sent
+/// SIGXCPU) can use before the kernel sends it SIGKILL.
+const CPU_LIMIT_GRACE: u64 = 5;
+/// The permissions of temporary files which only snare and its commands should be able to access.
+const PRIVATE_FILE_MODE: u32 = 0o600;
+/// How long (in seconds) should a failed `errorcmd` wait before it is retried?
+const ERRORCMD_RETRY_DELAY: c_uint = 5;
+/// The maximum number of bytes read from a job's `%m` status file.
+const MAX_STATUS_LEN: u64 = 1024;
 
 struct JobRunner {
     snare: Arc<Snare>,
@@ -48,38 +77,69 @@ struct JobRunner {
     /// The maximum number of jobs we will run at any one point. Note that this may not necessarily
     /// be the same value as snare.conf.maxjobs.
     maxjobs: usize,
+    /// The `maxjobs` value of the most recently loaded config. `self.maxjobs` is moved towards
+    /// this value as soon as it is safe to do so.
+    target_maxjobs: usize,
     /// The running jobs (`num_running` of which will be `Some`, the remainder `None`).
     running: Vec<Option<Job>>,
     /// How many `Some` entries are there in `self.running`?
     num_running: usize,
-    /// The running jobs, with `0..2 *num_running + 1` entries. Each pair of entries are (stderr,
-    /// stdout) for the corresponding `running` `Job` (i.e. `running[0]` has its stderr entry at
-    /// `pollfds[0]` and stdout entry at `pollfds[1]`). The `+ 1` entry is the event file
-    /// descriptor that allows the HTTP server thread to wake up the JobRunner thread.
+    /// The sum of the weights of the running jobs. This is always `<= maxjobs`, except when the
+    /// user has reduced `maxjobs` while jobs are running.
+    running_weight: usize,
+    /// Were we draining (i.e. not popping jobs from the queue) when we last checked?
+    draining: bool,
+    /// Have we started any jobs since we last became idle?
+    ran_since_idle: bool,
+    /// Has any (non-errorcmd) job failed, or been unable to start, since we started? This
+    /// determines snare's exit status in `--once` mode.
+    failed: bool,
+    /// For each repository (for which `quarantine_after` is set) whose most recent job failed:
+    /// the number of consecutive failures and, if the repository is quarantined, when that
+    /// quarantine ends.
+    failures: HashMap<String, (usize, Option<Instant>)>,
+    /// If `runner_priority` has changed this thread's nice value, the original nice value, which
+    /// jobs are reset to.
+    job_nice: Option<c_int>,
+    /// The file descriptors to `poll`. `pollfds[0]` is the event file descriptor that allows the
+    /// HTTP server thread to wake up the JobRunner thread. The remaining entries are the stderr
+    /// and stdout pipes of running jobs which have not yet been closed, densely packed so that we
+    /// don't ask `poll` to check unused slots: `pollfd_jobs` maps them back to their jobs.
     pollfds: Vec<PollFd>,
+    /// For each entry in `pollfds[1..]`, a pair `(i, stderr)` where `i` is the index of the job
+    /// in `running` and `stderr` is `true` if the entry is the job's stderr pipe (or `false` if it
+    /// is its stdout pipe).
+    pollfd_jobs: Vec<(usize, bool)>,
 }
 
 impl JobRunner {
     fn new(snare: Arc<Snare>) -> Result<Self, Box<dyn Error>> {
         let shell = env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_owned());
-        let maxjobs = snare.conf.lock().unwrap().maxjobs;
+        let maxjobs = snare.lock_conf().maxjobs;
         assert!(maxjobs <= (std::usize::MAX - 1) / 2);
         let mut running = Vec::with_capacity(maxjobs);
         running.resize_with(maxjobs, || None);
-        let mut pollfds = Vec::with_capacity(maxjobs * 2 + 1);
-        pollfds.resize_with(maxjobs * 2 + 1, || PollFd::new(-1, PollFlags::empty()));
         Ok(JobRunner {
             snare,
             shell,
             maxjobs,
+            target_maxjobs: maxjobs,
             running,
             num_running: 0,
-            pollfds,
+            running_weight: 0,
+            draining: false,
+            ran_since_idle: false,
+            failed: false,
+            failures: HashMap::new(),
+            job_nice: None,
+            pollfds: Vec::with_capacity(maxjobs * 2 + 1),
+            pollfd_jobs: Vec::with_capacity(maxjobs * 2),
         })
     }
 
-    /// Listen for new jobs on the queue and then run them.
-    fn attend(&mut self) {
+    /// Listen for new jobs on the queue and then run them. This only returns in `--once` mode, once
+    /// the request's jobs have finished, returning `true` if they all succeeded.
+    fn attend(&mut self) -> bool {
         self.update_pollfds();
         // `check_queue` serves two subtly different purposes:
         //   * Has the event pipe told us there are new jobs in the queue?
@@ -90,13 +150,17 @@ impl JobRunner {
         // The earliest finish_by time of any running process (i.e. the process that will timeout
         // the soonest).
         let mut next_finish_by: Option<Instant> = None;
+        // If we've become idle, the time at which the `on_idle` command should be run (assuming
+        // that we remain idle until then).
+        let mut idle_at: Option<Instant> = None;
         loop {
             // If there are jobs on the queue we haven't been able to run for temporary reasons,
             // then wait a short amount of time and try again.
             let mut timeout = if check_queue { WAIT_TIMEOUT * 1000 } else { -1 };
-            // If any processes will exceed their timeout then, if that's shorter than the above
-            // timeout, only wait for enough time to pass before we need to send them SIGTERM.
-            if let Some(fby) = next_finish_by {
+            // If any processes will exceed their timeout (or we will need to run the `on_idle`
+            // command) then, if that's shorter than the above timeout, only wait for enough time to
+            // pass before we need to send them SIGTERM (or run the command).
+            for fby in [next_finish_by, idle_at].iter().flatten() {
                 let fby_timeout = fby.saturating_duration_since(Instant::now());
                 if timeout == -1
                     || fby_timeout < Duration::from_millis(timeout.try_into().unwrap_or(0))
@@ -108,89 +172,104 @@ impl JobRunner {
                 }
             }
             poll(&mut self.pollfds, timeout).ok();
+            // `update_pollfds` rebuilds `pollfds`, losing the events `poll` returned, so we take a
+            // copy of them before doing anything else.
+            let event_flags = self.pollfds[0].revents();
+            let job_events = self
+                .pollfd_jobs
+                .iter()
+                .zip(&self.pollfds[1..])
+                .filter_map(|(&(i, stderr), pfd)| pfd.revents().map(|flags| (i, stderr, flags)))
+                .collect::<Vec<_>>();
 
             self.check_for_sighup();
+            self.check_for_drain();
 
             // See if any of our active jobs have events. Knowing when a pipe is actually closed is
             // surprisingly hard. https://www.greenend.org.uk/rjk/tech/poll.html has an interesting
             // suggestion which we adapt slightly here.
             //
-            // This `for` loop has various unwrap() calls. If `flags[i * 2]` or `flags[i * 2 + 1]`
-            // is `Some(_)`, then `self.running[i]` is `Some(_)`, so the
-            // `self.running.as_mut.unwrap()`s are safe. Since we asked for stderr/stdout to be
-            // captured, `std[err|out].as_mut().unwrap()` should also be safe (though the Rust docs
-            // are a little vague on this).
-            for i in 0..self.maxjobs {
-                // stderr
-                if let Some(flags) = self.pollfds[i * 2].revents() {
-                    if flags.contains(PollFlags::POLLIN) {
-                        if let Ok(j) = self.running[i]
-                            .as_mut()
-                            .unwrap()
-                            .child
-                            .stderr
-                            .as_mut()
-                            .unwrap()
-                            .read(&mut *buf)
-                        {
-                            self.running[i]
-                                .as_mut()
-                                .unwrap()
-                                .stderrout
-                                .as_file_mut()
-                                .write_all(&buf[0..j])
-                                .ok();
-                        }
-                    }
-                    if flags.contains(PollFlags::POLLHUP) {
-                        self.running[i].as_mut().unwrap().stderr_hup = true;
-                        self.update_pollfds();
-                    }
+            // Every entry in `pollfd_jobs` refers to a running job, and `check_for_sighup` only
+            // removes job slots when no jobs are running, so the unwrap() below is safe.
+            let mut hup = false;
+            for (i, stderr, flags) in job_events {
+                if flags.contains(PollFlags::POLLIN) {
+                    self.drain_output(i, stderr, &mut *buf);
                 }
-                // stdout
-                if let Some(flags) = self.pollfds[i * 2 + 1].revents() {
-                    if flags.contains(PollFlags::POLLIN) {
-                        if let Ok(j) = self.running[i]
-                            .as_mut()
-                            .unwrap()
-                            .child
-                            .stdout
-                            .as_mut()
-                            .unwrap()
-                            .read(&mut *buf)
-                        {
-                            self.running[i]
-                                .as_mut()
-                                .unwrap()
-                                .stderrout
-                                .as_file_mut()
-                                .write_all(&buf[0..j])
-                                .ok();
+                if flags.contains(PollFlags::POLLHUP) {
+                    let job = self.running[i].as_mut().unwrap();
+                    let pending = if stderr {
+                        job.stderr_hup = true;
+                        &job.stderr_pending
+                    } else {
+                        job.stdout_hup = true;
+                        &job.stdout_pending
+                    };
+                    if let Some(priority) = job.rconf.syslog {
+                        if !pending.is_empty() {
+                            let l = String::from_utf8_lossy(pending);
+                            self.snare.log_job_output(priority, &job.repo_id, &l);
                         }
                     }
-                    if flags.contains(PollFlags::POLLHUP) {
-                        self.running[i].as_mut().unwrap().stdout_hup = true;
-                        self.update_pollfds();
-                    }
+                    hup = true;
                 }
             }
+            if hup {
+                self.update_pollfds();
+            }
 
             // Iterate over the running jobs and:
-            //   * If any jobs have exceeded their timeout, send them SIGTERM.
+            //   * If any jobs have exceeded their timeout (or idle timeout), send them their
+            //     `timeout_signal` (by default SIGTERM).
             //   * If there are jobs whose stderr/stdout have closed, keep waiting on them until
             //     they exit.
             next_finish_by = None;
             for i in 0..self.running.len() {
-                if let Some(Job {
-                    finish_by,
-                    ref child,
-                    ..
-                }) = self.running[i]
-                {
-                    if finish_by <= Instant::now() {
-                        kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).ok();
-                    } else if next_finish_by.is_none() || Some(finish_by) < next_finish_by {
-                        next_finish_by = Some(finish_by);
+                if let Some(job) = &mut self.running[i] {
+                    let deadline = job.deadline();
+                    if deadline <= Instant::now() {
+                        if !job.sigtermed {
+                            let what = if job.is_errorcmd { "errorcmd" } else { "Job" };
+                            // `deadline()` can only be earlier than `finish_by` if an idle
+                            // timeout is in effect, so the unwrap() below is safe.
+                            if deadline < job.finish_by {
+                                self.snare.warn(&format!(
+                                    "{what} for {} produced no output for its idle_timeout of {}s: sending {}",
+                                    job.repo_id,
+                                    job.rconf.idle_timeout.unwrap(),
+                                    job.rconf.timeout_signal.as_str()
+                                ));
+                            } else {
+                                self.snare.warn(&format!(
+                                    "{what} for {} exceeded its timeout of {}s: sending {}",
+                                    job.repo_id,
+                                    job.rconf.timeout,
+                                    job.rconf.timeout_signal.as_str()
+                                ));
+                            }
+                            job.sigtermed = true;
+                        }
+                        // If the job is in its own process group, its PID is also the process
+                        // group's ID, and a negative PID signals the whole group.
+                        let pid = job.child.id() as i32;
+                        let pid = if job.rconf.kill_group { -pid } else { pid };
+                        kill(Pid::from_raw(pid), job.rconf.timeout_signal).ok();
+                    } else if next_finish_by.is_none() || Some(deadline) < next_finish_by {
+                        next_finish_by = Some(deadline);
+                    }
+
+                    if job.rconf.kill_group
+                        && !job.orphans_signalled
+                        && !(job.stderr_hup && job.stdout_hup)
+                    {
+                        if let Ok(Some(_)) = job.child.try_wait() {
+                            // The command has exited, but its stderr/stdout are still open, so
+                            // other members of its process group (e.g. processes it started in
+                            // the background) must be holding them. Signal those processes so
+                            // that the job's slot is freed promptly rather than at the timeout.
+                            kill(Pid::from_raw(-(job.child.id() as i32)), Signal::SIGTERM).ok();
+                            job.orphans_signalled = true;
+                        }
                     }
                 }
 
@@ -206,6 +285,7 @@ impl JobRunner {
                     let mut exited_success = false;
                     let mut exit_type = "";
                     let mut exit_code = String::new();
+                    let mut status_code = None;
                     match self.running[i].as_mut().unwrap().child.try_wait() {
                         Ok(Some(status)) => {
                             exited = true;
@@ -213,6 +293,7 @@ impl JobRunner {
                             if let Some(x) = status.code() {
                                 exit_type = "status";
                                 exit_code = x.to_string();
+                                status_code = Some(x);
                             } else if let Some(x) = status.signal() {
                                 exit_type = "signal";
                                 exit_code = x.to_string();
@@ -230,23 +311,108 @@ impl JobRunner {
                         Ok(None) => (),
                     }
                     if exited {
+                        let job = self.running[i].as_mut().unwrap();
+                        if job.rconf.kill_group && !job.orphans_signalled {
+                            // Don't leave any processes the command started running after it.
+                            kill(Pid::from_raw(-(job.child.id() as i32)), Signal::SIGTERM).ok();
+                        }
+                        let job = self.running[i].as_ref().unwrap();
+                        if exited_success
+                            && !job.is_errorcmd
+                            && job.cmd_index + 1 < job.rconf.cmd.len()
+                        {
+                            // This job has further commands to run: they share the job's slot,
+                            // temporary files, output, and timeout.
+                            match self.run_next_cmd(job) {
+                                Some(child) => {
+                                    let job = self.running[i].as_mut().unwrap();
+                                    job.child = child;
+                                    job.cmd_index += 1;
+                                    job.orphans_signalled = false;
+                                    job.stderr_hup = false;
+                                    job.stdout_hup = false;
+                                    job.stderr_pending.clear();
+                                    job.stdout_pending.clear();
+                                    self.update_pollfds();
+                                    continue;
+                                }
+                                None => {
+                                    exited_success = false;
+                                    exit_type = "unknown";
+                                    exit_code = "unknown".to_owned();
+                                }
+                            }
+                        }
+                        // Make sure that all of the job's output is in the `stderrout` file
+                        // before we (potentially) pass its path to `errorcmd`.
+                        self.running[i].as_mut().unwrap().flush_output();
+                        if !self.running[i].as_ref().unwrap().is_errorcmd {
+                            self.record_outcome(i, exited_success);
+                            self.log_status(i);
+                            self.failed |= !exited_success;
+                        }
                         if !exited_success {
                             let job = &self.running[i].as_ref().unwrap();
                             if job.is_errorcmd {
-                                self.snare.error(&format!(
-                                    "errorcmd exited unsuccessfully: {}",
-                                    job.rconf.errorcmd.as_ref().unwrap()
-                                ));
-                            } else if let Some(errorchild) =
-                                self.run_errorcmd(job, exit_type, &exit_code)
-                            {
-                                let job = &mut self.running[i].as_mut().unwrap();
-                                job.child = errorchild;
-                                job.is_errorcmd = true;
-                                continue;
+                                // There's no point retrying an errorcmd that was terminated
+                                // for exceeding the job's deadline: it would immediately be
+                                // terminated again.
+                                if job.errorcmd_retried < job.rconf.errorcmd_retries
+                                    && !job.sigtermed
+                                {
+                                    self.snare.warn(&format!(
+                                        "errorcmd exited unsuccessfully: retrying in {ERRORCMD_RETRY_DELAY}s: {}",
+                                        job.rconf.errorcmd.as_ref().unwrap()
+                                    ));
+                                    if let Some(errorchild) = self.run_errorcmd(
+                                        job,
+                                        job.failed_exit_type,
+                                        &job.failed_exit_code,
+                                        true,
+                                    ) {
+                                        let job = &mut self.running[i].as_mut().unwrap();
+                                        job.child = errorchild;
+                                        job.errorcmd_retried += 1;
+                                        job.orphans_signalled = false;
+                                        continue;
+                                    }
+                                } else {
+                                    self.snare.error(&format!(
+                                        "errorcmd exited unsuccessfully: {}",
+                                        job.rconf.errorcmd.as_ref().unwrap()
+                                    ));
+                                }
+                            } else if job.rconf.errorcmd_wanted(status_code) {
+                                if let Some(errorchild) =
+                                    self.run_errorcmd(job, exit_type, &exit_code, false)
+                                {
+                                    let job = &mut self.running[i].as_mut().unwrap();
+                                    job.child = errorchild;
+                                    job.is_errorcmd = true;
+                                    job.sigtermed = false;
+                                    job.orphans_signalled = false;
+                                    job.failed_exit_type = exit_type;
+                                    job.failed_exit_code = exit_code;
+                                    continue;
+                                }
                             }
                         }
+                        let job = self.running[i].as_ref().unwrap();
+                        self.snare.info(&format!(
+                            "Job for {} finished: queued for {:.3}s, ran for {:.3}s",
+                            job.repo_id,
+                            job.started_at
+                                .saturating_duration_since(job.queued_at)
+                                .as_secs_f64(),
+                            job.started_at.elapsed().as_secs_f64()
+                        ));
                         remove_file(&self.running[i].as_ref().unwrap().json_path).ok();
+                        remove_file(&self.running[i].as_ref().unwrap().captures_path).ok();
+                        if let Some(p) = &self.running[i].as_ref().unwrap().raw_body_path {
+                            remove_file(p).ok();
+                        }
+                        remove_file(&self.running[i].as_ref().unwrap().status_path).ok();
+                        self.running_weight -= self.running[i].as_ref().unwrap().weight;
                         self.running[i] = None;
                         self.num_running -= 1;
                         self.update_pollfds();
@@ -256,7 +422,7 @@ impl JobRunner {
 
             // Has the HTTP server told us that we should check for new jobs and/or SIGCHLD/SIGHUP
             // has been received?
-            match self.pollfds[self.maxjobs * 2].revents() {
+            match event_flags {
                 Some(flags) if flags == PollFlags::POLLIN => {
                     check_queue = true;
                     // It's fine for us to drain the event pipe completely: we'll process all the
@@ -275,39 +441,145 @@ impl JobRunner {
             // it fully, or because the HTTP server has told us that there might be new jobs.
             // However, it's only worth us checking the queue (which requires a lock) if there's
             // space for us to run further jobs.
-            if check_queue && self.num_running < self.maxjobs {
+            if check_queue && self.running_weight < self.maxjobs {
                 check_queue = !self.try_pop_queue();
             }
+
+            // Have we become idle? We only check the queue (which requires a lock) if there's a
+            // chance that we are.
+            if self.ran_since_idle
+                && self.num_running == 0
+                && !check_queue
+                && self.snare.lock_queue().is_empty()
+            {
+                let now = Instant::now();
+                match idle_at {
+                    None => idle_at = Some(now + ON_IDLE_DELAY),
+                    Some(t) if t <= now => {
+                        idle_at = None;
+                        self.ran_since_idle = false;
+                        let on_idle = self.snare.lock_conf().on_idle.clone();
+                        if let Some(cmd) = on_idle {
+                            self.snare.run_hook("on_idle", &cmd, None);
+                        }
+                    }
+                    Some(_) => (),
+                }
+            } else {
+                idle_at = None;
+            }
+
+            // In `--once` mode, have the request's jobs all finished? Since the httpserver sets
+            // `once_queued` only after it has queued all of the request's jobs, an empty queue
+            // means that there are no more jobs to come.
+            if self.snare.once_queued.load(Ordering::Relaxed)
+                && self.num_running == 0
+                && !check_queue
+                && self.snare.lock_queue().is_empty()
+            {
+                return !self.failed;
+            }
+        }
+    }
+
+    /// Read the output that the job in slot `i` has written to its stderr (if `stderr` is `true`)
+    /// or stdout, using `buf` as a scratch buffer. Rather than doing a single read, we read until
+    /// the pipe is empty, so that a job which produces lots of output doesn't cause us to go
+    /// around the `poll` loop once per `READBUF` bytes: so that such a job can't starve other
+    /// jobs, we read at most `MAX_DRAIN` bytes in one go.
+    fn drain_output(&mut self, i: usize, stderr: bool, buf: &mut [u8]) {
+        // The caller guarantees that `self.running[i]` is `Some(_)`. Since we asked for
+        // stderr/stdout to be captured, `std[err|out].as_mut().unwrap()` should also be safe
+        // (though the Rust docs are a little vague on this).
+        let job = self.running[i].as_mut().unwrap();
+        let mut drained = 0;
+        while drained < MAX_DRAIN {
+            let r = if stderr {
+                job.child.stderr.as_mut().unwrap().read(buf)
+            } else {
+                job.child.stdout.as_mut().unwrap().read(buf)
+            };
+            // A read of 0 bytes means that the pipe has been closed, which `attend` detects via
+            // `POLLHUP`; an error (including `EAGAIN`) means that there's nothing more to read for
+            // now.
+            let j = match r {
+                Ok(0) | Err(_) => break,
+                Ok(j) => j,
+            };
+            drained += j;
+            job.write_output(&buf[0..j]);
+            job.last_output = Instant::now();
+            if let Some(priority) = job.rconf.syslog {
+                let pending = if stderr {
+                    &mut job.stderr_pending
+                } else {
+                    &mut job.stdout_pending
+                };
+                for l in take_lines(pending, &buf[0..j]) {
+                    self.snare.log_job_output(priority, &job.repo_id, &l);
+                }
+            }
         }
     }
 
     /// Try to pop all jobs on the queue: returns `true` if it was able to do so successfully or
     /// `false` otherwise.
     fn try_pop_queue(&mut self) -> bool {
+        if self.draining {
+            // We'll be woken up via the event pipe when draining ends, so there's no point in
+            // asking the caller to retry until then.
+            return true;
+        }
         let snare = Arc::clone(&self.snare);
-        let mut queue = snare.queue.lock().unwrap();
+        let max_parallel_global = snare.lock_conf().max_parallel_global;
+        let mut queue = snare.lock_queue();
         loop {
-            if self.num_running == self.maxjobs && !queue.is_empty() {
+            if self.running_weight >= self.maxjobs && !queue.is_empty() {
                 return false;
             }
-            let pjob = queue.pop(|repo_id| {
-                self.running.iter().any(|jobslot| {
-                    if let Some(job) = jobslot {
-                        repo_id == job.repo_id
-                    } else {
-                        false
-                    }
-                })
-            });
+            let allow_parallel = match max_parallel_global {
+                Some(m) => {
+                    self.running
+                        .iter()
+                        .flatten()
+                        .filter(|job| matches!(job.rconf.queuekind, QueueKind::Parallel))
+                        .count()
+                        < m
+                }
+                None => true,
+            };
+            let pjob = queue.pop(
+                |repo_id| {
+                    self.running.iter().any(|jobslot| {
+                        if let Some(job) = jobslot {
+                            repo_id == job.repo_id
+                        } else {
+                            false
+                        }
+                    })
+                },
+                |lock| {
+                    self.running
+                        .iter()
+                        .flatten()
+                        .any(|job| job.rconf.lock.as_deref() == Some(lock))
+                },
+                allow_parallel,
+                // Jobs which need more slots than are free are left on the queue until some of
+                // the running jobs have finished.
+                |weight| self.running_weight + weight.min(self.maxjobs) <= self.maxjobs,
+            );
             match pjob {
                 Some(qj) => {
-                    debug_assert!(self.num_running < self.maxjobs);
+                    debug_assert!(self.running_weight < self.maxjobs);
                     match self.try_job(qj) {
                         Ok(j) => {
                             // The unwrap is safe since we've already checked that there's room to
                             // run at least 1 job.
                             let i = self.running.iter().position(|x| x.is_none()).unwrap();
+                            self.running_weight += j.weight;
                             self.running[i] = Some(j);
+                            self.ran_since_idle = true;
                             self.num_running += 1;
                             self.update_pollfds();
                         }
@@ -341,34 +613,84 @@ impl JobRunner {
     /// job could not be run (either because there is no command, or because there was a permanent
     /// error, and the user was appropriately notified) and the job is consumed.
     fn try_job(&mut self, qj: QueueJob) -> Result<Job, Option<QueueJob>> {
-        let raw_cmd = match &qj.rconf.cmd {
-            Some(c) => c,
-            None => {
-                // There is no command to run.
+        if let Some(&(_, Some(until))) = self.failures.get(&qj.repo_id) {
+            if until > Instant::now() {
+                self.snare.info(&format!(
+                    "Dropping {} for {}: repository is quarantined",
+                    qj.event_type, qj.repo_id
+                ));
                 return Err(None);
             }
+            // The quarantine has ended: give the repository a fresh start.
+            self.failures.remove(&qj.repo_id);
+        }
+
+        // If there is no command to run, the job is dropped, unless the user has asked for
+        // `errorcmd` to be run instead (in which case `raw_cmd` is `None`). If `raw_cmd` is
+        // `None`, `errorcmd` is run with `%x` and `%?` set to `errorcmd_exit`.
+        let mut raw_cmd = match qj.rconf.cmd.first() {
+            Some(c) => Some(c),
+            None if qj.rconf.errorcmd_without_cmd && qj.rconf.errorcmd.is_some() => None,
+            None => return Err(None),
         };
+        let mut errorcmd_exit = ("none", "");
 
-        // Write the JSON to an unnamed temporary file.
-        let json_path = match NamedTempFile::new() {
-            Ok(tfile) => match tfile.into_temp_path().keep() {
-                Ok(p) => {
-                    if let Err(e) = fs::write(&p, qj.json_str.as_bytes()) {
-                        self.snare.error(&format!("Couldn't write JSON file: {e}"));
-                        remove_file(p).ok();
-                        return Err(Some(qj));
-                    }
-                    p
-                }
+        let cgroup_procs = match &qj.rconf.cgroup {
+            Some(cgroup) if raw_cmd.is_some() => match prepare_cgroup(cgroup) {
+                Ok(p) => Some(p),
                 Err(e) => {
                     self.snare
-                        .error(&format!("Couldn't create temporary file: {e}"));
+                        .error(&format!("Can't use cgroup '{cgroup}': {e}"));
+                    self.failed = true;
+                    if qj.rconf.errorcmd.is_none() {
+                        return Err(None);
+                    }
+                    // As with other failures to start a command, `errorcmd` (which is not run in
+                    // the cgroup) is run instead.
+                    raw_cmd = None;
+                    errorcmd_exit = ("unknown", "unknown");
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        // Write the JSON to an unnamed temporary file.
+        let json_path = match self.write_temp_file(qj.json_str.as_bytes(), qj.rconf.json_mode) {
+            Some(p) => p,
+            None => return Err(Some(qj)),
+        };
+        // Write the regex captures, as a JSON object, to an unnamed temporary file.
+        let captures_json = serde_json::Value::Object(qj.rconf.captures.clone()).to_string();
+        let captures_path = match self.write_temp_file(captures_json.as_bytes(), qj.rconf.json_mode)
+        {
+            Some(p) => p,
+            None => {
+                remove_file(json_path).ok();
+                return Err(Some(qj));
+            }
+        };
+        // If requested, write the raw request body to an unnamed temporary file.
+        let raw_body_path = match &qj.raw_body {
+            Some(raw_body) => match self.write_temp_file(raw_body, qj.rconf.json_mode) {
+                Some(p) => Some(p),
+                None => {
+                    remove_file(json_path).ok();
+                    remove_file(captures_path).ok();
                     return Err(Some(qj));
                 }
             },
-            Err(e) => {
-                self.snare
-                    .error(&format!("Couldn't create temporary file: {e}"));
+            None => None,
+        };
+        // Create an empty file to which the command can write a status line (see `%m`).
+        let status_path = match self.write_temp_file(&[], PRIVATE_FILE_MODE) {
+            Some(p) => p,
+            None => {
+                remove_file(json_path).ok();
+                remove_file(captures_path).ok();
+                if let Some(p) = raw_body_path {
+                    remove_file(p).ok();
+                }
                 return Err(Some(qj));
             }
         };
@@ -377,46 +699,76 @@ impl JobRunner {
         // file `stderrout_file`.
         if let Ok(tempdir) = tempdir() {
             if let Ok(stderrout) = NamedTempFile::new() {
-                if set_nonblock(stderrout.as_file().as_raw_fd()).is_ok() {
-                    if let Some(json_path_str) = json_path.to_str() {
-                        let cmd = cmd_replace(
-                            raw_cmd,
-                            &qj.event_type,
-                            &qj.owner,
-                            &qj.repo,
-                            json_path_str,
-                        );
-                        let child = match Command::new(&self.shell)
-                            .arg("-c")
-                            .arg(cmd)
-                            .current_dir(tempdir.path())
-                            .stderr(process::Stdio::piped())
-                            .stdout(process::Stdio::piped())
-                            .stdin(process::Stdio::null())
-                            .spawn()
-                        {
-                            Ok(c) => c,
-                            Err(e) => {
-                                self.snare.error(&format!("Can't spawn command: {e}"));
+                if stderrout
+                    .as_file()
+                    .set_permissions(Permissions::from_mode(PRIVATE_FILE_MODE))
+                    .is_ok()
+                    && set_nonblock(stderrout.as_file().as_raw_fd()).is_ok()
+                {
+                    if let (
+                        Some(json_path_str),
+                        Some(captures_path_str),
+                        Some(raw_body_path_str),
+                        Some(status_path_str),
+                        Some(stderrout_path_str),
+                    ) = (
+                        json_path.to_str(),
+                        captures_path.to_str(),
+                        raw_body_path.as_ref().map_or(Some(""), |p| p.to_str()),
+                        status_path.to_str(),
+                        stderrout.path().to_str(),
+                    ) {
+                        let child = match raw_cmd {
+                            Some(raw_cmd) => {
+                                let cmd = cmd_replace(
+                                    raw_cmd,
+                                    &qj.event_type,
+                                    &qj.forced,
+                                    &qj.head_sha,
+                                    &qj.owner,
+                                    &qj.repo,
+                                    json_path_str,
+                                    captures_path_str,
+                                    raw_body_path_str,
+                                    status_path_str,
+                                    &qj.rconf.matched,
+                                );
+                                self.spawn_cmd(
+                                    &cmd,
+                                    tempdir.path(),
+                                    &qj.rconf,
+                                    cgroup_procs.as_ref(),
+                                )
+                            }
+                            None => {
+                                // We checked above that `errorcmd` is set, so the unwrap() is
+                                // safe.
+                                let errorcmd = errorcmd_replace(
+                                    qj.rconf.errorcmd.as_ref().unwrap(),
+                                    &qj.event_type,
+                                    &qj.forced,
+                                    &qj.head_sha,
+                                    &qj.owner,
+                                    &qj.repo,
+                                    json_path_str,
+                                    captures_path_str,
+                                    raw_body_path_str,
+                                    &qj.rconf.matched,
+                                    stderrout_path_str,
+                                    errorcmd_exit.0,
+                                    errorcmd_exit.1,
+                                );
+                                self.spawn_errorcmd(&errorcmd, tempdir.path(), &qj.rconf, false)
+                            }
+                        };
+                        let child = match child {
+                            Some(c) => c,
+                            None => {
+                                self.failed = true;
                                 return Err(None);
                             }
                         };
 
-                        // Since we've asked for stderr/stdout to be captured, the unwrap()s should
-                        // be safe, though the Rust docs are slightly vague on this.
-                        let stderr = child.stderr.as_ref().unwrap();
-                        let stdout = child.stdout.as_ref().unwrap();
-
-                        let stderr_fd = stderr.as_raw_fd();
-                        let stdout_fd = stdout.as_raw_fd();
-                        if let Err(e) =
-                            set_nonblock(stderr_fd).and_then(|_| set_nonblock(stdout_fd))
-                        {
-                            self.snare
-                                .error(&format!("Can't set file descriptors to non-blocking: {e}"));
-                            return Err(None);
-                        }
-
                         // This unwrap() is, in theory, unsafe because we could exceed the timeout
                         // duration. However, a quick back-of-the-envelope calculation suggests
                         // that, assuming `Instant` is a `u64`, this could only happen with an
@@ -429,18 +781,46 @@ impl JobRunner {
                             .unwrap();
 
                         return Ok(Job {
-                            is_errorcmd: false,
+                            queued_at: qj.req_time,
+                            started_at: Instant::now(),
+                            last_output: Instant::now(),
+                            is_errorcmd: raw_cmd.is_none(),
+                            weight: qj.rconf.weight.min(self.maxjobs),
                             repo_id: qj.repo_id,
                             event_type: qj.event_type,
+                            forced: qj.forced,
+                            head_sha: qj.head_sha,
                             owner: qj.owner,
                             repo: qj.repo,
                             finish_by,
+                            sigtermed: false,
+                            orphans_signalled: false,
+                            cmd_index: 0,
+                            failed_exit_type: if raw_cmd.is_none() {
+                                errorcmd_exit.0
+                            } else {
+                                ""
+                            },
+                            failed_exit_code: if raw_cmd.is_none() {
+                                errorcmd_exit.1.to_owned()
+                            } else {
+                                String::new()
+                            },
+                            errorcmd_retried: 0,
                             child,
+                            cgroup_procs,
                             tempdir,
                             json_path,
-                            stderrout,
-                            stderr_hup: false,
-                            stdout_hup: false,
+                            captures_path,
+                            raw_body_path,
+                            status_path,
+                            stderrout: BufWriter::with_capacity(STDERROUT_BUF, stderrout),
+                            output_tail: VecDeque::new(),
+                            // `errorcmd`'s stderr/stdout are not captured.
+                            stderr_hup: raw_cmd.is_none(),
+                            stdout_hup: raw_cmd.is_none(),
+                            stderr_pending: Vec::new(),
+                            stdout_pending: Vec::new(),
                             rconf: qj.rconf,
                         });
                     }
@@ -451,112 +831,414 @@ impl JobRunner {
         Err(Some(qj))
     }
 
+    /// Write `contents` to a new temporary file with permissions `mode`, returning its path upon
+    /// success. The caller is responsible for removing the file when it is no longer needed. If
+    /// the file can't be created, the user is notified and `None` is returned.
+    fn write_temp_file(&self, contents: &[u8], mode: u32) -> Option<PathBuf> {
+        match NamedTempFile::new() {
+            Ok(tfile) => match tfile.into_temp_path().keep() {
+                Ok(p) => {
+                    // Unlike the mode passed to `open`, `set_permissions` is not affected by the
+                    // umask.
+                    if let Err(e) = fs::set_permissions(&p, Permissions::from_mode(mode))
+                        .and_then(|_| fs::write(&p, contents))
+                    {
+                        self.snare
+                            .error(&format!("Couldn't write temporary file: {e}"));
+                        remove_file(p).ok();
+                        return None;
+                    }
+                    Some(p)
+                }
+                Err(e) => {
+                    self.snare
+                        .error(&format!("Couldn't create temporary file: {e}"));
+                    None
+                }
+            },
+            Err(e) => {
+                self.snare
+                    .error(&format!("Couldn't create temporary file: {e}"));
+                None
+            }
+        }
+    }
+
     /// After a job has been inserted / removed from `self.running`, this function must be called
     /// so that `poll()` is called with up-to-date file descriptors.
     fn update_pollfds(&mut self) {
-        for (i, jobslot) in self.running.iter().enumerate() {
-            let (stderr_fd, stdout_fd) = if let Some(job) = jobslot {
-                // Since we've asked for stderr/stdout to be captured, the unwrap()s should be
-                // safe, though the Rust docs are slightly vague on this.
-                let stderr_fd = if job.stderr_hup {
-                    -1
-                } else {
-                    job.child.stderr.as_ref().unwrap().as_raw_fd()
-                };
-                let stdout_fd = if job.stdout_hup {
-                    -1
-                } else {
-                    job.child.stdout.as_ref().unwrap().as_raw_fd()
-                };
-                (stderr_fd, stdout_fd)
+        self.pollfds.clear();
+        self.pollfd_jobs.clear();
+        self.pollfds
+            .push(PollFd::new(self.snare.event_read_fd, PollFlags::POLLIN));
+        for (i, job) in self
+            .running
+            .iter()
+            .enumerate()
+            .filter_map(|(i, jobslot)| jobslot.as_ref().map(|job| (i, job)))
+        {
+            // Since we've asked for stderr/stdout to be captured, the unwrap()s should be safe,
+            // though the Rust docs are slightly vague on this.
+            if !job.stderr_hup {
+                let fd = job.child.stderr.as_ref().unwrap().as_raw_fd();
+                self.pollfds.push(PollFd::new(fd, PollFlags::POLLIN));
+                self.pollfd_jobs.push((i, true));
+            }
+            if !job.stdout_hup {
+                let fd = job.child.stdout.as_ref().unwrap().as_raw_fd();
+                self.pollfds.push(PollFd::new(fd, PollFlags::POLLIN));
+                self.pollfd_jobs.push((i, false));
+            }
+        }
+    }
+
+    /// If `runner_priority` is set, change this thread's nice value accordingly. Since child
+    /// processes inherit the nice value of the thread that creates them, we record the original
+    /// nice value so that jobs can be reset to it.
+    fn set_priority(&mut self) {
+        let prio = match self.snare.lock_conf().runner_priority {
+            Some(x) => x,
+            None => return,
+        };
+        // On Linux, `setpriority` with `PRIO_PROCESS` and a thread ID affects only that thread;
+        // elsewhere, we can only change the priority of snare as a whole.
+        #[cfg(target_os = "linux")]
+        let who = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+        #[cfg(not(target_os = "linux"))]
+        let who = 0;
+        // `getpriority` can legitimately return -1, so errors can only be detected via `errno`.
+        Errno::clear();
+        let orig = unsafe { libc::getpriority(libc::PRIO_PROCESS, who) };
+        if orig == -1 && Errno::last() != Errno::UnknownErrno {
+            self.snare
+                .error(&format!("Can't get job runner priority: {}", Errno::last()));
+            return;
+        }
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, who, prio) } == -1 {
+            self.snare.error(&format!(
+                "Can't set job runner priority to {prio}: {}",
+                Errno::last()
+            ));
+            return;
+        }
+        self.job_nice = Some(orig);
+    }
+
+    /// Record whether the (non-errorcmd) job `self.running[i]` exited successfully, quarantining
+    /// its repository if it has now failed `quarantine_after` times in a row.
+    fn record_outcome(&mut self, i: usize, success: bool) {
+        // The caller guarantees that `self.running[i]` is `Some`.
+        let job = self.running[i].as_ref().unwrap();
+        let quarantine_after = match job.rconf.quarantine_after {
+            Some(x) if !success => x,
+            _ => {
+                self.failures.remove(&job.repo_id);
+                return;
+            }
+        };
+        let entry = self
+            .failures
+            .entry(job.repo_id.clone())
+            .or_insert((0, None));
+        entry.0 += 1;
+        if entry.0 >= quarantine_after {
+            // As with `finish_by`, this unwrap() could only fail with an absurdly long uptime.
+            entry.1 = Some(
+                Instant::now()
+                    .checked_add(Duration::from_millis(
+                        job.rconf.quarantine_cooldown.saturating_mul(1000),
+                    ))
+                    .unwrap(),
+            );
+            self.snare.warn(&format!(
+                "Quarantining {} for {}s after {} consecutive failures",
+                job.repo_id, job.rconf.quarantine_cooldown, entry.0
+            ));
+        }
+    }
+
+    /// If the command for the job in `self.running[i]` wrote a status line to its `%m` file, log
+    /// it. At most `MAX_STATUS_LEN` bytes of the file are read, and only its first line is used.
+    fn log_status(&self, i: usize) {
+        let job = self.running[i].as_ref().unwrap();
+        let mut buf = Vec::new();
+        if let Err(e) = fs::File::open(&job.status_path)
+            .and_then(|f| f.take(MAX_STATUS_LEN).read_to_end(&mut buf))
+        {
+            self.snare
+                .warn(&format!("Can't read status file for {}: {e}", job.repo_id));
+            return;
+        }
+        let status = String::from_utf8_lossy(&buf);
+        let status = status.lines().next().unwrap_or("").trim();
+        if !status.is_empty() {
+            self.snare.info(&format!(
+                "Job for {} reported status: {status}",
+                job.repo_id
+            ));
+        }
+    }
+
+    /// If SIGUSR2 has toggled draining on or off since we last checked, log the change.
+    fn check_for_drain(&mut self) {
+        let draining = self.snare.draining.load(Ordering::Relaxed);
+        if draining != self.draining {
+            self.draining = draining;
+            if draining {
+                self.snare.info(
+                    "Draining: new jobs will be queued but not run until SIGUSR2 is received",
+                );
             } else {
-                (-1, -1)
-            };
-            self.pollfds[i * 2] = PollFd::new(stderr_fd, PollFlags::POLLIN);
-            self.pollfds[i * 2 + 1] = PollFd::new(stdout_fd, PollFlags::POLLIN);
+                self.snare.info("No longer draining: running queued jobs");
+            }
         }
-        self.pollfds[self.maxjobs * 2] = PollFd::new(self.snare.event_read_fd, PollFlags::POLLIN);
     }
 
     /// If SIGHUP has been received, reload the config, and update self.maxjobs if possible.
     fn check_for_sighup(&mut self) {
-        self.snare.check_for_sighup();
+        if let Some(new_maxjobs) = self.snare.check_for_sighup() {
+            self.target_maxjobs = new_maxjobs;
+        }
 
-        let new_maxjobs = self.snare.conf.lock().unwrap().maxjobs;
+        let new_maxjobs = self.target_maxjobs;
         if new_maxjobs > self.maxjobs {
             // The user now wants to allow more jobs which we can do simply and safely -- even if
-            // there are jobs running -- by extending self.running and self.pollfds with blank
-            // entries.
+            // there are jobs running -- by extending self.running with blank entries.
             self.running.resize_with(new_maxjobs, || None);
-            self.pollfds
-                .resize_with(new_maxjobs * 2 + 1, || PollFd::new(-1, PollFlags::empty()));
             self.maxjobs = new_maxjobs;
-            self.update_pollfds();
         } else if new_maxjobs < self.maxjobs && self.num_running == 0 {
             // The user wants to allow fewer jobs. This is somewhat hard because we may be running
             // jobs, and possibly more than the user now wants us to be running. We could be clever
-            // and compact self.running and self.pollfds, though that may still not drop the number
-            // of jobs down enough. We currently do the laziest thing: we wait until there are no
-            // running jobs and then truncate self.running and self.pollfds. If there are always
-            // running jobs then this means we will never reduce the number of maximum possible
-            // jobs.
+            // and compact self.running, though that may still not drop the number of jobs down
+            // enough. We currently do the laziest thing: we wait until there are no running jobs
+            // and then truncate self.running. If there are always running jobs then this means we
+            // will never reduce the number of maximum possible jobs.
             self.running.truncate(new_maxjobs);
-            self.pollfds.truncate(new_maxjobs * 2 + 1);
             self.maxjobs = new_maxjobs;
-            self.update_pollfds();
-        }
-    }
-
-    /// Run the user's errorcmd (if they've specified one).
-    fn run_errorcmd(&self, job: &Job, exit_type: &str, exit_code: &str) -> Option<Child> {
-        if let Some(raw_errorcmd) = &job.rconf.errorcmd {
-            let errorcmd = errorcmd_replace(
-                raw_errorcmd,
-                &job.event_type,
-                &job.owner,
-                &job.repo,
-                job.json_path.as_os_str().to_str().unwrap(),
-                job.stderrout.path().as_os_str().to_str().unwrap(),
-                exit_type,
-                exit_code,
-            );
-            match Command::new(&self.shell)
-                .arg("-c")
-                .arg(&errorcmd)
-                .current_dir(job.tempdir.path())
-                .stderr(process::Stdio::null())
-                .stdout(process::Stdio::null())
-                .stdin(process::Stdio::null())
-                .spawn()
-            {
-                Ok(c) => return Some(c),
-                Err(e) => self.snare.error(&format!("Can't spawn '{errorcmd}': {e}")),
+        }
+    }
+
+    /// Spawn the (already substituted) command `cmd` for a job whose config is `rconf` in the
+    /// directory `dir`, with its stderr/stdout captured via non-blocking pipes. If `cgroup_procs`
+    /// is not `None`, the command is run in that cgroup. Returns `None` (after logging an error) if
+    /// the command could not be spawned.
+    fn spawn_cmd(
+        &self,
+        cmd: &str,
+        dir: &Path,
+        rconf: &RepoConfig,
+        cgroup_procs: Option<&CString>,
+    ) -> Option<Child> {
+        let mut command = Command::new(&self.shell);
+        command
+            .arg("-c")
+            .arg(cmd)
+            .current_dir(dir)
+            .stderr(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .stdin(process::Stdio::null());
+        if rconf.kill_group {
+            command.process_group(0);
+        }
+        if let Some(nice) = self.job_nice {
+            unsafe {
+                command.pre_exec(move || reset_priority(nice));
+            }
+        }
+        if rconf.merge_output {
+            // Since `pre_exec` closures run after the child's stdio has been set up, this makes
+            // stderr share stdout's pipe. The child's end of the stderr pipe is thus closed, so we
+            // will see it as hung up.
+            unsafe {
+                command.pre_exec(|| {
+                    if libc::dup2(1, 2) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if let Some(secs) = rconf.cpu_limit {
+            unsafe {
+                command.pre_exec(move || set_cpu_limit(secs));
+            }
+        }
+        if let Some(bytes) = rconf.mem_limit {
+            unsafe {
+                command.pre_exec(move || set_mem_limit(bytes));
+            }
+        }
+        if let Some(procs) = cgroup_procs {
+            // By entering the cgroup before `exec`, we guarantee that neither the command nor any
+            // of its children can escape the cgroup's limits.
+            let procs = procs.clone();
+            unsafe {
+                command.pre_exec(move || enter_cgroup(&procs));
+            }
+        }
+        let child = match command.spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                self.snare.error(&format!("Can't spawn command: {e}"));
+                return None;
+            }
+        };
+
+        // Since we've asked for stderr/stdout to be captured, the unwrap()s should be safe, though
+        // the Rust docs are slightly vague on this.
+        let stderr_fd = child.stderr.as_ref().unwrap().as_raw_fd();
+        let stdout_fd = child.stdout.as_ref().unwrap().as_raw_fd();
+        if let Err(e) = set_nonblock(stderr_fd).and_then(|_| set_nonblock(stdout_fd)) {
+            self.snare
+                .error(&format!("Can't set file descriptors to non-blocking: {e}"));
+            return None;
+        }
+        Some(child)
+    }
+
+    /// Spawn the command after `job`'s current command. The caller must ensure that there is such a
+    /// command.
+    fn run_next_cmd(&self, job: &Job) -> Option<Child> {
+        // The unwrap()s on paths are safe since they were checked when the job was started.
+        let cmd = cmd_replace(
+            &job.rconf.cmd[job.cmd_index + 1],
+            &job.event_type,
+            &job.forced,
+            &job.head_sha,
+            &job.owner,
+            &job.repo,
+            job.json_path.as_os_str().to_str().unwrap(),
+            job.captures_path.as_os_str().to_str().unwrap(),
+            job.raw_body_path
+                .as_ref()
+                .map_or("", |p| p.as_os_str().to_str().unwrap()),
+            job.status_path.as_os_str().to_str().unwrap(),
+            &job.rconf.matched,
+        );
+        self.spawn_cmd(
+            &cmd,
+            job.tempdir.path(),
+            &job.rconf,
+            job.cgroup_procs.as_ref(),
+        )
+    }
+
+    /// Run the user's errorcmd (if they've specified one). If `delay` is true (i.e. the errorcmd
+    /// is being retried), the errorcmd waits `ERRORCMD_RETRY_DELAY` seconds before it starts.
+    fn run_errorcmd(
+        &self,
+        job: &Job,
+        exit_type: &str,
+        exit_code: &str,
+        delay: bool,
+    ) -> Option<Child> {
+        let raw_errorcmd = job.rconf.errorcmd.as_ref()?;
+        let errorcmd = errorcmd_replace(
+            raw_errorcmd,
+            &job.event_type,
+            &job.forced,
+            &job.head_sha,
+            &job.owner,
+            &job.repo,
+            job.json_path.as_os_str().to_str().unwrap(),
+            job.captures_path.as_os_str().to_str().unwrap(),
+            job.raw_body_path
+                .as_ref()
+                .map_or("", |p| p.as_os_str().to_str().unwrap()),
+            &job.rconf.matched,
+            job.stderrout.get_ref().path().as_os_str().to_str().unwrap(),
+            exit_type,
+            exit_code,
+        );
+        self.spawn_errorcmd(&errorcmd, job.tempdir.path(), &job.rconf, delay)
+    }
+
+    /// Spawn the (already substituted) `errorcmd` in `tempdir`, returning `None` (after logging an
+    /// error) if that is not possible. If `delay` is true, the errorcmd waits
+    /// `ERRORCMD_RETRY_DELAY` seconds before it starts.
+    fn spawn_errorcmd(
+        &self,
+        errorcmd: &str,
+        tempdir: &Path,
+        rconf: &RepoConfig,
+        delay: bool,
+    ) -> Option<Child> {
+        let mut command = Command::new(&self.shell);
+        command
+            .arg("-c")
+            .arg(errorcmd)
+            .current_dir(tempdir)
+            .stderr(process::Stdio::null())
+            .stdout(process::Stdio::null())
+            .stdin(process::Stdio::null());
+        if rconf.kill_group {
+            command.process_group(0);
+        }
+        if let Some(nice) = self.job_nice {
+            unsafe {
+                command.pre_exec(move || reset_priority(nice));
+            }
+        }
+        if delay {
+            // Sleeping in the child, rather than in the runner, means that other jobs are not
+            // held up, and that the job's timeout continues to be enforced. `sleep` is
+            // async-signal-safe.
+            unsafe {
+                command.pre_exec(|| {
+                    libc::sleep(ERRORCMD_RETRY_DELAY);
+                    Ok(())
+                });
+            }
+        }
+        match command.spawn() {
+            Ok(c) => Some(c),
+            Err(e) => {
+                self.snare.error(&format!("Can't spawn '{errorcmd}': {e}"));
+                None
             }
         }
-        None
     }
 }
 
 /// Take the string `raw_cmd` and return a string with the following replaced:
 ///   * `%e` with `event_type`
+///   * `%F` with `forced`
+///   * `%h` with `head_sha`
 ///   * `%o` with `owner`
 ///   * `%r` with `repo`
 ///   * `%j` with `json_path`
+///   * `%g` with `captures_path`
+///   * `%R` with `raw_body_path`
+///   * `%m` with `status_path`
+///   * `%M` with `matched`
 ///
-/// Note that `raw_cmd` *must* have been validated by config::GitHub::verify_cmd_str or undefined
+/// Note that `raw_cmd` *must* have been validated against config::CmdContext::Cmd or undefined
 /// behaviour will occur.
-fn cmd_replace(
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn cmd_replace(
     raw_cmd: &str,
     event_type: &str,
+    forced: &str,
+    head_sha: &str,
     owner: &str,
     repo: &str,
     json_path: &str,
+    captures_path: &str,
+    raw_body_path: &str,
+    status_path: &str,
+    matched: &str,
 ) -> String {
     let modifiers = [
         ('e', event_type),
+        ('F', forced),
+        ('h', head_sha),
         ('o', owner),
         ('r', repo),
         ('j', json_path),
+        ('g', captures_path),
+        ('R', raw_body_path),
+        ('m', status_path),
+        ('M', matched),
         ('%', "%"),
     ]
     .iter()
@@ -567,30 +1249,46 @@ fn cmd_replace(
 
 /// Take the string `raw_errorcmd` and return a string with the following replaced:
 ///   * `%e` with `event_type`
+///   * `%F` with `forced`
+///   * `%h` with `head_sha`
 ///   * `%o` with `owner`
 ///   * `%r` with `repo`
 ///   * `%j` with `json_path`
+///   * `%g` with `captures_path`
+///   * `%R` with `raw_body_path`
+///   * `%M` with `matched`
 ///   * `%s` with `stderrout_path`
-///   * `%x` with `exit_type` ("status", "signal", or "unknown")
-///   * `%?` with `exit_code` (integer or "unknown")
+///   * `%x` with `exit_type` ("status", "signal", "none", or "unknown")
+///   * `%?` with `exit_code` (integer, "unknown", or the empty string if `exit_type` is "none")
 ///
-/// Note that `raw_cmd` *must* have been validated by config::GitHub::verify_errorcmd_str or
+/// Note that `raw_cmd` *must* have been validated against config::CmdContext::ErrorCmd or
 /// undefined behaviour will occur.
+#[allow(clippy::too_many_arguments)]
 fn errorcmd_replace(
     raw_errorcmd: &str,
     event_type: &str,
+    forced: &str,
+    head_sha: &str,
     owner: &str,
     repo: &str,
     json_path: &str,
+    captures_path: &str,
+    raw_body_path: &str,
+    matched: &str,
     stderrout_path: &str,
     exit_type: &str,
     exit_code: &str,
 ) -> String {
     let modifiers = [
         ('e', event_type),
+        ('F', forced),
+        ('h', head_sha),
         ('o', owner),
         ('r', repo),
         ('j', json_path),
+        ('g', captures_path),
+        ('R', raw_body_path),
+        ('M', matched),
         ('s', stderrout_path),
         ('x', exit_type),
         ('?', exit_code),
@@ -602,6 +1300,19 @@ fn errorcmd_replace(
     replace(raw_errorcmd, modifiers)
 }
 
+/// Take the string `raw_reload_errorcmd` and return a string with the following replaced:
+///   * `%s` with `msg_path`
+///
+/// Note that `raw_reload_errorcmd` *must* have been validated against
+/// config::CmdContext::ReloadErrorCmd or undefined behaviour will occur.
+pub(crate) fn reload_errorcmd_replace(raw_reload_errorcmd: &str, msg_path: &str) -> String {
+    let modifiers = [('s', msg_path), ('%', "%")].iter().cloned().collect();
+    replace(raw_reload_errorcmd, modifiers)
+}
+
+/// Replace each `%` modifier in `s` with its value in `modifiers`. Since `s` will be run via the
+/// shell, values are quoted with `shell_quote`, except for `%%` which is always replaced with a
+/// literal `%`.
 fn replace(s: &str, modifiers: HashMap<char, &str>) -> String {
     // Except in the presence of '%%'s, the output string will be at least as long as the input
     // string, so starting at that capacity is a reasonable heuristic.
@@ -610,7 +1321,11 @@ fn replace(s: &str, modifiers: HashMap<char, &str>) -> String {
     while i < s.len() {
         if s[i..].starts_with('%') {
             let mdf = s[i + 1..].chars().next().unwrap(); // modifier
-            n.push_str(modifiers.get(&mdf).unwrap());
+            if mdf == '%' {
+                n.push('%');
+            } else {
+                n.push_str(&shell_quote(modifiers.get(&mdf).unwrap()));
+            }
             i += 1 + mdf.len_utf8();
         } else {
             let c = s[i..].chars().next().unwrap();
@@ -621,36 +1336,248 @@ fn replace(s: &str, modifiers: HashMap<char, &str>) -> String {
     n
 }
 
+/// If `s` consists solely of characters which have no special meaning to the shell, return it
+/// unchanged; otherwise return it wrapped in single quotes (with any single quotes it contains
+/// escaped) so that the shell treats it as a single literal word.
+fn shell_quote(s: &str) -> Cow<'_, str> {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+,-./:=@_".contains(c))
+    {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(format!("'{}'", s.replace('\'', "'\\''")))
+    }
+}
+
+/// Append `chunk` to the partial line `pending`, returning any complete lines (without their
+/// trailing newline) and leaving any trailing partial line in `pending`. To bound memory use, a
+/// partial line which reaches `READBUF` bytes is returned as if it were a complete line.
+fn take_lines(pending: &mut Vec<u8>, chunk: &[u8]) -> Vec<String> {
+    pending.extend_from_slice(chunk);
+    let mut lines = Vec::new();
+    let mut start = 0;
+    while let Some(off) = pending[start..].iter().position(|b| *b == b'\n') {
+        lines.push(String::from_utf8_lossy(&pending[start..start + off]).into_owned());
+        start += off + 1;
+    }
+    while pending.len() - start >= READBUF {
+        lines.push(String::from_utf8_lossy(&pending[start..start + READBUF]).into_owned());
+        start += READBUF;
+    }
+    pending.drain(..start);
+    lines
+}
+
 struct Job {
     /// Set to `false` if this is a normal command and `true` if it is an error command.
     is_errorcmd: bool,
+    /// How many of the `maxjobs` slots this job consumes. A job's weight is capped at `maxjobs` so
+    /// that it can always eventually be run.
+    weight: usize,
     /// The repo identifier. This is used to determine if a given repository already has jobs
     /// running or not. Typically of the form "provider/owner/repo".
     repo_id: String,
     /// The event type.
     event_type: String,
+    /// Whether a push was forced ("true"/"false"), or the empty string for other events.
+    forced: String,
+    /// The SHA of the commit the event relates to, or the empty string if there is no such commit.
+    head_sha: String,
     /// The repository owner's name.
     owner: String,
     /// The repository name.
     repo: String,
+    /// When was the request that led to this job received?
+    queued_at: Instant,
+    /// When did this job start running?
+    started_at: Instant,
+    /// When did this job last write to stderr/stdout (or start running, if it has not yet done
+    /// so)?
+    last_output: Instant,
     /// What time must this Job have completed by? If it exceeds this time, it will be terminated.
     finish_by: Instant,
+    /// Has the child process been sent its `timeout_signal` for exceeding its deadline? Since we
+    /// send the signal on every iteration until the process exits, this stops us logging that more
+    /// than once.
+    sigtermed: bool,
+    /// If `kill_group` is set, have the other members of the current child process's process
+    /// group been sent SIGTERM because the child exited without them closing its stderr/stdout?
+    orphans_signalled: bool,
+    /// The index in `rconf.cmd` of the command that is currently running (or, if `is_errorcmd` is
+    /// true, that failed).
+    cmd_index: usize,
+    /// If `is_errorcmd` is true, the `%x` and `%?` values for the command that failed, so that
+    /// `errorcmd` can be retried with them.
+    failed_exit_type: &'static str,
+    failed_exit_code: String,
+    /// How many times has `errorcmd` been retried?
+    errorcmd_retried: usize,
     /// The child process itself.
     child: Child,
+    /// If the job runs in a cgroup, the path to that cgroup's `cgroup.procs` file.
+    cgroup_procs: Option<CString>,
     /// This TempDir will be dropped, and its file system contents removed, when this Job is dropped.
     tempdir: TempDir,
     /// We are responsible for manually cleaning up the JSON file stored in `json_path`.
     json_path: PathBuf,
-    /// The temporary file to which we write combined stderr/stdout.
-    stderrout: NamedTempFile,
+    /// We are responsible for manually cleaning up the regex captures file stored in
+    /// `captures_path`.
+    captures_path: PathBuf,
+    /// If the `raw_body` option is set, we are responsible for manually cleaning up the raw
+    /// request body file stored in `raw_body_path`.
+    raw_body_path: Option<PathBuf>,
+    /// We are responsible for manually cleaning up the file stored in `status_path`, to which the
+    /// command may write a status line (see `%m`).
+    status_path: PathBuf,
+    /// The temporary file to which we write combined stderr/stdout. Since commands can produce
+    /// output in many small chunks, writes are buffered: the buffer must be flushed before the
+    /// file is read.
+    stderrout: BufWriter<NamedTempFile>,
+    /// If `tail_output` is set, the most recent output, which is only written to `stderrout` when
+    /// the job's commands have finished.
+    output_tail: VecDeque<u8>,
     /// Has the child process's stderr been closed?
     stderr_hup: bool,
     /// Has the child process's stdout been closed?
     stdout_hup: bool,
+    /// If output is being forwarded to syslog, the partial line (if any) most recently read from
+    /// stderr.
+    stderr_pending: Vec<u8>,
+    /// If output is being forwarded to syslog, the partial line (if any) most recently read from
+    /// stdout.
+    stdout_pending: Vec<u8>,
     /// The `RepoConfig` for this job.
     rconf: RepoConfig,
 }
 
+/// Create (if necessary) the cgroup `cgroup`, relative to `CGROUP_ROOT`, returning the path to its
+/// `cgroup.procs` file if it can be written to.
+fn prepare_cgroup(cgroup: &str) -> Result<CString, Box<dyn Error>> {
+    let dir = Path::new(CGROUP_ROOT).join(cgroup);
+    fs::create_dir_all(&dir)?;
+    let procs = dir.join("cgroup.procs");
+    OpenOptions::new().write(true).open(&procs)?;
+    Ok(CString::new(procs.into_os_string().into_vec())?)
+}
+
+/// Set the current process's nice value to `nice`, undoing the effects of `runner_priority`. This
+/// is called in a child process between `fork` and `exec`, so it must only call async-signal-safe
+/// functions.
+fn reset_priority(nice: c_int) -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Limit the CPU time of the current process to `secs` seconds, after which the kernel sends it
+/// SIGXCPU. If the process ignores SIGXCPU, it is sent SIGKILL `CPU_LIMIT_GRACE` seconds later.
+/// This is called in a child process between `fork` and `exec`, so it must only call
+/// async-signal-safe functions.
+fn set_cpu_limit(secs: u64) -> io::Result<()> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_CPU, &mut rlim) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // We can't raise the hard limit, so we have to make sure that neither limit exceeds it.
+    let hard = rlim.rlim_max;
+    rlim.rlim_cur = (secs as libc::rlim_t).min(hard);
+    rlim.rlim_max = (secs.saturating_add(CPU_LIMIT_GRACE) as libc::rlim_t).min(hard);
+    if unsafe { libc::setrlimit(libc::RLIMIT_CPU, &rlim) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Limit the virtual memory of the current process to `bytes` bytes, beyond which its attempts to
+/// allocate memory fail. This is called in a child process between `fork` and `exec`, so it must
+/// only call async-signal-safe functions.
+///
+/// Unlike `set_cpu_limit`, there is no way for us to find out that the limit was reached: the
+/// kernel doesn't send a signal, so `%x` in `errorcmd` reports whatever the command did when its
+/// allocation failed.
+fn set_mem_limit(bytes: u64) -> io::Result<()> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_AS, &mut rlim) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // We can't raise the hard limit, so we have to make sure that we don't exceed it. Lowering the
+    // hard limit stops the command from raising the limit itself.
+    rlim.rlim_cur = (bytes as libc::rlim_t).min(rlim.rlim_max);
+    rlim.rlim_max = rlim.rlim_cur;
+    if unsafe { libc::setrlimit(libc::RLIMIT_AS, &rlim) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Move the current process into the cgroup whose `cgroup.procs` file is `procs`. This is called
+/// in a child process between `fork` and `exec`, so it must only call async-signal-safe functions
+/// and must not allocate.
+fn enter_cgroup(procs: &CStr) -> io::Result<()> {
+    let fd = unsafe { libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Writing "0" to `cgroup.procs` moves the writing process into the cgroup.
+    let r = unsafe { libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) };
+    let err = io::Error::last_os_error();
+    unsafe {
+        libc::close(fd);
+    }
+    if r != 1 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+impl Job {
+    /// Record `data` that the job's command wrote to stderr/stdout.
+    fn write_output(&mut self, data: &[u8]) {
+        match self.rconf.tail_output {
+            Some(n) => {
+                self.output_tail.extend(data);
+                if self.output_tail.len() > n {
+                    self.output_tail.drain(..self.output_tail.len() - n);
+                }
+            }
+            None => {
+                self.stderrout.write_all(data).ok();
+            }
+        }
+    }
+
+    /// Make sure that all the output recorded by `write_output` is in the `stderrout` file.
+    fn flush_output(&mut self) {
+        let (front, back) = self.output_tail.as_slices();
+        self.stderrout.write_all(front).ok();
+        self.stderrout.write_all(back).ok();
+        self.output_tail.clear();
+        self.stderrout.flush().ok();
+    }
+
+    /// When must this job be terminated by? This is the earlier of `finish_by` and, if the job has
+    /// an idle timeout, the time at which it will have produced no output for that long. Since
+    /// `errorcmd`s' output is not captured, they are only subject to `finish_by`.
+    fn deadline(&self) -> Instant {
+        match self.rconf.idle_timeout {
+            Some(t) if !self.is_errorcmd => self
+                .last_output
+                .checked_add(Duration::from_secs(t))
+                .map(|x| x.min(self.finish_by))
+                .unwrap_or(self.finish_by),
+            _ => self.finish_by,
+        }
+    }
+}
+
 fn set_nonblock(fd: RawFd) -> Result<(), Box<dyn Error>> {
     let mut flags = fcntl(fd, FcntlArg::F_GETFL)?;
     flags |= OFlag::O_NONBLOCK.bits();
@@ -661,42 +1588,130 @@ fn set_nonblock(fd: RawFd) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-pub(crate) fn attend(snare: Arc<Snare>) -> Result<(), Box<dyn Error>> {
+pub(crate) fn attend(snare: Arc<Snare>) -> Result<JoinHandle<bool>, Box<dyn Error>> {
     let mut rn = JobRunner::new(snare)?;
-    thread::spawn(move || rn.attend());
-    Ok(())
+    Ok(thread::spawn(move || {
+        rn.set_priority();
+        rn.attend()
+    }))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::config::CmdContext;
+
+    #[test]
+    fn test_modifiers_substituted() {
+        // Every modifier that config allows in a context must be substituted in that context
+        // (`replace` panics on modifiers it has no value for).
+        for &c in CmdContext::Cmd.modifiers() {
+            let s = format!("%{c}");
+            assert_ne!(
+                cmd_replace(&s, "x", "x", "x", "x", "x", "x", "x", "x", "x", "x"),
+                s
+            );
+        }
+        for &c in CmdContext::ErrorCmd.modifiers() {
+            let s = format!("%{c}");
+            assert_ne!(
+                errorcmd_replace(&s, "x", "x", "x", "x", "x", "x", "x", "x", "x", "x", "x", "x"),
+                s
+            );
+        }
+        for &c in CmdContext::ReloadErrorCmd.modifiers() {
+            let s = format!("%{c}");
+            assert_ne!(reload_errorcmd_replace(&s, "x"), s);
+        }
+    }
 
     #[test]
     fn test_cmd_replace() {
-        assert_eq!(cmd_replace("", "", "", "", ""), "");
-        assert_eq!(cmd_replace("a", "", "", "", ""), "a");
+        assert_eq!(cmd_replace("", "", "", "", "", "", "", "", "", "", ""), "");
+        assert_eq!(
+            cmd_replace("a", "", "", "", "", "", "", "", "", "", ""),
+            "a"
+        );
+        assert_eq!(
+            cmd_replace(
+                "%% %e %F %h %o %r %j %g %R %m %M %%",
+                "ee",
+                "true",
+                "hh",
+                "oo",
+                "rr",
+                "jj",
+                "gg",
+                "RR",
+                "mm",
+                "a/.*"
+            ),
+            "% ee true hh oo rr jj gg RR mm 'a/.*' %"
+        );
+        assert_eq!(
+            cmd_replace("%F", "", "", "", "", "", "", "", "", "", ""),
+            "''"
+        );
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("a"), "a");
+        assert_eq!(shell_quote("/tmp/.tmpA-b_c"), "/tmp/.tmpA-b_c");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$(rm -rf /)"), "'$(rm -rf /)'");
+        assert_eq!(shell_quote("a'b"), "'a'\\''b'");
+        assert_eq!(
+            cmd_replace("%j %%", "", "", "", "", "", "/a b/c", "", "", "", ""),
+            "'/a b/c' %"
+        );
+    }
+
+    #[test]
+    fn test_take_lines() {
+        let mut pending = Vec::new();
+        assert!(take_lines(&mut pending, b"").is_empty());
+        assert!(take_lines(&mut pending, b"ab").is_empty());
         assert_eq!(
-            cmd_replace("%% %e %o %r %j %%", "ee", "oo", "rr", "jj"),
-            "% ee oo rr jj %"
+            take_lines(&mut pending, b"c\nd\n\nef"),
+            vec!["abc", "d", ""]
         );
+        assert_eq!(pending, b"ef");
+        assert_eq!(take_lines(&mut pending, b"\n"), vec!["ef"]);
+        assert!(pending.is_empty());
+        let long = vec![b'a'; READBUF + 1];
+        assert_eq!(take_lines(&mut pending, &long).len(), 1);
+        assert_eq!(pending, b"a");
     }
 
     #[test]
     fn test_errorcmd_replace() {
-        assert_eq!(errorcmd_replace("", "", "", "", "", "", "", ""), "");
-        assert_eq!(errorcmd_replace("a", "", "", "", "", "", "", ""), "a");
+        assert_eq!(
+            errorcmd_replace("", "", "", "", "", "", "", "", "", "", "", "", ""),
+            ""
+        );
+        assert_eq!(
+            errorcmd_replace("a", "", "", "", "", "", "", "", "", "", "", "", ""),
+            "a"
+        );
         assert_eq!(
             errorcmd_replace(
-                "%% %e %o %r %j %s %x %? %%",
+                "%% %e %F %h %o %r %j %g %R %M %s %x %? %%",
                 "ee",
+                "false",
+                "hh",
                 "oo",
                 "rr",
                 "jj",
+                "gg",
+                "RR",
+                "MM",
                 "ss",
                 "ex",
                 "ec"
             ),
-            "% ee oo rr jj ss ex ec %"
+            "% ee false hh oo rr jj gg RR MM ss ex ec %"
         );
     }
 }
@@ -4,7 +4,7 @@
 //!   * The `jobrunner` pops elements from the `Queue` and runs them in parallel.
 //! These two components run as two different threads: the `httpserver` writes a solitary byte to
 //! an "event pipe" to wake up the `jobrunner` when the queue has new elements. We also wake up the
-//! `jobrunner` on SIGHUP and SIGCHLD.
+//! `jobrunner` on SIGHUP, SIGCHLD, and SIGUSR2.
 
 #![allow(clippy::type_complexity)]
 
@@ -16,27 +16,31 @@ mod queue;
 
 use std::{
     env::{self, current_exe, set_current_dir},
-    ffi::CString,
+    ffi::{CString, OsString},
+    fs::read_to_string,
+    io::Write,
     os::unix::io::RawFd,
-    path::PathBuf,
-    process,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        Arc, Mutex, MutexGuard,
     },
+    thread,
 };
 
 use getopts::Options;
 use libc::{
-    c_char, openlog, syslog, LOG_CONS, LOG_CRIT, LOG_DAEMON, LOG_ERR, LOG_INFO, LOG_WARNING,
+    c_char, c_int, openlog, syslog, LOG_CONS, LOG_CRIT, LOG_DAEMON, LOG_ERR, LOG_INFO, LOG_WARNING,
 };
 use nix::{
     fcntl::OFlag,
     unistd::{daemon, pipe2, setresgid, setresuid, Gid, Uid},
 };
 use pwd::Passwd;
+use tempfile::{NamedTempFile, TempPath};
 
-use config::Config;
+use config::{syslog_facility, Config, GitHub, Provider};
 use queue::Queue;
 
 /// Default location of `snare.conf`.
@@ -57,13 +61,18 @@ pub(crate) struct Snare {
     log_level: LogLevel,
     /// The location of snare.conf; this file will be reloaded if SIGHUP is received.
     conf_path: PathBuf,
-    /// The current configuration: note that this can change at any point due to SIGHUP. All calls
-    /// to `conf.lock().unwrap()` are considered safe since the only way this can fail is if the
-    /// other thread has `panic`ed, at which point we're already doomed.
+    /// If `Some`, the value of `--maxjobs`, which overrides `maxjobs` in snare.conf (including
+    /// when snare.conf is reloaded).
+    maxjobs_override: Option<usize>,
+    /// Was `--once` specified? If so, the httpserver stops accepting requests once one has caused
+    /// a job to be queued, and the jobrunner returns once all jobs have finished.
+    once: bool,
+    /// In `--once` mode, has the httpserver accepted the request whose jobs we are to run?
+    once_queued: AtomicBool,
+    /// The current configuration: note that this can change at any point due to SIGHUP. This
+    /// should only be locked via `lock_conf`.
     conf: Mutex<Config>,
-    /// The current queue of incoming jobs. All calls to `queue.lock().unwrap()` are considered
-    /// safe since the only way this can fail is if the other thread has `panic`ed, at which point
-    /// we're already doomed.
+    /// The current queue of incoming jobs. This should only be locked via `lock_queue`.
     queue: Mutex<Queue>,
     /// The read end of the pipe used by the httpserver and the SIGHUP handler to wake up the job
     /// runner thread.
@@ -74,20 +83,120 @@ pub(crate) struct Snare {
     /// Has a SIGHUP event occurred? If so, the jobrunner will process it, and set this to false in
     /// case future SIGHUP events are detected.
     sighup_occurred: Arc<AtomicBool>,
+    /// Are we draining? If so, incoming requests are still queued, but the jobrunner does not run
+    /// any of them until this is set to false. This is toggled each time SIGUSR2 is received.
+    draining: Arc<AtomicBool>,
+    /// Have we warned that an unsigned request for a repository with no secret was received from a
+    /// non-loopback address? We only warn once so as not to flood the logs.
+    warned_unsecured: AtomicBool,
 }
 
 impl Snare {
     /// Check to see if we've received a SIGHUP since the last check. If so, we will try reloading
-    /// the snare.conf file specified when we started. **Note that another thread may have called
-    /// this function and caused the config to have changed.**
-    fn check_for_sighup(&self) {
+    /// the snare.conf file specified when we started. If the config was successfully reloaded,
+    /// the new config's `maxjobs` is returned: since this is read from the new config before it is
+    /// made visible to other threads, the caller can never observe a `maxjobs` which is out of
+    /// sync with the active config. **Note that another thread may have called this function and
+    /// caused the config to have changed.**
+    fn check_for_sighup(self: &Arc<Self>) -> Option<usize> {
+        let mut new_maxjobs = None;
         if self.sighup_occurred.load(Ordering::Relaxed) {
             match Config::from_path(&self.conf_path) {
-                Ok(conf) => *self.conf.lock().unwrap() = conf,
-                Err(msg) => self.error(&msg),
+                Ok(mut conf) => {
+                    if let Some(maxjobs) = self.maxjobs_override {
+                        conf.maxjobs = maxjobs;
+                    }
+                    let reloadcmd = conf.reloadcmd.clone();
+                    new_maxjobs = Some(conf.maxjobs);
+                    *self.lock_conf() = conf;
+                    if let Some(cmd) = reloadcmd {
+                        self.run_hook("reloadcmd", &cmd, None);
+                    }
+                }
+                Err(msg) => {
+                    self.error(&msg);
+                    let reload_errorcmd = self.lock_conf().reload_errorcmd.clone();
+                    if let Some(raw_cmd) = reload_errorcmd {
+                        self.run_reload_errorcmd(&raw_cmd, &msg);
+                    }
+                }
             }
             self.sighup_occurred.store(false, Ordering::Relaxed);
         }
+        new_maxjobs
+    }
+
+    /// Lock the current configuration. A poisoned lock means that another thread `panic`ed while
+    /// holding it, so we can no longer trust our internal state: rather than have every
+    /// subsequent request fail, we log the problem and exit so that a supervisor can cleanly
+    /// restart snare.
+    pub fn lock_conf(&self) -> MutexGuard<'_, Config> {
+        self.conf
+            .lock()
+            .unwrap_or_else(|_| fatal(self.daemonised, "Internal error: config lock poisoned"))
+    }
+
+    /// Lock the queue of incoming jobs. See `lock_conf` for how a poisoned lock is handled.
+    pub fn lock_queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue
+            .lock()
+            .unwrap_or_else(|_| fatal(self.daemonised, "Internal error: queue lock poisoned"))
+    }
+
+    /// Run `reload_errorcmd` (`raw_cmd`), with `%s` referencing a temporary file containing the
+    /// error message `msg`.
+    fn run_reload_errorcmd(self: &Arc<Self>, raw_cmd: &str, msg: &str) {
+        let msg_path = match NamedTempFile::new().and_then(|mut tfile| {
+            tfile.write_all(msg.as_bytes())?;
+            Ok(tfile.into_temp_path())
+        }) {
+            Ok(p) => p,
+            Err(e) => {
+                self.error(&format!(
+                    "Can't create temporary file for reload_errorcmd: {e}"
+                ));
+                return;
+            }
+        };
+        match msg_path.to_str() {
+            Some(msg_path_str) => {
+                let cmd = jobrunner::reload_errorcmd_replace(raw_cmd, msg_path_str);
+                self.run_hook("reload_errorcmd", &cmd, Some(msg_path));
+            }
+            None => self.error("Temporary file path for reload_errorcmd is not valid UTF-8"),
+        }
+    }
+
+    /// Run the shell command `cmd` in the background, logging (but otherwise ignoring) any
+    /// failure. `name` is used to identify the command in log messages. If `tmp` is `Some`, the
+    /// temporary file it references is removed once the command has exited.
+    fn run_hook(self: &Arc<Self>, name: &str, cmd: &str, tmp: Option<TempPath>) {
+        let shell = env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_owned());
+        match Command::new(shell)
+            .arg("-c")
+            .arg(cmd)
+            .stderr(Stdio::null())
+            .stdout(Stdio::null())
+            .stdin(Stdio::null())
+            .spawn()
+        {
+            Ok(mut child) => {
+                // We wait for the child in a separate thread so that it doesn't become a zombie.
+                let snare = Arc::clone(self);
+                let name = name.to_owned();
+                thread::spawn(move || {
+                    match child.wait() {
+                        Ok(status) if status.success() => (),
+                        Ok(status) => {
+                            snare.error(&format!("{name} exited unsuccessfully: {status}"))
+                        }
+                        Err(e) => snare.error(&format!("Can't wait for {name}: {e}")),
+                    }
+                    drop(tmp);
+                });
+            }
+            Err(e) => self.error(&format!("Can't spawn {name}: {e}")),
+        }
     }
 
     fn log(&self, msg: &str, log_level: LogLevel) {
@@ -95,11 +204,9 @@ impl Snare {
             return;
         }
         if self.daemonised {
-            // We know that `%s` and `<can't represent as CString>` are both valid C strings, and
-            // that neither unwrap() can fail.
+            // We know that `%s` is a valid C string, so this unwrap() can't fail.
             let fmt = CString::new("%s").unwrap();
-            let msg = CString::new(msg)
-                .unwrap_or_else(|_| CString::new("<can't represent as CString>").unwrap());
+            let msg = log_cstring(msg);
             let syslog_level = match self.log_level {
                 LogLevel::Error => LOG_ERR,
                 LogLevel::Warn => LOG_WARNING,
@@ -113,29 +220,33 @@ impl Snare {
         }
     }
 
+    /// Log a line of output `line` from a job for `repo_id` at syslog priority `priority`. If we
+    /// are not daemonised, `line` is printed to stderr.
+    pub fn log_job_output(&self, priority: c_int, repo_id: &str, line: &str) {
+        let msg = format!("{repo_id}: {line}");
+        if self.daemonised {
+            // We know that `%s` is a valid C string, so this unwrap() can't fail.
+            let fmt = CString::new("%s").unwrap();
+            let msg = log_cstring(&msg);
+            unsafe {
+                syslog(priority, fmt.as_ptr(), msg.as_ptr());
+            }
+        } else {
+            eprintln!("{}", msg);
+        }
+    }
+
     /// Log `msg` as an error.
-    ///
-    /// # Panics
-    ///
-    /// If `msg` contains a `NUL` byte.
     pub fn error(&self, msg: &str) {
         self.log(msg, LogLevel::Error);
     }
 
     /// Log `msg` as a warning.
-    ///
-    /// # Panics
-    ///
-    /// If `msg` contains a `NUL` byte.
     pub fn warn(&self, msg: &str) {
         self.log(msg, LogLevel::Warn);
     }
 
     /// Log `msg` as an informational message.
-    ///
-    /// # Panics
-    ///
-    /// If `msg` contains a `NUL` byte.
     pub fn info(&self, msg: &str) {
         self.log(msg, LogLevel::Info);
     }
@@ -150,6 +261,19 @@ fn search_snare_conf() -> Option<PathBuf> {
     None
 }
 
+/// Return the path of the `snare.conf` file to use: `cli_path` (i.e. the argument to `-c`) if
+/// specified; otherwise `env_path` (i.e. the value of `$SNARE_CONF`) if it is set and non-empty;
+/// otherwise the result of `search_snare_conf`.
+fn resolve_conf_path(cli_path: Option<String>, env_path: Option<OsString>) -> Option<PathBuf> {
+    if let Some(p) = cli_path {
+        return Some(PathBuf::from(p));
+    }
+    if let Some(p) = env_path.filter(|p| !p.is_empty()) {
+        return Some(PathBuf::from(p));
+    }
+    search_snare_conf()
+}
+
 fn user_from_name(n: &str) -> Option<Passwd> {
     match Passwd::from_name(n) {
         Ok(Some(x)) => Some(x),
@@ -162,6 +286,7 @@ fn user_from_name(n: &str) -> Option<Passwd> {
 }
 
 /// If the config specified a 'user' then switch to that and update $HOME and $USER appropriately.
+/// Unless the config explicitly allows it, we then check that we are not (still) running as root.
 /// This function must not be called after daemonisation.
 fn change_user(conf: &Config) {
     match conf.user {
@@ -181,7 +306,7 @@ fn change_user(conf: &Config) {
             None => fatal(false, &format!("Unknown user '{user}'")),
         },
         None => {
-            if Uid::current().is_root() {
+            if Uid::current().is_root() && !conf.allow_root {
                 fatal(
                     false,
                     "The 'user' option must be set if snare is run as root",
@@ -189,6 +314,13 @@ fn change_user(conf: &Config) {
             }
         }
     }
+    // Even if we have changed user, we might still be root (e.g. because `user` has a UID of 0).
+    if !conf.allow_root && (Uid::current().is_root() || Uid::effective().is_root()) {
+        fatal(
+            false,
+            "Still running as root after changing user: set 'allow_root = on;' if this is intended",
+        );
+    }
 }
 
 fn progname() -> String {
@@ -202,14 +334,19 @@ fn progname() -> String {
     }
 }
 
+/// Convert `msg` into a C string suitable for passing to syslog. Since C strings can't contain
+/// `NUL` bytes, any in `msg` are replaced with the escape sequence `\0`.
+fn log_cstring(msg: &str) -> CString {
+    // Since we've replaced all `NUL` bytes, this unwrap() can't fail.
+    CString::new(msg.replace('\0', "\\0")).unwrap()
+}
+
 /// Exit with a fatal error.
 fn fatal(daemonised: bool, msg: &str) -> ! {
     if daemonised {
-        // We know that `%s` and `<can't represent as CString>` are both valid C strings, and
-        // that neither unwrap() can fail.
+        // We know that `%s` is a valid C string, so this unwrap() can't fail.
         let fmt = CString::new("%s").unwrap();
-        let msg = CString::new(msg)
-            .unwrap_or_else(|_| CString::new("<can't represent as CString>").unwrap());
+        let msg = log_cstring(msg);
         unsafe {
             syslog(LOG_CRIT, fmt.as_ptr(), msg.as_ptr());
         }
@@ -219,9 +356,215 @@ fn fatal(daemonised: bool, msg: &str) -> ! {
     process::exit(1);
 }
 
+/// Return the configuration of the provider named `name` (`github` or `gitlab`) or, if `name` is
+/// `None`, of the default provider.
+fn provider_conf<'a>(conf: &'a Config, name: Option<&str>) -> Result<&'a GitHub, String> {
+    let provider = match name {
+        None => return Ok(conf.default_provider()),
+        Some("github") => Provider::GitHub,
+        Some("gitlab") => Provider::GitLab,
+        Some(x) => {
+            return Err(format!(
+                "Unknown provider '{x}': must be 'github' or 'gitlab'"
+            ))
+        }
+    };
+    conf.provider(provider)
+        .ok_or_else(|| format!("No '{}' block is specified", provider.block_name()))
+}
+
+/// Print out which `match` blocks of the provider `pconf` apply to a request, and the command that
+/// would be run, without running anything. `owner_repo` is of the form `owner/repo`: if it is
+/// `None`, the owner and repository name are taken from the JSON payload in `payload_path`.
+fn explain_match(
+    conf: &Config,
+    pconf: &GitHub,
+    owner_repo: Option<&str>,
+    event_type: &str,
+    payload_path: Option<&str>,
+) -> Result<(), String> {
+    let payload = match payload_path {
+        Some(p) => {
+            let s = read_to_string(p).map_err(|e| format!("Can't read {p}: {e}"))?;
+            let jv = serde_json::from_str::<serde_json::Value>(&s)
+                .map_err(|e| format!("Can't parse {p}: {e}"))?;
+            Some(jv)
+        }
+        None => None,
+    };
+    let (owner, repo) = match (owner_repo, &payload) {
+        (Some(x), _) => match x.split_once('/') {
+            Some((o, r)) => (o.to_owned(), r.to_owned()),
+            None => return Err(format!("'{x}' is not of the form owner/repo")),
+        },
+        (None, Some(jv)) => match pconf.owner_repo(jv) {
+            Some((o, r)) => (o.to_owned(), r.to_owned()),
+            None => return Err("Payload does not specify a repository owner and name".to_owned()),
+        },
+        (None, None) => return Err("No repository specified".to_owned()),
+    };
+
+    println!("Matching blocks for {owner}/{repo}:");
+    for re in pconf.applicable_regexes(&owner, &repo, None) {
+        println!("  match \"{re}\"");
+    }
+    if let Some(everycmd) = &conf.everycmd {
+        println!("everycmd (run for every request): {everycmd}");
+    }
+    let (mut rconf, _) = pconf.repoconfig(&owner, &repo, None);
+    if event_type == "ping" {
+        match &conf.ping_cmd {
+            Some(ping_cmd) => rconf.cmd = vec![ping_cmd.clone()],
+            None => {
+                println!("No command would be run for a ping event");
+                return Ok(());
+            }
+        }
+    } else if let Some(jv) = &payload {
+        if let Err(msg) = rconf.check_filters(jv, None) {
+            println!("Request would be dropped: {msg}");
+            return Ok(());
+        }
+    }
+    if rconf.cmd.is_empty() {
+        if rconf.errorcmd_without_cmd && rconf.errorcmd.is_some() {
+            println!("No command would be run, but errorcmd would be run (errorcmd_without_cmd)");
+        } else {
+            println!("No command would be run");
+        }
+    }
+    let forced = match payload.as_ref().and_then(|jv| jv["forced"].as_bool()) {
+        Some(true) => "true",
+        Some(false) => "false",
+        None => "",
+    };
+    for raw_cmd in &rconf.cmd {
+        let cmd = jobrunner::cmd_replace(
+            raw_cmd,
+            event_type,
+            forced,
+            payload.as_ref().map(httpserver::head_sha).unwrap_or(""),
+            &owner,
+            &repo,
+            payload_path.unwrap_or("<json-path>"),
+            "<captures-path>",
+            if rconf.raw_body {
+                "<raw-body-path>"
+            } else {
+                ""
+            },
+            "<status-path>",
+            &rconf.matched,
+        );
+        println!("cmd: {cmd}");
+    }
+    if let Some(errorcmd) = &rconf.errorcmd {
+        if rconf.cmd.is_empty() && rconf.errorcmd_without_cmd {
+            println!("errorcmd (with %x set to \"none\"): {errorcmd}");
+        } else {
+            println!("errorcmd (if cmd fails): {errorcmd}");
+        }
+    }
+    Ok(())
+}
+
+/// For each `owner/repo` line in the file `repos_path`, print which `match` blocks of the provider
+/// `pconf` apply to a
+/// request for that repository, and whether a command (or, because of `errorcmd_without_cmd`, only
+/// `errorcmd`) would be run. Blank lines, and lines starting with `#`, are ignored.
+fn check_repos(pconf: &GitHub, repos_path: &str) -> Result<(), String> {
+    let s = read_to_string(repos_path).map_err(|e| format!("Can't read {repos_path}: {e}"))?;
+    let mut rows = Vec::new();
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (owner, repo) = line.split_once('/').ok_or_else(|| {
+            format!(
+                "{repos_path}:{}: '{line}' is not of the form owner/repo",
+                i + 1
+            )
+        })?;
+        let regexes = pconf
+            .applicable_regexes(owner, repo, None)
+            .iter()
+            .map(|re| format!("\"{re}\""))
+            .collect::<Vec<_>>();
+        let (rconf, _) = pconf.repoconfig(owner, repo, None);
+        let cmd = if !rconf.cmd.is_empty() {
+            "yes"
+        } else if rconf.errorcmd_without_cmd && rconf.errorcmd.is_some() {
+            "errorcmd"
+        } else {
+            "no"
+        };
+        rows.push((line, cmd, regexes.join(", ")));
+    }
+    let width = rows
+        .iter()
+        .map(|(owner_repo, _, _)| owner_repo.len())
+        .chain(Some("REPOSITORY".len()))
+        .max()
+        .unwrap();
+    println!("{:width$}  {:8}  MATCHES", "REPOSITORY", "CMD");
+    for (owner_repo, cmd, regexes) in rows {
+        println!("{owner_repo:width$}  {cmd:8}  {regexes}");
+    }
+    Ok(())
+}
+
+/// Return a systemd unit which runs this snare binary with the config file `conf_path`.
+fn systemd_unit(conf_path: &Path) -> String {
+    let exe = current_exe().unwrap_or_else(|_| PathBuf::from("/usr/local/bin/snare"));
+    // systemd requires absolute paths.
+    let conf_path = conf_path
+        .canonicalize()
+        .unwrap_or_else(|_| conf_path.to_owned());
+    format!(
+        r#"# A systemd unit for snare. Save this as /etc/systemd/system/snare.service and
+# then run 'systemctl daemon-reload && systemctl enable --now snare'. The
+# configuration is read from {conf}: see snare.conf(5).
+
+[Unit]
+Description=snare GitHub webhooks daemon
+Documentation=man:snare(1) man:snare.conf(5)
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=simple
+# snare logs to stderr (and thus the journal) when run with -d.
+ExecStart={exe} -d -c {conf}
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+# The user snare runs as. If snare.conf sets 'user', it should be set to the same
+# user. If 'listen' uses a port below 1024, add the following line:
+#   AmbientCapabilities=CAP_NET_BIND_SERVICE
+User=snare
+# Hardening. Jobs run with the same restrictions, so you may need to relax these
+# if your per-repo programs need to, for example, write to /etc.
+NoNewPrivileges=yes
+PrivateTmp=yes
+ProtectSystem=full
+ProtectKernelModules=yes
+ProtectKernelTunables=yes
+RestrictSUIDSGID=yes
+
+[Install]
+WantedBy=multi-user.target
+"#,
+        exe = exe.display(),
+        conf = conf_path.display()
+    )
+}
+
 /// Print out program usage then exit. This function must not be called after daemonisation.
 fn usage() -> ! {
-    eprintln!("Usage: {} [-c <config-path>] [-d]", progname());
+    let pn = progname();
+    eprintln!(
+        "Usage: {pn} [-c <config-path>] [-d] [--maxjobs <int>] [--no-chdir] [--once] [--syslog-facility <facility>]\n       {pn} [-c <config-path>] [--match <owner/repo>] [--event <event>] [--payload <json-path>] [--provider <provider>]\n       {pn} [-c <config-path>] [--provider <provider>] -t <repos-path>\n       {pn} --config-schema\n       {pn} --print-default-config\n       {pn} [-c <config-path>] --print-systemd-unit"
+    );
     process::exit(1)
 }
 
@@ -234,6 +577,74 @@ pub fn main() {
             "",
             "Don't detach from the terminal and log errors to stderr.",
         )
+        .optopt(
+            "",
+            "event",
+            "The event type to use with --match/--payload (default: push).",
+            "<event>",
+        )
+        .optopt(
+            "",
+            "match",
+            "Print what would happen if a request for <owner/repo> was received.",
+            "<owner/repo>",
+        )
+        .optopt(
+            "",
+            "provider",
+            "The provider (github or gitlab) to use with --match/--payload and -t (default: github if \
+             a github block is specified, otherwise gitlab).",
+            "<provider>",
+        )
+        .optopt(
+            "",
+            "payload",
+            "Print what would happen if the JSON payload in <json-path> was received.",
+            "<json-path>",
+        )
+        .optflag(
+            "",
+            "config-schema",
+            "Print all recognised snare.conf options.",
+        )
+        .optflag(
+            "",
+            "print-default-config",
+            "Print a minimal example snare.conf.",
+        )
+        .optflag(
+            "",
+            "print-systemd-unit",
+            "Print a systemd unit which runs snare.",
+        )
+        .optopt(
+            "",
+            "maxjobs",
+            "The maximum number of jobs to run in parallel (overrides 'maxjobs' in snare.conf).",
+            "<int>",
+        )
+        .optflag(
+            "",
+            "once",
+            "Run the jobs for a single request and then exit (implies -d).",
+        )
+        .optflag(
+            "",
+            "no-chdir",
+            "Don't change directory at startup (overrides 'chdir' in snare.conf).",
+        )
+        .optopt(
+            "",
+            "syslog-facility",
+            "The syslog facility to log to (default: daemon).",
+            "<facility>",
+        )
+        .optopt(
+            "t",
+            "",
+            "Print which match blocks apply to each owner/repo line in <repos-path>.",
+            "<repos-path>",
+        )
         .optflag("h", "help", "")
         .optflagmulti("v", "verbose", "")
         .parse(&args[1..])
@@ -242,13 +653,75 @@ pub fn main() {
         usage();
     }
 
-    let daemonise = !matches.opt_present("d");
+    if matches.opt_present("config-schema") {
+        print!("{}", Config::schema());
+        process::exit(0);
+    }
+    if matches.opt_present("print-default-config") {
+        print!("{}", Config::default_config());
+        process::exit(0);
+    }
+    if matches.opt_present("print-systemd-unit") {
+        let conf_path = resolve_conf_path(matches.opt_str("c"), env::var_os("SNARE_CONF"))
+            .unwrap_or_else(|| PathBuf::from(SNARE_CONF_PATH));
+        print!("{}", systemd_unit(&conf_path));
+        process::exit(0);
+    }
 
-    let conf_path = match matches.opt_str("c") {
-        Some(p) => PathBuf::from(&p),
-        None => search_snare_conf().unwrap_or_else(|| fatal(false, "Can't find snare.conf")),
+    let once = matches.opt_present("once");
+    // In `--once` mode, our caller waits for our exit status, so we mustn't detach.
+    let daemonise = !matches.opt_present("d") && !once;
+    let facility = match matches.opt_str("syslog-facility") {
+        Some(f) => syslog_facility(&f).unwrap_or_else(|| {
+            fatal(
+                false,
+                &format!(
+                    "Unknown syslog facility '{f}': must be one of daemon, user, or local0-local7"
+                ),
+            )
+        }),
+        None => LOG_DAEMON,
     };
-    let conf = Config::from_path(&conf_path).unwrap_or_else(|m| fatal(false, &m));
+
+    let conf_path = resolve_conf_path(matches.opt_str("c"), env::var_os("SNARE_CONF"))
+        .unwrap_or_else(|| fatal(false, "Can't find snare.conf"));
+    let maxjobs_override = matches
+        .opt_str("maxjobs")
+        .map(|s| match s.parse::<usize>() {
+            Ok(x) if x > 0 => x,
+            _ => fatal(
+                false,
+                &format!("Invalid --maxjobs '{s}': must be a non-zero positive integer"),
+            ),
+        });
+    let mut conf = Config::from_path(&conf_path).unwrap_or_else(|m| fatal(false, &m));
+    if let Some(maxjobs) = maxjobs_override {
+        conf.maxjobs = maxjobs;
+    }
+
+    if let Some(repos_path) = matches.opt_str("t") {
+        let pconf = provider_conf(&conf, matches.opt_str("provider").as_deref())
+            .unwrap_or_else(|m| fatal(false, &m));
+        check_repos(pconf, &repos_path).unwrap_or_else(|m| fatal(false, &m));
+        process::exit(0);
+    }
+
+    if matches.opt_present("match") || matches.opt_present("payload") {
+        let event_type = matches
+            .opt_str("event")
+            .unwrap_or_else(|| "push".to_owned());
+        let pconf = provider_conf(&conf, matches.opt_str("provider").as_deref())
+            .unwrap_or_else(|m| fatal(false, &m));
+        explain_match(
+            &conf,
+            pconf,
+            matches.opt_str("match").as_deref(),
+            &event_type,
+            matches.opt_str("payload").as_deref(),
+        )
+        .unwrap_or_else(|m| fatal(false, &m));
+        process::exit(0);
+    }
 
     let log_level = match matches.opt_count("v") {
         0 => LogLevel::Error,
@@ -256,9 +729,22 @@ pub fn main() {
         _ => LogLevel::Info,
     };
 
+    // We must check for systemd socket activation before daemonising, as that changes our PID.
+    let inherited_listener = httpserver::systemd_listener().unwrap_or_else(|m| fatal(false, &m));
+    if conf.listen.is_none() && inherited_listener.is_none() {
+        fatal(false, "A 'listen' address must be specified");
+    }
+
     change_user(&conf);
 
-    set_current_dir("/").unwrap_or_else(|_| fatal(false, "Can't chdir to '/'"));
+    if !matches.opt_present("no-chdir") {
+        set_current_dir(&conf.chdir).unwrap_or_else(|e| {
+            fatal(
+                false,
+                &format!("Can't chdir to '{}': {e}", conf.chdir.display()),
+            )
+        });
+    }
     if daemonise {
         if let Err(e) = daemon(true, false) {
             fatal(false, &format!("Couldn't daemonise: {e}"));
@@ -273,7 +759,7 @@ pub fn main() {
     let progname =
         Box::into_raw(CString::new(progname()).unwrap().into_boxed_c_str()) as *const c_char;
     unsafe {
-        openlog(progname, LOG_CONS, LOG_DAEMON);
+        openlog(progname, LOG_CONS, facility);
     }
 
     let (event_read_fd, event_write_fd) = match pipe2(OFlag::O_NONBLOCK) {
@@ -283,8 +769,10 @@ pub fn main() {
         }
     };
     let sighup_occurred = Arc::new(AtomicBool::new(false));
+    let draining = Arc::new(AtomicBool::new(false));
     {
         let sighup_occurred = Arc::clone(&sighup_occurred);
+        let draining = Arc::clone(&draining);
         if let Err(e) = unsafe {
             signal_hook::low_level::register(signal_hook::consts::SIGHUP, move || {
                 // All functions called in this function must be signal safe. See signal(3).
@@ -302,25 +790,70 @@ pub fn main() {
         } {
             fatal(daemonise, &format!("Can't install SIGCHLD handler: {e}"));
         }
+        if let Err(e) = unsafe {
+            signal_hook::low_level::register(signal_hook::consts::SIGUSR2, move || {
+                // All functions called in this function must be signal safe. See signal(3).
+                draining.fetch_xor(true, Ordering::Relaxed);
+                nix::unistd::write(event_write_fd, &[0]).ok();
+            })
+        } {
+            fatal(daemonise, &format!("Can't install SIGUSR2 handler: {e}"));
+        }
     }
 
     let snare = Arc::new(Snare {
         daemonised: daemonise,
         log_level,
         conf_path,
+        maxjobs_override,
+        once,
+        once_queued: AtomicBool::new(false),
         conf: Mutex::new(conf),
         queue: Mutex::new(Queue::new()),
         event_read_fd,
         event_write_fd,
         sighup_occurred,
+        draining,
+        warned_unsecured: AtomicBool::new(false),
     });
 
-    match jobrunner::attend(Arc::clone(&snare)) {
+    for w in snare.lock_conf().warnings() {
+        snare.warn(&w);
+    }
+
+    let runner = match jobrunner::attend(Arc::clone(&snare)) {
         Ok(x) => x,
         Err(e) => {
             fatal(daemonise, &format!("Couldn't start runner thread: {e}"));
         }
+    };
+
+    httpserver::serve(snare, inherited_listener).unwrap();
+    // `serve` only returns in `--once` mode, after which we wait for the request's jobs to finish.
+    match runner.join() {
+        Ok(true) => process::exit(0),
+        _ => process::exit(1),
     }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    httpserver::serve(snare).unwrap();
+    #[test]
+    fn test_resolve_conf_path() {
+        assert_eq!(
+            resolve_conf_path(Some("/a".to_owned()), Some(OsString::from("/b"))),
+            Some(PathBuf::from("/a"))
+        );
+        assert_eq!(
+            resolve_conf_path(None, Some(OsString::from("/b"))),
+            Some(PathBuf::from("/b"))
+        );
+        assert_eq!(
+            resolve_conf_path(None, Some(OsString::new())),
+            search_snare_conf()
+        );
+        assert_eq!(resolve_conf_path(None, None), search_snare_conf());
+    }
 }
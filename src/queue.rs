@@ -5,23 +5,41 @@ use std::{
 
 use crate::config::{QueueKind, RepoConfig};
 
+/// How many younger jobs can be run in preference to a job which is too heavy to run now? Once a
+/// job has been overtaken this many times, no younger jobs are run until it has been run, so that
+/// a steady stream of light jobs can't stop a heavy job from ever running.
+const MAX_OVERTAKES: usize = 8;
+
 pub(crate) struct QueueJob {
     pub repo_id: String,
     pub owner: String,
     pub repo: String,
     pub req_time: Instant,
     pub event_type: String,
+    /// For push events, "true" if the push was forced or "false" otherwise; for all other
+    /// events, the empty string.
+    pub forced: String,
+    /// The SHA of the commit the event relates to, or the empty string if there is no such commit.
+    pub head_sha: String,
+    /// If the `raw_body` option is set, the request body exactly as it was received.
+    pub raw_body: Option<Vec<u8>>,
     pub json_str: String,
     pub rconf: RepoConfig,
+    /// How many younger jobs have been run while this job was too heavy to run.
+    overtaken: usize,
 }
 
 impl QueueJob {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         repo_id: String,
         owner: String,
         repo: String,
         req_time: Instant,
         event_type: String,
+        forced: String,
+        head_sha: String,
+        raw_body: Option<Vec<u8>>,
         json_str: String,
         rconf: RepoConfig,
     ) -> Self {
@@ -31,42 +49,65 @@ impl QueueJob {
             repo,
             req_time,
             event_type,
+            forced,
+            head_sha,
+            raw_body,
             json_str,
             rconf,
+            overtaken: 0,
         }
     }
 }
 
 pub(crate) struct Queue {
+    /// The queued jobs of each `repo_id`. Entries are removed as soon as they are empty, so each
+    /// `VecDeque` contains at least one job.
     q: HashMap<String, VecDeque<QueueJob>>,
+    /// The total number of jobs in `q`.
+    len: usize,
 }
 
 impl Queue {
     pub fn new() -> Self {
-        Queue { q: HashMap::new() }
+        Queue {
+            q: HashMap::new(),
+            len: 0,
+        }
     }
 
     /// Are there any jobs in the queue?
     pub fn is_empty(&self) -> bool {
-        for v in self.q.values() {
-            if !v.is_empty() {
-                return false;
-            }
-        }
-        true
+        self.q.is_empty()
     }
 
-    /// Push a new request to the back of the queue.
-    pub fn push_back(&mut self, qj: QueueJob) {
-        let mut entry = self.q.entry(qj.repo_id.clone());
-
-        match qj.rconf.queuekind {
-            QueueKind::Evict => {
-                entry = entry.and_modify(|v| v.clear());
+    /// Push a new request to the back of the queue. If doing so would mean that more than
+    /// `max_total` jobs, or jobs for more than `max_repos` distinct `repo_id`s, are queued, the
+    /// request is not queued, and is instead returned via `Err`.
+    pub fn push_back(
+        &mut self,
+        qj: QueueJob,
+        max_total: Option<usize>,
+        max_repos: Option<usize>,
+    ) -> Result<(), QueueJob> {
+        let evicted = match qj.rconf.queuekind {
+            QueueKind::Evict => self.q.get(&qj.repo_id).map(|v| v.len()).unwrap_or(0),
+            QueueKind::Parallel | QueueKind::Sequential => 0,
+        };
+        if let Some(max_total) = max_total {
+            if self.len - evicted >= max_total {
+                return Err(qj);
             }
-            QueueKind::Parallel | QueueKind::Sequential => (),
         }
-        entry.or_default().push_back(qj);
+        if let Some(max_repos) = max_repos {
+            if self.q.len() >= max_repos && !self.q.contains_key(&qj.repo_id) {
+                return Err(qj);
+            }
+        }
+        let entry = self.q.entry(qj.repo_id.clone()).or_default();
+        entry.drain(..evicted);
+        entry.push_back(qj);
+        self.len = self.len - evicted + 1;
+        Ok(())
     }
 
     /// Push an old request which has failed due to a temporary error back to the front of the
@@ -75,16 +116,56 @@ impl Queue {
     /// lock on `self` should be held between calls to `pop` and `push_front`.
     pub fn push_front(&mut self, qj: QueueJob) {
         self.q.entry(qj.repo_id.clone()).or_default().push_front(qj);
+        self.len += 1;
     }
 
     /// If the queue has a runnable entry, pop and return it, or `None` otherwise. Note that `None`
     /// does not guarantee that the queue is empty: it may mean that there are queued jobs that
     /// can't be run until existing jobs finish. `running(repo_id)` is a function which must return
-    /// `true` if a job at `repo_id` is currently running and `false` otherwise.
-    pub fn pop<F>(&mut self, running: F) -> Option<QueueJob>
+    /// `true` if a job at `repo_id` is currently running and `false` otherwise. `locked(lock)` is a
+    /// function which must return `true` if a running job holds the lock `lock` and `false`
+    /// otherwise. If `allow_parallel` is `false`, jobs with `QueueKind::Parallel` are not popped.
+    /// `fits(weight)` is a function which must return `true` if a job of weight `weight` can be run
+    /// now: jobs which don't fit are skipped so that they don't block lighter jobs queued behind
+    /// them, but only until they have been overtaken `MAX_OVERTAKES` times.
+    pub fn pop<F, G, H>(
+        &mut self,
+        running: F,
+        locked: G,
+        allow_parallel: bool,
+        fits: H,
+    ) -> Option<QueueJob>
     where
         F: Fn(&str) -> bool,
+        G: Fn(&str) -> bool,
+        H: Fn(usize) -> bool,
     {
+        let runnable = |qj: &QueueJob| {
+            if let Some(lock) = &qj.rconf.lock {
+                if locked(lock) {
+                    return false;
+                }
+            }
+            match qj.rconf.queuekind {
+                QueueKind::Parallel => allow_parallel,
+                QueueKind::Evict | QueueKind::Sequential => !running(&qj.repo_id),
+            }
+        };
+        // Find the oldest job which could be run now were it not for its weight.
+        let mut heavy: Option<(&String, &QueueJob)> = None;
+        for (k, v) in self.q.iter() {
+            if let Some(qj) = v.front() {
+                if runnable(qj)
+                    && !fits(qj.rconf.weight)
+                    && heavy
+                        .map(|(_, hj)| qj.req_time < hj.req_time)
+                        .unwrap_or(true)
+                {
+                    heavy = Some((k, qj));
+                }
+            }
+        }
+        let heavy = heavy.map(|(k, hj)| (k.clone(), hj.req_time, hj.overtaken));
         // We find the oldest element in the queue and pop that.
         let mut earliest_time = None;
         let mut earliest_key = None;
@@ -95,12 +176,12 @@ impl Queue {
                         continue;
                     }
                 }
-                match qj.rconf.queuekind {
-                    QueueKind::Parallel => (),
-                    QueueKind::Evict | QueueKind::Sequential => {
-                        if running(&qj.repo_id) {
-                            continue;
-                        }
+                if !runnable(qj) || !fits(qj.rconf.weight) {
+                    continue;
+                }
+                if let Some((_, heavy_time, overtaken)) = &heavy {
+                    if *overtaken >= MAX_OVERTAKES && qj.req_time >= *heavy_time {
+                        continue;
                     }
                 }
                 earliest_time = Some(qj.req_time);
@@ -108,7 +189,102 @@ impl Queue {
             }
         }
         // If there's an `Entry` for the key, then the corresponding value vec has at least one
-        // value, so both unwrap()s are safe.
-        earliest_key.map(|k| self.q.get_mut(&k).unwrap().pop_front().unwrap())
+        // value, so all the unwrap()s are safe.
+        let k = earliest_key?;
+        let v = self.q.get_mut(&k).unwrap();
+        let qj = v.pop_front().unwrap();
+        if v.is_empty() {
+            self.q.remove(&k);
+        }
+        self.len -= 1;
+        if let Some((heavy_key, heavy_time, _)) = heavy {
+            if qj.req_time >= heavy_time {
+                self.q
+                    .get_mut(&heavy_key)
+                    .unwrap()
+                    .front_mut()
+                    .unwrap()
+                    .overtaken += 1;
+            }
+        }
+        Some(qj)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+    use std::str::FromStr;
+
+    fn queue_job(conf: &Config, repo: &str) -> QueueJob {
+        let (rconf, _) = conf.default_provider().repoconfig("owner", repo, None);
+        QueueJob::new(
+            format!("github/owner/{repo}"),
+            "owner".to_owned(),
+            repo.to_owned(),
+            Instant::now(),
+            "push".to_owned(),
+            String::new(),
+            String::new(),
+            None,
+            "{}".to_owned(),
+            rconf,
+        )
+    }
+
+    #[test]
+    fn empty_deques_pruned() {
+        let conf = Config::from_str("github { }").unwrap();
+        let mut q = Queue::new();
+        for i in 0..1000 {
+            let repo = format!("repo{i}");
+            assert!(q.push_back(queue_job(&conf, &repo), None, None).is_ok());
+            assert!(q.push_back(queue_job(&conf, &repo), None, None).is_ok());
+            assert_eq!(q.q.len(), 1);
+            assert!(q.pop(|_| false, |_| false, true, |_| true).is_some());
+            assert!(!q.is_empty());
+            assert!(q.pop(|_| false, |_| false, true, |_| true).is_some());
+            assert!(q.is_empty());
+            assert!(q.q.is_empty());
+            assert_eq!(q.len, 0);
+        }
+    }
+
+    #[test]
+    fn heavy_jobs_skipped() {
+        let conf = Config::from_str("github { match \"owner/heavy\" { weight = 2; } }").unwrap();
+        let mut q = Queue::new();
+        assert!(q.push_back(queue_job(&conf, "heavy"), None, None).is_ok());
+        assert!(q.push_back(queue_job(&conf, "light"), None, None).is_ok());
+        // With only one free slot, the heavy job is skipped in favour of the light one...
+        let qj = q.pop(|_| false, |_| false, true, |w| w <= 1).unwrap();
+        assert_eq!(qj.repo, "light");
+        assert!(q.pop(|_| false, |_| false, true, |w| w <= 1).is_none());
+        // ...but is popped once enough slots are free.
+        let qj = q.pop(|_| false, |_| false, true, |w| w <= 2).unwrap();
+        assert_eq!(qj.repo, "heavy");
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn heavy_jobs_not_starved() {
+        let conf = Config::from_str("github { match \"owner/heavy\" { weight = 2; } }").unwrap();
+        let mut q = Queue::new();
+        assert!(q.push_back(queue_job(&conf, "heavy"), None, None).is_ok());
+        // While only one slot is free, a steady stream of light jobs overtakes the heavy job...
+        for _ in 0..MAX_OVERTAKES {
+            assert!(q.push_back(queue_job(&conf, "light"), None, None).is_ok());
+            let qj = q.pop(|_| false, |_| false, true, |w| w <= 1).unwrap();
+            assert_eq!(qj.repo, "light");
+        }
+        // ...but only so many times, after which younger jobs wait until the heavy job has run.
+        assert!(q.push_back(queue_job(&conf, "light"), None, None).is_ok());
+        assert!(q.pop(|_| false, |_| false, true, |w| w <= 1).is_none());
+        let qj = q.pop(|_| false, |_| false, true, |w| w <= 2).unwrap();
+        assert_eq!(qj.repo, "heavy");
+        let qj = q.pop(|_| false, |_| false, true, |w| w <= 1).unwrap();
+        assert_eq!(qj.repo, "light");
+        assert!(q.is_empty());
     }
 }
@@ -85,6 +85,76 @@ X-GitHub-Hook-Installation-Target-Type: repository
     .unwrap();
 }
 
+#[test]
+fn first_match() {
+    // This tests that with `match_mode = first`, only the first matching block takes effect.
+
+    let td = Builder::new()
+        .tempdir_in(env!("CARGO_TARGET_TMPDIR"))
+        .unwrap();
+    let mut tp1 = td.path().to_owned();
+    tp1.push("t1");
+    let tp1s = tp1.as_path().to_str().unwrap();
+    let mut tp2 = td.path().to_owned();
+    tp2.push("t2");
+    let tp2s = tp2.as_path().to_str().unwrap();
+
+    run_success(
+        &format!(
+            r#"listen = "127.0.0.1:0";
+match_mode = first;
+github {{
+  // Should match
+  match "testuser/.*" {{
+    cmd = "touch {tp1s}";
+    secret = "secretsecret";
+  }}
+  // Should match but be ignored because of the previous entry
+  match "testuser/testrepo" {{
+    cmd = "touch {tp2s}";
+  }}
+}}"#
+        ),
+        &[(
+            move |port| {
+                Ok(format!(
+                    r#"POST /payload HTTP/1.1
+Host: 127.0.0.1:{port}
+Content-Length: 96
+X-GitHub-Delivery: 72d3162e-cc78-11e3-81ab-4c9367dc0958
+X-Hub-Signature-256: sha256=d11297e14fe5286dd68fd58c5e23ea7fb45e60ceff51ec3eb3729400fcbcb4b2
+User-Agent: GitHub-Hookshot/044aadd
+Content-Type: application/json
+X-GitHub-Event: issues
+X-GitHub-Hook-ID: 292430182
+X-GitHub-Hook-Installation-Target-ID: 79929171
+X-GitHub-Hook-Installation-Target-Type: repository
+
+{{
+  "repository": {{
+    "owner": {{
+      "login": "testuser"
+    }},
+    "name": "testrepo"
+  }}
+}}"#
+                ))
+            },
+            move |response: String| {
+                if response.starts_with("HTTP/1.1 200 OK") {
+                    sleep(SNARE_PAUSE);
+                    assert!(tp1.is_file());
+                    assert!(!tp2.is_file());
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        )],
+    )
+    .unwrap();
+}
+
 #[test]
 fn errorcmd() {
     // This tests both large stdout/stderr output from `cmd` as well as that `errorcmd` works.
@@ -148,3 +218,390 @@ X-GitHub-Hook-Installation-Target-Type: repository
         )],
     ).unwrap();
 }
+
+#[test]
+fn errorcmd_without_cmd() {
+    // This tests that `errorcmd_without_cmd` causes `errorcmd` to be run when there is no `cmd`.
+
+    let td = Builder::new()
+        .tempdir_in(env!("CARGO_TARGET_TMPDIR"))
+        .unwrap();
+    let mut tp = td.path().to_owned();
+    tp.push("t");
+    let tps = tp.as_path().to_str().unwrap();
+
+    run_success(
+        &format!(
+            r#"listen = "127.0.0.1:0";
+github {{
+  match ".*" {{
+    errorcmd = "echo %x %? > {tps}";
+    errorcmd_without_cmd = on;
+    secret = "secretsecret";
+  }}
+}}"#
+        ),
+        &[(
+            move |port| {
+                Ok(format!(
+                    r#"POST /payload HTTP/1.1
+Host: 127.0.0.1:{port}
+Content-Length: 96
+X-GitHub-Delivery: 72d3162e-cc78-11e3-81ab-4c9367dc0958
+X-Hub-Signature-256: sha256=d11297e14fe5286dd68fd58c5e23ea7fb45e60ceff51ec3eb3729400fcbcb4b2
+User-Agent: GitHub-Hookshot/044aadd
+Content-Type: application/json
+X-GitHub-Event: issues
+X-GitHub-Hook-ID: 292430182
+X-GitHub-Hook-Installation-Target-ID: 79929171
+X-GitHub-Hook-Installation-Target-Type: repository
+
+{{
+  "repository": {{
+    "owner": {{
+      "login": "testuser"
+    }},
+    "name": "testrepo"
+  }}
+}}"#
+                ))
+            },
+            move |response: String| {
+                if response.starts_with("HTTP/1.1 200 OK") {
+                    sleep(SNARE_PAUSE);
+                    assert_eq!(read_to_string(&tp).unwrap().trim(), "none");
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        )],
+    )
+    .unwrap();
+}
+
+#[test]
+fn multiple_cmds() {
+    // This tests that multiple `cmd`s are run in order, sharing their output, and that the first
+    // to fail stops the remainder being run and causes `errorcmd` to be run.
+
+    let td = Builder::new()
+        .tempdir_in(env!("CARGO_TARGET_TMPDIR"))
+        .unwrap();
+    let mut tp1 = td.path().to_owned();
+    tp1.push("t1");
+    let tp1s = tp1.as_path().to_str().unwrap();
+    let mut tp2 = td.path().to_owned();
+    tp2.push("t2");
+    let tp2s = tp2.as_path().to_str().unwrap();
+    let mut tp3 = td.path().to_owned();
+    tp3.push("t3");
+    let tp3s = tp3.as_path().to_str().unwrap();
+
+    run_success(
+        &format!(
+            r#"listen = "127.0.0.1:0";
+github {{
+  match ".*" {{
+    cmd = "echo a && touch {tp1s}";
+    cmd = "echo b && test -f {tp1s} && exit 2";
+    cmd = "touch {tp2s}";
+    errorcmd = "echo %x %? > {tp3s} && cat %s >> {tp3s}";
+    secret = "secretsecret";
+  }}
+}}"#
+        ),
+        &[(
+            move |port| {
+                Ok(format!(
+                    r#"POST /payload HTTP/1.1
+Host: 127.0.0.1:{port}
+Content-Length: 96
+X-GitHub-Delivery: 72d3162e-cc78-11e3-81ab-4c9367dc0958
+X-Hub-Signature-256: sha256=d11297e14fe5286dd68fd58c5e23ea7fb45e60ceff51ec3eb3729400fcbcb4b2
+User-Agent: GitHub-Hookshot/044aadd
+Content-Type: application/json
+X-GitHub-Event: issues
+X-GitHub-Hook-ID: 292430182
+X-GitHub-Hook-Installation-Target-ID: 79929171
+X-GitHub-Hook-Installation-Target-Type: repository
+
+{{
+  "repository": {{
+    "owner": {{
+      "login": "testuser"
+    }},
+    "name": "testrepo"
+  }}
+}}"#
+                ))
+            },
+            move |response: String| {
+                if response.starts_with("HTTP/1.1 200 OK") {
+                    sleep(SNARE_PAUSE);
+                    assert!(tp1.is_file());
+                    assert!(!tp2.is_file());
+                    assert_eq!(read_to_string(&tp3).unwrap(), "status 2\na\nb\n");
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        )],
+    )
+    .unwrap();
+}
+
+/// Return an HTTP request for a GitHub `event_type` event from the webhook `hook_id` with the JSON
+/// payload `body`.
+fn req(port: u16, event_type: &str, hook_id: &str, body: &str) -> String {
+    format!(
+        r#"POST /payload HTTP/1.1
+Host: 127.0.0.1:{port}
+Content-Length: {}
+X-GitHub-Delivery: 72d3162e-cc78-11e3-81ab-4c9367dc0958
+User-Agent: GitHub-Hookshot/044aadd
+Content-Type: application/json
+X-GitHub-Event: {event_type}
+X-GitHub-Hook-ID: {hook_id}
+X-GitHub-Hook-Installation-Target-ID: 79929171
+X-GitHub-Hook-Installation-Target-Type: repository
+
+{body}"#,
+        body.len()
+    )
+}
+
+/// Check that a request whose payload is `body` and whose webhook is `hook_id` does (if
+/// `should_run` is `true`) or doesn't run the command of a `match` block with the options
+/// `match_opts`.
+fn check_filter(match_opts: &str, hook_id: &'static str, body: String, should_run: bool) {
+    let td = Builder::new()
+        .tempdir_in(env!("CARGO_TARGET_TMPDIR"))
+        .unwrap();
+    let mut tp1 = td.path().to_owned();
+    tp1.push("t1");
+    let tp1s = tp1.as_path().to_str().unwrap();
+
+    run_success(
+        &format!(
+            r#"listen = "127.0.0.1:0";
+github {{
+  match ".*" {{
+    cmd = "touch {tp1s}";
+    {match_opts}
+  }}
+}}"#
+        ),
+        &[(
+            move |port| Ok(req(port, "push", hook_id, &body)),
+            move |response: String| {
+                if response.starts_with("HTTP/1.1 200 OK") {
+                    sleep(SNARE_PAUSE);
+                    assert_eq!(tp1.is_file(), should_run);
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        )],
+    )
+    .unwrap();
+}
+
+#[test]
+fn filters() {
+    // This tests that jobs are only run for repositories which satisfy `topic` and `visibility`.
+
+    for (vis, should_run) in [("public", true), ("private", false)] {
+        check_filter(
+            &format!(r#"topic = "prod"; visibility = {vis};"#),
+            "292430182",
+            r#"{
+  "repository": {
+    "owner": {
+      "login": "testuser"
+    },
+    "name": "testrepo",
+    "visibility": "public",
+    "topics": ["prod"]
+  }
+}"#
+            .to_owned(),
+            should_run,
+        );
+    }
+}
+
+#[test]
+fn ignore_senders() {
+    // This tests that jobs are not run for requests from senders in `ignore_senders`.
+
+    for (sender, should_run) in [("someone", true), ("dependabot[bot]", false)] {
+        check_filter(
+            r#"ignore_senders = ["github-actions[bot]", "dependabot[bot]"];"#,
+            "292430182",
+            format!(
+                r#"{{
+  "repository": {{
+    "owner": {{
+      "login": "testuser"
+    }},
+    "name": "testrepo"
+  }},
+  "sender": {{
+    "login": "{sender}"
+  }}
+}}"#
+            ),
+            should_run,
+        );
+    }
+}
+
+#[test]
+fn hook_id() {
+    // This tests that jobs are only run for requests from the webhook specified by `hook_id`.
+
+    for (hook_id, should_run) in [("292430182", true), ("292430183", false)] {
+        check_filter(
+            "hook_id = 292430182;",
+            hook_id,
+            r#"{
+  "repository": {
+    "owner": {
+      "login": "testuser"
+    },
+    "name": "testrepo"
+  }
+}"#
+            .to_owned(),
+            should_run,
+        );
+    }
+}
+
+#[test]
+fn resolved_repoconfig() {
+    // This tests how options are cascaded across `match` blocks by asking snare for the resolved
+    // configuration of a repository.
+
+    for (owner_repo, cmd, timeout, queue, secret, matched) in [
+        (
+            "testuser/testrepo",
+            "b",
+            10,
+            "parallel",
+            true,
+            "testuser/testrepo",
+        ),
+        (
+            "testuser/other",
+            "a",
+            3600,
+            "parallel",
+            false,
+            "testuser/.*",
+        ),
+        ("other/other", "a", 3600, "sequential", false, ".*"),
+    ] {
+        run_success(
+            r#"listen = "127.0.0.1:0";
+github {
+  match ".*" {
+    cmd = "a";
+  }
+  match "testuser/.*" {
+    queue = parallel;
+  }
+  match "testuser/testrepo" {
+    cmd = "b";
+    timeout = 10;
+    secret = "secretsecret";
+  }
+}"#,
+            &[(
+                move |port| {
+                    Ok(format!(
+                        r#"POST /payload HTTP/1.1
+Host: 127.0.0.1:{port}
+Content-Length: 0
+X-Snare-Debug-RepoConfig: {owner_repo}
+
+"#
+                    ))
+                },
+                move |response: String| {
+                    let body = match response.split_once("\r\n\r\n") {
+                        Some(("HTTP/1.1 200 OK\r\nContent-Type: application/json", body)) => body,
+                        _ => return Err(format!("Received HTTP response '{response}'").into()),
+                    };
+                    let jv = serde_json::from_str::<serde_json::Value>(body)?;
+                    assert_eq!(jv["cmd"], serde_json::json!([cmd]));
+                    assert_eq!(jv["timeout"], timeout);
+                    assert_eq!(jv["queue"], queue);
+                    assert_eq!(jv["secret"], secret);
+                    assert_eq!(jv["weight"], 1);
+                    assert_eq!(jv["matched"], matched);
+                    Ok(())
+                },
+            )],
+        )
+        .unwrap();
+    }
+}
+
+#[test]
+fn host_repoconfig() {
+    // Check that `match` blocks with a `host` only apply to requests from that host.
+
+    for (host, cmd) in [
+        (Some("ghe1.example.com"), "b"),
+        (Some("GHE2.example.com"), "c"),
+        (Some("ghe3.example.com"), "a"),
+        (None, "a"),
+    ] {
+        run_success(
+            r#"listen = "127.0.0.1:0";
+github {
+  match ".*" {
+    cmd = "a";
+  }
+  match ".*" {
+    host = "ghe1.example.com";
+    cmd = "b";
+  }
+  match ".*" {
+    host = "ghe2.example.com";
+    cmd = "c";
+  }
+}"#,
+            &[(
+                move |port| {
+                    let host_hdr = match host {
+                        Some(h) => format!("X-GitHub-Enterprise-Host: {h}\n"),
+                        None => String::new(),
+                    };
+                    Ok(format!(
+                        r#"POST /payload HTTP/1.1
+Host: 127.0.0.1:{port}
+Content-Length: 0
+{host_hdr}X-Snare-Debug-RepoConfig: testuser/testrepo
+
+"#
+                    ))
+                },
+                move |response: String| {
+                    let body = match response.split_once("\r\n\r\n") {
+                        Some(("HTTP/1.1 200 OK\r\nContent-Type: application/json", body)) => body,
+                        _ => return Err(format!("Received HTTP response '{response}'").into()),
+                    };
+                    let jv = serde_json::from_str::<serde_json::Value>(body)?;
+                    assert_eq!(jv["cmd"], serde_json::json!([cmd]));
+                    Ok(())
+                },
+            )],
+        )
+        .unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::{error::Error, path::PathBuf, thread::sleep};
+use std::{error::Error, fs::read_to_string, path::PathBuf, thread::sleep};
 use tempfile::{Builder, TempDir};
 
 mod common;
@@ -80,6 +80,125 @@ fn ping() -> Result<(), Box<dyn Error>> {
     )
 }
 
+#[test]
+fn ping_cmd() -> Result<(), Box<dyn Error>> {
+    // Check that, if `ping_cmd` is specified, it is executed for ping events.
+    let td = Builder::new().tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let mut tp = td.path().to_owned();
+    tp.push("t");
+    let tps = tp.as_path().to_str().unwrap();
+    let cfg = format!(
+        r#"listen = "127.0.0.1:0";
+ping_cmd = "touch {tps}";
+github {{
+  match ".*" {{
+    cmd = "true";
+    secret = "secretsecret";
+  }}
+}}"#
+    );
+
+    run_success(
+        &cfg,
+        &[(
+            move |port| Ok(req(port, true, "ping")),
+            move |response| {
+                if response.starts_with("HTTP/1.1 200 OK") {
+                    sleep(SNARE_PAUSE);
+                    assert!(tp.is_file());
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        )],
+    )
+}
+
+#[test]
+fn everycmd() -> Result<(), Box<dyn Error>> {
+    // Check that `everycmd` is executed even for events (here, a ping with no `ping_cmd`) for which
+    // no other command is run.
+    let td = Builder::new().tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let mut tp = td.path().to_owned();
+    tp.push("t");
+    let tps = tp.as_path().to_str().unwrap();
+    let cfg = format!(
+        r#"listen = "127.0.0.1:0";
+everycmd = "touch {tps}";
+github {{
+  match ".*" {{
+    cmd = "false";
+    secret = "secretsecret";
+  }}
+}}"#
+    );
+
+    run_success(
+        &cfg,
+        &[(
+            move |port| Ok(req(port, true, "ping")),
+            move |response| {
+                if response.starts_with("HTTP/1.1 200 OK") {
+                    sleep(SNARE_PAUSE);
+                    assert!(tp.is_file());
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        )],
+    )
+}
+
+#[test]
+fn auditlog() -> Result<(), Box<dyn Error>> {
+    // Check that every request that reaches authentication is recorded in the audit log, including
+    // pings and requests which fail authentication.
+    let td = Builder::new().tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let mut ap = td.path().to_owned();
+    ap.push("audit.log");
+    let aps = ap.as_path().to_str().unwrap();
+    let cfg = format!(
+        r#"listen = "127.0.0.1:0";
+auditlog = "{aps}";
+github {{
+  match ".*" {{
+    cmd = "true";
+    secret = "secretsecret";
+  }}
+}}"#
+    );
+
+    let mut reqs = Vec::new();
+    for (i, &(good_sha256, event_type)) in [(true, "ping"), (false, "push")].iter().enumerate() {
+        let ap = ap.clone();
+        reqs.push((
+            move |port| Ok(req(port, good_sha256, event_type)),
+            move |response: String| {
+                let expected = if good_sha256 {
+                    "HTTP/1.1 200 OK"
+                } else {
+                    "HTTP/1.1 401"
+                };
+                if !response.starts_with(expected) {
+                    return Err(format!("Received HTTP response '{response}'").into());
+                }
+                let log = read_to_string(&ap).unwrap();
+                let lines = log.lines().collect::<Vec<_>>();
+                assert_eq!(lines.len(), i + 1);
+                let authenticated = if good_sha256 { "yes" } else { "failed" };
+                assert!(lines[i].contains(r#""repo":"testuser/testrepo""#));
+                assert!(lines[i].contains(&format!(r#""event":"{event_type}""#)));
+                assert!(lines[i].contains(r#""delivery":"72d3162e-cc78-11e3-81ab-4c9367dc0958""#));
+                assert!(lines[i].contains(&format!(r#""authenticated":"{authenticated}""#)));
+                Ok(())
+            },
+        ));
+    }
+    run_success(&cfg, &reqs)
+}
+
 #[test]
 fn successful_auth() -> Result<(), Box<dyn Error>> {
     // This test checks that snare both responds to, and executes the correct command for, a given
@@ -133,6 +252,35 @@ fn bad_sha256() -> Result<(), Box<dyn Error>> {
     )
 }
 
+#[test]
+fn fast_ack() -> Result<(), Box<dyn Error>> {
+    // Check that `fast_ack` only acknowledges requests once they have been authenticated.
+    let (cfg, _td, tp) = cfg(true)?;
+    let cfg = format!("fast_ack = on;\n{cfg}");
+    let mut reqs = Vec::new();
+    for good_sha256 in [false, true] {
+        let tp = tp.clone();
+        reqs.push((
+            move |port| Ok(req(port, good_sha256, "push")),
+            move |response: String| {
+                let expected = if good_sha256 {
+                    "HTTP/1.1 200 OK"
+                } else {
+                    "HTTP/1.1 401"
+                };
+                if response.starts_with(expected) {
+                    sleep(SNARE_PAUSE);
+                    assert_eq!(tp.is_file(), good_sha256);
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        ));
+    }
+    run_success(&cfg, &reqs)
+}
+
 #[test]
 fn wrong_secret() -> Result<(), Box<dyn Error>> {
     // Takes the example from [full_request], alters the client-side secret, and checks that this
@@ -158,3 +306,74 @@ fn wrong_secret() -> Result<(), Box<dyn Error>> {
         )],
     )
 }
+
+fn gitlab_req(port: u16, token: &str) -> String {
+    let body = r#"{
+  "object_kind": "push",
+  "project": {
+    "namespace": "testuser",
+    "name": "testrepo"
+  }
+}"#;
+    format!(
+        r#"POST /payload HTTP/1.1
+Host: 127.0.0.1:{port}
+Content-Length: {}
+User-Agent: GitLab/16.0.0
+Content-Type: application/json
+X-Gitlab-Event: Push Hook
+X-Gitlab-Token: {token}
+
+{body}"#,
+        body.len()
+    )
+}
+
+#[test]
+fn gitlab() -> Result<(), Box<dyn Error>> {
+    // Check that GitLab requests are authenticated by comparing their `X-Gitlab-Token` header to
+    // the secret, and that the owner and repository are taken from the payload's `project`.
+    let td = Builder::new().tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let mut tp = td.path().to_owned();
+    tp.push("testuser-testrepo-push");
+    let tds = td.path().to_str().unwrap();
+    let cfg = format!(
+        r#"listen = "127.0.0.1:0";
+github {{
+  match ".*" {{
+    cmd = "false";
+    secret = "secretsecret";
+  }}
+}}
+gitlab {{
+  match ".*" {{
+    cmd = "touch {tds}/%o-%r-%e";
+    secret = "secretsecret";
+  }}
+}}"#
+    );
+
+    let mut reqs = Vec::new();
+    for token in ["secretsecretsecret", "secretsecret"] {
+        let tp = tp.clone();
+        reqs.push((
+            move |port| Ok(gitlab_req(port, token)),
+            move |response: String| {
+                let good = token == "secretsecret";
+                let expected = if good {
+                    "HTTP/1.1 200 OK"
+                } else {
+                    "HTTP/1.1 401"
+                };
+                if response.starts_with(expected) {
+                    sleep(SNARE_PAUSE);
+                    assert_eq!(tp.is_file(), good);
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        ));
+    }
+    run_success(&cfg, &reqs)
+}
@@ -98,3 +98,103 @@ payload={{
         )],
     )
 }
+
+#[test]
+fn max_json_size() -> Result<(), Box<dyn Error>> {
+    // The 96 byte payload exceeds `max_json_size`, so the request must be rejected.
+    run_success(
+        r#"
+            listen = "127.0.0.1:0";
+            max_json_size = 64;
+            github {
+                match ".*" {
+                    cmd = "true";
+                    secret = "secretsecret";
+                }
+            }
+        "#,
+        &[(
+            move |port| {
+                Ok(format!(
+                    r#"POST /payload HTTP/1.1
+Host: 127.0.0.1:{port}
+Content-Length: 96
+X-GitHub-Delivery: 72d3162e-cc78-11e3-81ab-4c9367dc0958
+X-Hub-Signature-256: sha256=d11297e14fe5286dd68fd58c5e23ea7fb45e60ceff51ec3eb3729400fcbcb4b2
+User-Agent: GitHub-Hookshot/044aadd
+Content-Type: application/json
+X-GitHub-Event: issues
+X-GitHub-Hook-ID: 292430182
+X-GitHub-Hook-Installation-Target-ID: 79929171
+X-GitHub-Hook-Installation-Target-Type: repository
+
+{{
+  "repository": {{
+    "owner": {{
+      "login": "testuser"
+    }},
+    "name": "testrepo"
+  }}
+}}"#
+                ))
+            },
+            move |response: String| {
+                if response.starts_with("HTTP/1.1 413") {
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        )],
+    )
+}
+
+#[test]
+fn content_types() -> Result<(), Box<dyn Error>> {
+    // JSON payloads aren't in `content_types`, so the request must be rejected.
+    run_success(
+        r#"
+            listen = "127.0.0.1:0";
+            content_types = ["application/x-www-form-urlencoded"];
+            github {
+                match ".*" {
+                    cmd = "true";
+                    secret = "secretsecret";
+                }
+            }
+        "#,
+        &[(
+            move |port| {
+                Ok(format!(
+                    r#"POST /payload HTTP/1.1
+Host: 127.0.0.1:{port}
+Content-Length: 96
+X-GitHub-Delivery: 72d3162e-cc78-11e3-81ab-4c9367dc0958
+X-Hub-Signature-256: sha256=d11297e14fe5286dd68fd58c5e23ea7fb45e60ceff51ec3eb3729400fcbcb4b2
+User-Agent: GitHub-Hookshot/044aadd
+Content-Type: application/json
+X-GitHub-Event: issues
+X-GitHub-Hook-ID: 292430182
+X-GitHub-Hook-Installation-Target-ID: 79929171
+X-GitHub-Hook-Installation-Target-Type: repository
+
+{{
+  "repository": {{
+    "owner": {{
+      "login": "testuser"
+    }},
+    "name": "testrepo"
+  }}
+}}"#
+                ))
+            },
+            move |response: String| {
+                if response.starts_with("HTTP/1.1 415") {
+                    Ok(())
+                } else {
+                    Err(format!("Received HTTP response '{response}'").into())
+                }
+            },
+        )],
+    )
+}